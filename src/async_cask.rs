@@ -0,0 +1,253 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use std::vec::Vec;
+
+use tokio::sync::oneshot;
+
+use cask::Cask;
+use data::SequenceNumber;
+use errors::{Error, Result};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// An asynchronous handle to a `Cask` database, for use from async code.
+///
+/// Reads and writes block on file IO and on the locks of the `Cask`, so instead of running on the
+/// executor of the caller they're sent to a dedicated pool of threads. Each operation returns a
+/// `Pending` future which resolves to its result once a thread of the pool has performed it, and
+/// can be awaited like the result of an `async fn`.
+///
+/// This handle can be cloned and shared between tasks. Calling `shutdown` on any of the handles
+/// stops the pool for all of them.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use cask::{AsyncCask, CaskOptions};
+///
+/// let cask = CaskOptions::default().open("cask.db").unwrap();
+/// let cask = AsyncCask::new(cask, 4);
+///
+/// // within an async fn
+/// // cask.put("hello", "world").await?;
+/// // cask.get("hello").await?;
+/// // cask.shutdown().await;
+/// ```
+#[derive(Clone)]
+pub struct AsyncCask {
+    cask: Cask,
+    pool: Arc<ThreadPool>,
+}
+
+impl AsyncCask {
+    /// Returns an asynchronous handle to `cask` whose operations are performed by a pool of
+    /// `threads` threads.
+    pub fn new(cask: Cask, threads: usize) -> AsyncCask {
+        AsyncCask {
+            cask: cask,
+            pool: Arc::new(ThreadPool::new(threads.max(1))),
+        }
+    }
+
+    /// Returns the underlying `Cask`, for the operations which don't have an asynchronous
+    /// version.
+    pub fn cask(&self) -> &Cask {
+        &self.cask
+    }
+
+    /// Returns the value corresponding to the key, if any.
+    pub fn get<K: Into<Vec<u8>>>(&self, key: K) -> Pending<Option<Vec<u8>>> {
+        let key = key.into();
+        self.spawn(move |cask| cask.get(key))
+    }
+
+    /// Inserts a key-value pair into the map, returning the sequence number assigned to the write.
+    pub fn put<K: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Pending<SequenceNumber> {
+        let key = key.into();
+        let value = value.into();
+        self.spawn(move |cask| cask.put(key, value))
+    }
+
+    /// Removes a key from the map, returning the sequence number assigned to the write if the key
+    /// was present.
+    pub fn delete<K: Into<Vec<u8>>>(&self, key: K) -> Pending<Option<SequenceNumber>> {
+        let key = key.into();
+        self.spawn(move |cask| cask.delete(key))
+    }
+
+    /// Triggers `Cask` compaction, see `Cask::compact`.
+    pub fn compact(&self) -> Pending<()> {
+        self.spawn(|cask| cask.compact())
+    }
+
+    /// Shuts down the thread pool along with the background threads of the `Cask` (see
+    /// `Cask::shutdown`), returning a future which resolves once the operations in progress and
+    /// the ones already requested are done. Operations requested afterwards fail with
+    /// `Error::ShutDown`.
+    pub fn shutdown(&self) -> Pending<()> {
+        let pool = self.pool.clone();
+        let cask = self.cask.clone();
+        let (sender, receiver) = oneshot::channel();
+
+        // waiting for the threads blocks, so it's done by a thread of its own
+        thread::spawn(move || {
+            pool.shutdown();
+            cask.shutdown();
+            let _ = sender.send(Ok(()));
+        });
+
+        Pending { receiver: receiver }
+    }
+
+    fn spawn<T, F>(&self, f: F) -> Pending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Cask) -> Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let cask = self.cask.clone();
+
+        // the future may have been dropped in the meantime, in which case the result is discarded
+        self.pool.execute(Box::new(move || {
+            let _ = sender.send(f(&cask));
+        }));
+
+        Pending { receiver: receiver }
+    }
+}
+
+/// A future resolving to the result of an operation of an `AsyncCask`.
+pub struct Pending<T> {
+    receiver: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(Error::ShutDown)))
+    }
+}
+
+/// A fixed pool of threads running jobs in the order they're sent.
+struct ThreadPool {
+    sender: Mutex<Option<Sender<Job>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ThreadPool {
+    fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let threads = (0..size)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || run_jobs(&receiver))
+            })
+            .collect();
+
+        ThreadPool {
+            sender: Mutex::new(Some(sender)),
+            threads: Mutex::new(threads),
+        }
+    }
+
+    /// Sends `job` to the threads, unless the pool has been shut down in which case it's dropped.
+    fn execute(&self, job: Job) {
+        if let Some(ref sender) = *self.sender.lock().unwrap() {
+            // sending only fails if all the threads have panicked, which drops the job as well
+            let _ = sender.send(job);
+        }
+    }
+
+    /// Stops accepting jobs and waits for the threads to run the ones already sent.
+    fn shutdown(&self) {
+        // the threads exit once the channel is closed and empty
+        self.sender.lock().unwrap().take();
+
+        let threads: Vec<_> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                warn!("Thread pool thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_jobs(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        job();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::runtime::Builder;
+
+    use async_cask::AsyncCask;
+    use cask::{CaskOptions, SyncStrategy};
+    use errors::Error;
+
+    #[test]
+    fn test_async_cask() {
+        let path = "test-async-cask.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Interval(10))
+            .open(path)
+            .unwrap();
+        let cask = AsyncCask::new(cask, 2);
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+
+        let sequence = runtime.block_on(cask.put("hello", "world")).unwrap();
+        assert_eq!(
+            runtime.block_on(cask.get("hello")).unwrap(),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(
+            runtime.block_on(cask.delete("hello")).unwrap(),
+            Some(sequence + 1)
+        );
+        assert_eq!(runtime.block_on(cask.get("hello")).unwrap(), None);
+        runtime.block_on(cask.compact()).unwrap();
+
+        // operations requested before the shutdown are still performed
+        let put = cask.put("a", "1");
+        runtime.block_on(cask.shutdown()).unwrap();
+        runtime.block_on(put).unwrap();
+
+        match runtime.block_on(cask.get("a")) {
+            Err(Error::ShutDown) => {}
+            _ => panic!("expected the operation to fail"),
+        }
+        assert_eq!(cask.cask().get("a").unwrap(), Some(b"1".to_vec()));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::vec::Vec;
+
+use bucket::BUCKETS_FILE_NAME;
+use data::SequenceNumber;
+use errors::{Error, Result};
+use log::copy_files;
+
+const MANIFEST_EXTENSION: &'static str = "manifest";
+const BUCKETS_EXTENSION: &'static str = "buckets";
+const MANIFEST_HEADER: &'static str = "cask-backup 1";
+
+/// Describes an incremental backup of a `Cask`, see `Cask::backup_incremental`.
+///
+/// All backups of a `Cask` share the same backup directory, each backup only adds the data files
+/// which were created since the previous one. The data files which compaction removed since the
+/// previous backup are listed in `retired`, they are still needed to restore older backups but
+/// can be deleted from the backup directory along with them.
+///
+/// The bucket catalog of the `Cask` (see `Cask::bucket`), if any, is copied along with each
+/// manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupManifest {
+    /// Id of the backup, which is greater than the ids of the backups already in the backup
+    /// directory and names the manifest within it.
+    pub id: u64,
+    /// Sequence number of the last write included in the backup.
+    pub sequence: SequenceNumber,
+    /// Data files which make up the backup.
+    pub files: Vec<u32>,
+    /// Data files which were copied by this backup.
+    pub added: Vec<u32>,
+    /// Data files of the previous backup which aren't part of this backup.
+    pub retired: Vec<u32>,
+}
+
+impl BackupManifest {
+    pub(crate) fn new(
+        id: u64,
+        sequence: SequenceNumber,
+        files: Vec<u32>,
+        previous: Option<&BackupManifest>,
+    ) -> BackupManifest {
+        let (added, retired) = match previous {
+            Some(previous) => (
+                files
+                    .iter()
+                    .filter(|file_id| !previous.files.contains(file_id))
+                    .cloned()
+                    .collect(),
+                previous
+                    .files
+                    .iter()
+                    .filter(|file_id| !files.contains(file_id))
+                    .cloned()
+                    .collect(),
+            ),
+            None => (files.clone(), Vec::new()),
+        };
+
+        BackupManifest {
+            id: id,
+            sequence: sequence,
+            files: files,
+            added: added,
+            retired: retired,
+        }
+    }
+
+    /// Reads the manifest stored at `path`.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<BackupManifest> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines();
+
+        match lines.next() {
+            Some(Ok(ref header)) if header == MANIFEST_HEADER => {}
+            _ => return Err(invalid_manifest("missing header")),
+        }
+
+        let mut manifest = BackupManifest {
+            id: 0,
+            sequence: 0,
+            files: Vec::new(),
+            added: Vec::new(),
+            retired: Vec::new(),
+        };
+
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+
+            match fields.next() {
+                Some("id") => manifest.id = parse(fields.next())?,
+                Some("sequence") => manifest.sequence = parse(fields.next())?,
+                Some("files") => manifest.files = parse_list(fields)?,
+                Some("added") => manifest.added = parse_list(fields)?,
+                Some("retired") => manifest.retired = parse_list(fields)?,
+                Some(field) => return Err(invalid_manifest(&format!("unknown field {}", field))),
+                None => {}
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Writes the manifest into the backup directory `path`, returning the path of the manifest.
+    /// Fails if there's already a manifest with the same id, which is never overwritten.
+    pub(crate) fn write(&self, path: &Path) -> Result<PathBuf> {
+        let manifest_path = manifest_path(path, self.id);
+
+        let mut manifest_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&manifest_path)?;
+        writeln!(manifest_file, "{}", MANIFEST_HEADER)?;
+        writeln!(manifest_file, "id {}", self.id)?;
+        writeln!(manifest_file, "sequence {}", self.sequence)?;
+        writeln!(manifest_file, "files{}", join(&self.files))?;
+        writeln!(manifest_file, "added{}", join(&self.added))?;
+        writeln!(manifest_file, "retired{}", join(&self.retired))?;
+        manifest_file.sync_all()?;
+
+        Ok(manifest_path)
+    }
+
+    /// Restores the backup from the backup directory `backup_path` into `path`, which must either
+    /// not exist or be an empty directory. The restored `Cask` can then be opened at `path`.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(&self, backup_path: P, path: Q) -> Result<()> {
+        let path = path.as_ref();
+
+        if !path.exists() {
+            fs::create_dir_all(path)?;
+        } else if !path.is_dir() || fs::read_dir(path)?.next().is_some() {
+            return Err(Error::InvalidPath(path.to_string_lossy().into_owned()));
+        }
+
+        info!(
+            "Restoring backup of sequence {} to {:?}",
+            self.sequence, path
+        );
+
+        let buckets_path = buckets_path(backup_path.as_ref(), self.id);
+        if buckets_path.is_file() {
+            fs::copy(buckets_path, path.join(BUCKETS_FILE_NAME))?;
+        }
+
+        // the files are copied so that the restored `Cask` doesn't share them with the backup
+        copy_files(backup_path.as_ref(), path, &self.files, false)
+    }
+}
+
+/// Returns the id of the next backup into the backup directory `path`, which follows the ids of
+/// the manifests already there.
+pub(crate) fn next_id(path: &Path) -> Result<u64> {
+    let mut next_id = 1;
+
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+
+        if path.extension() == Some(OsStr::new(MANIFEST_EXTENSION)) {
+            let id = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+
+            if let Some(id) = id {
+                next_id = next_id.max(id + 1);
+            }
+        }
+    }
+
+    Ok(next_id)
+}
+
+/// Returns the path of the manifest of the backup `id`.
+pub(crate) fn manifest_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{:020}", id))
+        .with_extension(MANIFEST_EXTENSION)
+}
+
+/// Returns the path of the copy of the bucket catalog of the backup `id`.
+pub(crate) fn buckets_path(path: &Path, id: u64) -> PathBuf {
+    path.join(format!("{:020}", id))
+        .with_extension(BUCKETS_EXTENSION)
+}
+
+fn parse<T: FromStr>(field: Option<&str>) -> Result<T> {
+    field
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| invalid_manifest("invalid number"))
+}
+
+fn parse_list<'a, I: Iterator<Item = &'a str>>(fields: I) -> Result<Vec<u32>> {
+    fields.map(|field| parse(Some(field))).collect()
+}
+
+fn join(file_ids: &[u32]) -> String {
+    file_ids.iter().map(|file_id| format!(" {}", file_id)).collect()
+}
+
+fn invalid_manifest(msg: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid backup manifest: {}", msg),
+    ))
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+use std::vec::Vec;
+
+#[derive(Debug)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>, Option<Duration>),
+    Delete(Vec<u8>),
+}
+
+/// A set of writes to be applied atomically to a `Cask`.
+///
+/// Either all of the writes in a batch are visible after a crash or none of them are.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use cask::{CaskOptions, WriteBatch};
+///
+/// let cask = CaskOptions::default().open("cask.db").unwrap();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.put("user/1", "alice").put("email/alice@example.com", "user/1");
+/// batch.delete("email/alice@example.org");
+///
+/// cask.write_batch(batch).unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds a key-value pair insertion to the batch.
+    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> &mut WriteBatch {
+        self.ops
+            .push(BatchOp::Put(key.into(), value.as_ref().to_vec(), None));
+        self
+    }
+
+    /// Adds a key-value pair insertion to the batch which expires after `ttl`, counted from the
+    /// moment the batch is written.
+    pub fn put_with_ttl<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> &mut WriteBatch {
+        self.ops
+            .push(BatchOp::Put(key.into(), value.as_ref().to_vec(), Some(ttl)));
+        self
+    }
+
+    /// Adds a key removal to the batch.
+    pub fn delete<K: Into<Vec<u8>>>(&mut self, key: K) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete(key.into()));
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Removes all writes from the batch.
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+}
@@ -0,0 +1,626 @@
+extern crate base64;
+extern crate cask;
+extern crate clap;
+extern crate hex;
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+#[cfg(any(feature = "server", feature = "http", feature = "memcached"))]
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::str;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+#[cfg(feature = "http")]
+use cask::http;
+use cask::inspect::inspect_file;
+#[cfg(feature = "memcached")]
+use cask::memcached;
+#[cfg(feature = "server")]
+use cask::server;
+use cask::{Cask, CaskOptions, SyncStrategy, WriteBatch};
+
+type CliResult<T> = Result<T, Box<dyn Error>>;
+
+/// Number of entries written per batch by `load`.
+const LOAD_BATCH_SIZE: usize = 1000;
+
+/// Encoding of the keys and values given on the command line or printed by the `cask` binary.
+#[derive(Clone, Copy)]
+enum Encoding {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    fn from_name(name: &str) -> Encoding {
+        match name {
+            "hex" => Encoding::Hex,
+            "base64" => Encoding::Base64,
+            _ => Encoding::Utf8,
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> CliResult<String> {
+        match self {
+            Encoding::Utf8 => Ok(str::from_utf8(bytes)
+                .map_err(|_| "not valid UTF-8, use the hex or base64 encoding")?
+                .to_owned()),
+            Encoding::Hex => Ok(hex::encode(bytes)),
+            Encoding::Base64 => Ok(base64::encode(bytes)),
+        }
+    }
+
+    fn decode(self, s: &str) -> CliResult<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Ok(s.as_bytes().to_vec()),
+            Encoding::Hex => Ok(hex::decode(s)?),
+            Encoding::Base64 => Ok(base64::decode(s)?),
+        }
+    }
+}
+
+struct Context {
+    path: String,
+    key_encoding: Encoding,
+    value_encoding: Encoding,
+}
+
+impl Context {
+    /// Opens the `Cask` read-only, which works alongside a process that has it open for writing.
+    fn open_read_only(&self) -> CliResult<Cask> {
+        let cask = CaskOptions::default()
+            .create(false)
+            .read_only(true)
+            .open(&self.path)?;
+
+        Ok(cask)
+    }
+
+    fn open(&self) -> CliResult<Cask> {
+        let cask = CaskOptions::default()
+            .create(false)
+            .compaction(false)
+            .sync(SyncStrategy::Always)
+            .open(&self.path)?;
+
+        Ok(cask)
+    }
+
+    fn key(&self, matches: &ArgMatches) -> CliResult<Vec<u8>> {
+        self.key_encoding.decode(matches.value_of("KEY").unwrap())
+    }
+}
+
+fn main() {
+    let encoding = |name| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .possible_values(&["utf8", "hex", "base64"])
+            .default_value("utf8")
+            .global(true)
+    };
+    let key = || Arg::with_name("KEY").required(true);
+    let file = || Arg::with_name("FILE").help("File to use instead of stdin/stdout");
+
+    let app = App::new("cask")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Inspects and edits a cask database")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(encoding("key-encoding").help("Encoding of keys"))
+        .arg(encoding("value-encoding").help("Encoding of values"))
+        .arg(
+            Arg::with_name("PATH")
+                .help("Path of the cask database")
+                .required(true),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Prints the value of a key")
+                .arg(key()),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Sets the value of a key")
+                .arg(key())
+                .arg(Arg::with_name("VALUE").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Deletes a key")
+                .arg(key()),
+        )
+        .subcommand(
+            SubCommand::with_name("keys")
+                .about("Lists the live keys, in key order")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .takes_value(true)
+                        .help("Only lists the keys starting with the given (encoded) prefix"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("stats").about("Prints statistics about the database"))
+        .subcommand(SubCommand::with_name("compact").about("Triggers log compaction"))
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verifies the integrity of the data and hint files"),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Writes all key-value pairs as tab separated lines")
+                .arg(file()),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .about("Reads tab separated key-value pairs, as written by dump")
+                .arg(file()),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Decodes a single data or hint file, without opening the database")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("Data or hint file in the database directory")
+                        .required(true),
+                ),
+        );
+
+    #[cfg(feature = "server")]
+    let app = app.subcommand(
+        SubCommand::with_name("serve")
+            .about("Serves the database to Redis clients")
+            .arg(
+                Arg::with_name("bind")
+                    .long("bind")
+                    .takes_value(true)
+                    .default_value("127.0.0.1:6379")
+                    .help("Address to listen on"),
+            ),
+    );
+
+    #[cfg(feature = "http")]
+    let app = app.subcommand(
+        SubCommand::with_name("serve-http")
+            .about("Serves the database over HTTP")
+            .arg(
+                Arg::with_name("bind")
+                    .long("bind")
+                    .takes_value(true)
+                    .default_value("127.0.0.1:8080")
+                    .help("Address to listen on"),
+            )
+            .arg(
+                Arg::with_name("max-body-size")
+                    .long("max-body-size")
+                    .takes_value(true)
+                    .help("Maximum size of a request body in bytes, 64 MiB by default"),
+            ),
+    );
+
+    #[cfg(feature = "memcached")]
+    let app = app.subcommand(
+        SubCommand::with_name("serve-memcached")
+            .about("Serves the database over the memcached text protocol")
+            .arg(
+                Arg::with_name("bind")
+                    .long("bind")
+                    .takes_value(true)
+                    .default_value("127.0.0.1:11211")
+                    .help("Address to listen on"),
+            ),
+    );
+
+    let matches = app.get_matches();
+
+    let (command, command_matches) = matches.subcommand();
+    let command_matches = command_matches.unwrap();
+
+    let context = Context {
+        path: matches.value_of("PATH").unwrap().to_owned(),
+        key_encoding: Encoding::from_name(command_matches.value_of("key-encoding").unwrap()),
+        value_encoding: Encoding::from_name(command_matches.value_of("value-encoding").unwrap()),
+    };
+
+    let result = match command {
+        "get" => get(&context, command_matches),
+        "put" => put(&context, command_matches),
+        "delete" => delete(&context, command_matches),
+        "keys" => keys(&context, command_matches),
+        "stats" => stats(&context),
+        "compact" => compact(&context),
+        "verify" => verify(&context),
+        "dump" => dump(&context, command_matches),
+        "load" => load(&context, command_matches),
+        "inspect" => inspect(&context, command_matches),
+        #[cfg(feature = "server")]
+        "serve" => serve(&context, command_matches),
+        #[cfg(feature = "http")]
+        "serve-http" => serve_http(&context, command_matches),
+        #[cfg(feature = "memcached")]
+        "serve-memcached" => serve_memcached(&context, command_matches),
+        _ => unreachable!(),
+    };
+
+    if let Err(err) = result {
+        eprintln!("cask: {}", err);
+        process::exit(1);
+    }
+}
+
+fn get(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = context.open_read_only()?;
+
+    match cask.get(context.key(matches)?)? {
+        Some(value) => {
+            println!("{}", context.value_encoding.encode(&value)?);
+            Ok(())
+        }
+        None => Err("key not found".into()),
+    }
+}
+
+fn put(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = context.open()?;
+    let value = context
+        .value_encoding
+        .decode(matches.value_of("VALUE").unwrap())?;
+
+    cask.put(context.key(matches)?, value)?;
+    Ok(())
+}
+
+fn delete(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = context.open()?;
+
+    match cask.delete(context.key(matches)?)? {
+        Some(_) => Ok(()),
+        None => Err("key not found".into()),
+    }
+}
+
+fn keys(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = context.open_read_only()?;
+    let prefix = match matches.value_of("prefix") {
+        Some(prefix) => context.key_encoding.decode(prefix)?,
+        None => Vec::new(),
+    };
+
+    let mut keys: Vec<_> = cask
+        .keys()
+        .into_iter()
+        .filter(|key| key.starts_with(&prefix))
+        .collect();
+    keys.sort();
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    for key in keys {
+        writeln!(out, "{}", context.key_encoding.encode(&key)?)?;
+    }
+
+    Ok(())
+}
+
+fn stats(context: &Context) -> CliResult<()> {
+    let cask = context.open_read_only()?;
+    let stats = cask.stats()?;
+
+    println!("keys: {}", stats.keys);
+    println!("live bytes: {}", stats.live_bytes);
+    println!("dead bytes: {}", stats.dead_bytes);
+    println!("disk size: {}", stats.disk_size);
+    println!("sequence: {}", stats.sequence);
+    println!("data files: {}", stats.files.len());
+
+    for file in &stats.files {
+        println!(
+            "  {}: size {}, entries {}, dead entries {}, fragmentation {:.2}",
+            file.file_id, file.size, file.entries, file.dead_entries, file.fragmentation
+        );
+    }
+
+    Ok(())
+}
+
+fn compact(context: &Context) -> CliResult<()> {
+    let cask = context.open()?;
+    cask.compact()?;
+    Ok(())
+}
+
+fn verify(context: &Context) -> CliResult<()> {
+    let cask = context.open_read_only()?;
+    let report = cask.verify()?;
+
+    println!(
+        "verified {} entries in {} data files",
+        report.entries, report.data_files
+    );
+
+    for corrupt_entry in &report.corrupt_entries {
+        println!(
+            "corrupt entry in data file {} at {}: {}",
+            corrupt_entry.file_id, corrupt_entry.entry_pos, corrupt_entry.error
+        );
+    }
+    for problem in &report.hint_file_problems {
+        println!("hint file problem: {:?}", problem);
+    }
+    for file_id in &report.missing_hint_files {
+        println!("data file {} has no hint file", file_id);
+    }
+    for problem in &report.index_problems {
+        println!(
+            "index entry for key {} doesn't match data file {} at {}",
+            context.key_encoding.encode(&problem.key)?,
+            problem.file_id,
+            problem.entry_pos
+        );
+    }
+    for orphan_file in &report.orphan_files {
+        println!("orphan file: {}", orphan_file.display());
+    }
+
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err("database is corrupt".into())
+    }
+}
+
+fn dump(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = context.open_read_only()?;
+
+    let path = match matches.value_of("FILE") {
+        Some(path) => path,
+        None => {
+            let stdout = io::stdout();
+            return dump_to(context, &cask, BufWriter::new(stdout.lock()));
+        }
+    };
+
+    // the dump is written to a temporary file first so that a failure doesn't leave a truncated
+    // dump behind
+    let tmp_path = format!("{}.tmp", path);
+
+    let result = File::create(&tmp_path)
+        .map_err(Into::into)
+        .and_then(|file| dump_to(context, &cask, BufWriter::new(file)));
+
+    match result {
+        Ok(()) => Ok(fs::rename(&tmp_path, path)?),
+        Err(err) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(err)
+        }
+    }
+}
+
+fn dump_to<W: Write>(context: &Context, cask: &Cask, mut out: W) -> CliResult<()> {
+    for entry in cask.iter() {
+        let (key, value) = entry?;
+        let key = context.key_encoding.encode(&key)?;
+        let value = context.value_encoding.encode(&value)?;
+
+        // utf8 keys and values can't be told apart from the separators, and a trailing '\r' would
+        // be taken as part of a '\r\n' line ending by `load`
+        if key.contains(&['\t', '\n', '\r'][..]) {
+            return Err(format!(
+                "key {:?} can't be dumped with the utf8 key encoding, use hex or base64",
+                key
+            )
+            .into());
+        }
+        if value.contains(&['\n', '\r'][..]) {
+            return Err(format!(
+                "value {:?} of key {:?} can't be dumped with the utf8 value encoding, use hex or \
+                 base64",
+                value, key
+            )
+            .into());
+        }
+
+        writeln!(out, "{}\t{}", key, value)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+fn load(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = context.open()?;
+
+    let stdin = io::stdin();
+    let input: Box<dyn BufRead> = match matches.value_of("FILE") {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(stdin.lock()),
+    };
+
+    load_from(context, &cask, input)
+}
+
+fn load_from<R: BufRead>(context: &Context, cask: &Cask, input: R) -> CliResult<()> {
+    let mut batch = WriteBatch::new();
+
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields = line.splitn(2, '\t');
+        let (key, value) = match (fields.next(), fields.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => {
+                return Err(
+                    format!("line {}: expected a tab separated key and value", n + 1).into(),
+                )
+            }
+        };
+
+        batch.put(
+            context.key_encoding.decode(key)?,
+            context.value_encoding.decode(value)?,
+        );
+
+        if batch.len() == LOAD_BATCH_SIZE {
+            cask.write_batch(batch)?;
+            batch = WriteBatch::new();
+        }
+    }
+
+    if !batch.is_empty() {
+        cask.write_batch(batch)?;
+    }
+
+    Ok(())
+}
+
+fn inspect(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let path = Path::new(&context.path).join(matches.value_of("FILE").unwrap());
+    print!("{}", inspect_file(path)?);
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn serve(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    // unlike the other subcommands the database stays open, so it's compacted as usual
+    let cask = CaskOptions::default().create(false).open(&context.path)?;
+    let listener = TcpListener::bind(matches.value_of("bind").unwrap())?;
+
+    server::serve(&cask, listener)?;
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+fn serve_http(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = CaskOptions::default().create(false).open(&context.path)?;
+    let listener = TcpListener::bind(matches.value_of("bind").unwrap())?;
+    let max_body_size = match matches.value_of("max-body-size") {
+        Some(size) => size.parse()?,
+        None => http::DEFAULT_MAX_BODY_SIZE,
+    };
+
+    http::serve_with_max_body_size(&cask, listener, max_body_size)?;
+    Ok(())
+}
+
+#[cfg(feature = "memcached")]
+fn serve_memcached(context: &Context, matches: &ArgMatches) -> CliResult<()> {
+    let cask = CaskOptions::default().create(false).open(&context.path)?;
+    let listener = TcpListener::bind(matches.value_of("bind").unwrap())?;
+
+    memcached::serve(&cask, listener)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use cask::{CaskOptions, SyncStrategy};
+
+    use super::{dump_to, load_from, Context, Encoding};
+
+    fn round_trip(name: &str, encoding: Encoding, entries: &[(&[u8], &[u8])]) {
+        let source_path = format!("test-cli-{}-source.db", name);
+        let target_path = format!("test-cli-{}-target.db", name);
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        let context = Context {
+            path: source_path.clone(),
+            key_encoding: encoding,
+            value_encoding: encoding,
+        };
+
+        let mut dump = Vec::new();
+
+        {
+            let source = options.open(&source_path).unwrap();
+            for &(key, value) in entries {
+                source.put(key, value).unwrap();
+            }
+
+            dump_to(&context, &source, &mut dump).unwrap();
+        }
+
+        {
+            let target = options.open(&target_path).unwrap();
+            load_from(&context, &target, &dump[..]).unwrap();
+
+            assert_eq!(target.keys().len(), entries.len());
+            for &(key, value) in entries {
+                assert_eq!(target.get(key).unwrap(), Some(value.to_vec()));
+            }
+        }
+
+        assert!(fs::remove_dir_all(source_path).is_ok());
+        assert!(fs::remove_dir_all(target_path).is_ok());
+    }
+
+    #[test]
+    fn test_dump_load_utf8() {
+        round_trip(
+            "utf8",
+            Encoding::Utf8,
+            &[(b"a", b"1"), (b"b c", b"2\t3"), (b"d", b"")],
+        );
+    }
+
+    #[test]
+    fn test_dump_load_hex() {
+        round_trip(
+            "hex",
+            Encoding::Hex,
+            &[(b"a\t", b"1\r"), (b"\n", b"\r\n"), (b"\xff", b"")],
+        );
+    }
+
+    #[test]
+    fn test_dump_load_base64() {
+        round_trip(
+            "base64",
+            Encoding::Base64,
+            &[(b"a\t", b"1\r"), (b"\n", b"\r\n"), (b"\xff", b"")],
+        );
+    }
+
+    #[test]
+    fn test_dump_utf8_separators() {
+        let path = "test-cli-separators.db";
+
+        let context = Context {
+            path: path.to_owned(),
+            key_encoding: Encoding::Utf8,
+            value_encoding: Encoding::Utf8,
+        };
+
+        {
+            let cask = CaskOptions::default().open(path).unwrap();
+            cask.put("a\r", "1").unwrap();
+
+            let err = dump_to(&context, &cask, Vec::new()).unwrap_err();
+            assert!(err.to_string().starts_with("key \"a\\r\""));
+        }
+
+        for value in &["1\n", "1\r"] {
+            let cask = CaskOptions::default().open(path).unwrap();
+            cask.delete("a\r").unwrap();
+            cask.put("a", *value).unwrap();
+
+            let err = dump_to(&context, &cask, Vec::new()).unwrap_err();
+            assert!(err.to_string().starts_with(&format!("value {:?} of key \"a\"", value)));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+
+use data::{BucketId, DEFAULT_BUCKET};
+use errors::{Error, Result};
+
+pub const BUCKETS_FILE_NAME: &'static str = "cask.buckets";
+const BUCKETS_HEADER: &'static str = "cask-buckets 1";
+
+/// The catalog of the named buckets of a `Cask`, which maps their names to the ids stored in their
+/// entries and is kept in the `cask.buckets` file of the `Cask` dir.
+///
+/// Ids are never reused: dropping a bucket only removes its name from the catalog, so that the
+/// entries of a dropped bucket, which are left in the data files until compaction, are those with
+/// an id below `next_id` which isn't in the catalog anymore.
+pub struct Buckets {
+    path: PathBuf,
+    names: BTreeMap<String, BucketId>,
+    ids: HashSet<BucketId>,
+    next_id: BucketId,
+}
+
+impl Buckets {
+    /// Reads the catalog of the `Cask` dir `path`, which is empty if the catalog doesn't exist.
+    pub fn open(path: &Path) -> Result<Buckets> {
+        let mut buckets = Buckets {
+            path: path.join(BUCKETS_FILE_NAME),
+            names: BTreeMap::new(),
+            ids: HashSet::new(),
+            next_id: DEFAULT_BUCKET + 1,
+        };
+
+        let file = match File::open(&buckets.path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(buckets),
+            Err(err) => return Err(Error::Io(err)),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+
+        match lines.next() {
+            Some(Ok(ref header)) if header == BUCKETS_HEADER => {}
+            _ => return Err(invalid_catalog("missing header")),
+        }
+
+        for line in lines {
+            let line = line?;
+            let mut fields = line.splitn(2, ' ');
+
+            match (fields.next(), fields.next()) {
+                (Some("next"), Some(id)) => buckets.next_id = parse(id)?,
+                (Some(id), Some(name)) => {
+                    let id = parse(id)?;
+                    buckets.names.insert(name.to_string(), id);
+                    buckets.ids.insert(id);
+                }
+                (Some(""), None) => {}
+                _ => return Err(invalid_catalog("invalid line")),
+            }
+        }
+
+        Ok(buckets)
+    }
+
+    /// Returns the id of the bucket named `name`, if any.
+    pub fn get(&self, name: &str) -> Option<BucketId> {
+        self.names.get(name).cloned()
+    }
+
+    /// Returns the ids of the buckets, in no particular order.
+    pub fn ids(&self) -> Vec<BucketId> {
+        self.ids.iter().cloned().collect()
+    }
+
+    /// Returns the names of the buckets, in alphabetical order.
+    pub fn names(&self) -> Vec<String> {
+        self.names.keys().cloned().collect()
+    }
+
+    /// Returns whether `bucket` is the default bucket or a bucket of the catalog.
+    pub fn contains(&self, bucket: BucketId) -> bool {
+        bucket == DEFAULT_BUCKET || self.ids.contains(&bucket)
+    }
+
+    /// Returns whether `bucket` has been dropped. The ids which were never allocated by this
+    /// catalog, e.g. those of the entries received from another `Cask` through replication, aren't
+    /// considered dropped.
+    pub fn is_dropped(&self, bucket: BucketId) -> bool {
+        bucket != DEFAULT_BUCKET && bucket < self.next_id && !self.ids.contains(&bucket)
+    }
+
+    /// Adds a bucket named `name` to the catalog, returning its id.
+    pub fn create(&mut self, name: &str) -> Result<BucketId> {
+        if name.is_empty() || name.contains('\n') || name.contains('\r') {
+            return Err(Error::InvalidBucket(name.to_string()));
+        }
+
+        let id = self.next_id;
+
+        self.names.insert(name.to_string(), id);
+        self.ids.insert(id);
+        self.next_id += 1;
+
+        if let Err(err) = self.write() {
+            self.names.remove(name);
+            self.ids.remove(&id);
+            self.next_id -= 1;
+            return Err(err);
+        }
+
+        Ok(id)
+    }
+
+    /// Removes the bucket named `name` from the catalog, returning its id if it existed.
+    pub fn remove(&mut self, name: &str) -> Result<Option<BucketId>> {
+        let id = match self.names.remove(name) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        self.ids.remove(&id);
+
+        if let Err(err) = self.write() {
+            self.names.insert(name.to_string(), id);
+            self.ids.insert(id);
+            return Err(err);
+        }
+
+        Ok(Some(id))
+    }
+
+    /// Copies the catalog to `path`, if there's one.
+    pub fn copy_to(&self, path: &Path) -> Result<()> {
+        if self.path.is_file() {
+            fs::copy(&self.path, path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the catalog file, through a temporary file so that it's never left half-written.
+    fn write(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+
+        {
+            let mut file = File::create(&tmp_path)?;
+            writeln!(file, "{}", BUCKETS_HEADER)?;
+            writeln!(file, "next {}", self.next_id)?;
+            for (name, id) in &self.names {
+                writeln!(file, "{} {}", id, name)?;
+            }
+            file.sync_all()?;
+        }
+
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+fn parse(field: &str) -> Result<BucketId> {
+    field
+        .parse()
+        .map_err(|_| invalid_catalog("invalid bucket id"))
+}
+
+fn invalid_catalog(msg: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid bucket catalog: {}", msg),
+    ))
+}
@@ -1,58 +1,207 @@
-use std::collections::hash_map::{Entry as HashMapEntry, Keys};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::Entry as HashMapEntry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::default::Default;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+#[cfg(feature = "export")]
+use std::io::{BufReader, Read, Write};
+use std::iter;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 use std::vec::Vec;
 
+#[cfg(any(feature = "memcached", feature = "server", test))]
+use byteorder::{ByteOrder, LittleEndian};
 use time;
 
-use data::{Entry, Hint, SequenceNumber};
-use errors::Result;
-use log::{Log, LogWrite};
-use stats::Stats;
-use util::human_readable_byte_count;
+use backup::{self, BackupManifest};
+use batch::{BatchOp, WriteBatch};
+use bucket::{Buckets, BUCKETS_FILE_NAME};
+use changes::{Changes, Subscribers, Subscription};
+use data::{BucketId, Entry, Hint, SequenceNumber, DEFAULT_BUCKET};
+use errors::{Error, Result};
+#[cfg(feature = "export")]
+use export::{ExportFormat, ExportReader, ExportWriter};
+use log::{EntryReader, Log, LogWrite, ValueReader, copy_files};
+use replication::{LogRecord, LogSubscribers, ReplicationStream};
+use snapshot::Snapshots;
+use stats::{BucketStats, CaskStats, FileStats, Stats};
+use util::{human_readable_byte_count, now_millis};
+use verify::{HintFile, IndexProblem, VerifyReport, verify_file};
+
+/// Number of entries written per batch by `Cask::import`.
+#[cfg(feature = "export")]
+const IMPORT_BATCH_SIZE: usize = 1000;
 
 #[derive(Debug)]
 pub struct IndexEntry {
     pub file_id: u32,
-    entry_pos: u64,
+    pub entry_pos: u64,
     pub entry_size: u64,
-    sequence: SequenceNumber,
+    pub sequence: SequenceNumber,
+    expiry: Option<u64>,
+}
+
+impl IndexEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        match self.expiry {
+            Some(expiry) => expiry <= now,
+            None => false,
+        }
+    }
+}
+
+/// The current value of a key, as passed to the function given to `Cask::update`.
+#[cfg(any(feature = "memcached", feature = "server", test))]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Version {
+    pub value: Vec<u8>,
+    pub sequence: SequenceNumber,
+    /// Expiry timestamp, in milliseconds since the epoch.
+    pub expiry: Option<u64>,
+    /// Metadata stored along with the value, see `Cask::update_with_meta`.
+    pub meta: Option<Vec<u8>>,
+}
+
+enum IndexMap {
+    Hash(HashMap<Vec<u8>, IndexEntry>),
+    Ordered(BTreeMap<Vec<u8>, IndexEntry>),
+}
+
+impl IndexMap {
+    fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
+        match *self {
+            IndexMap::Hash(ref map) => map.get(key),
+            IndexMap::Ordered(ref map) => map.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) -> Option<IndexEntry> {
+        match *self {
+            IndexMap::Hash(ref mut map) => map.insert(key, index_entry),
+            IndexMap::Ordered(ref mut map) => map.insert(key, index_entry),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        match *self {
+            IndexMap::Hash(ref mut map) => map.remove(key),
+            IndexMap::Ordered(ref mut map) => map.remove(key),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            IndexMap::Hash(ref map) => map.len(),
+            IndexMap::Ordered(ref map) => map.len(),
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a IndexEntry)> + 'a> {
+        match *self {
+            IndexMap::Hash(ref map) => Box::new(map.iter()),
+            IndexMap::Ordered(ref map) => Box::new(map.iter()),
+        }
+    }
+}
+
+/// The index of the keys of a single bucket.
+struct BucketIndex {
+    map: IndexMap,
+    stats: Stats,
+}
+
+impl BucketIndex {
+    fn new(ordered: bool) -> BucketIndex {
+        BucketIndex {
+            map: if ordered {
+                IndexMap::Ordered(BTreeMap::new())
+            } else {
+                IndexMap::Hash(HashMap::new())
+            },
+            stats: Stats::new(),
+        }
+    }
 }
 
 struct Index {
-    map: HashMap<Vec<u8>, IndexEntry>,
+    ordered: bool,
+    buckets: HashMap<BucketId, BucketIndex>,
+    /// Statistics of the entries of all buckets, including the dropped ones.
     stats: Stats,
 }
 
 impl Index {
-    fn new() -> Index {
+    fn new(ordered: bool) -> Index {
+        let mut buckets = HashMap::new();
+        buckets.insert(DEFAULT_BUCKET, BucketIndex::new(ordered));
+
         Index {
-            map: HashMap::new(),
+            ordered: ordered,
+            buckets: buckets,
             stats: Stats::new(),
         }
     }
 
-    fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
-        self.map.get(key)
+    fn create_bucket(&mut self, bucket: BucketId) {
+        let ordered = self.ordered;
+        self.buckets
+            .entry(bucket)
+            .or_insert_with(|| BucketIndex::new(ordered));
     }
 
-    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) -> Option<IndexEntry> {
+    /// Removes the keys of `bucket`, whose entries are accounted as dead.
+    fn drop_bucket(&mut self, bucket: BucketId) -> Option<BucketIndex> {
+        let bucket_index = self.buckets.remove(&bucket);
+
+        if let Some(ref bucket_index) = bucket_index {
+            self.stats.remove_all(&bucket_index.stats);
+        }
+
+        bucket_index
+    }
+
+    fn get(&self, bucket: BucketId, key: &[u8]) -> Option<&IndexEntry> {
+        self.buckets
+            .get(&bucket)
+            .and_then(|bucket_index| bucket_index.map.get(key))
+    }
+
+    fn insert(
+        &mut self,
+        bucket: BucketId,
+        key: Vec<u8>,
+        index_entry: IndexEntry,
+    ) -> Option<IndexEntry> {
+        let ordered = self.ordered;
+        let bucket_index = self
+            .buckets
+            .entry(bucket)
+            .or_insert_with(|| BucketIndex::new(ordered));
+
         self.stats.add_entry(&index_entry);
-        self.map.insert(key, index_entry).map(|entry| {
-            self.stats.remove_entry(&entry);
+        bucket_index.stats.add_entry(&index_entry);
+
+        let stats = &mut self.stats;
+        bucket_index.map.insert(key, index_entry).map(|entry| {
+            stats.remove_entry(&entry);
+            bucket_index.stats.remove_entry(&entry);
             entry
         })
     }
 
-    fn remove(&mut self, key: &[u8]) -> Option<IndexEntry> {
-        self.map.remove(key).map(|entry| {
-            self.stats.remove_entry(&entry);
+    fn remove(&mut self, bucket: BucketId, key: &[u8]) -> Option<IndexEntry> {
+        let bucket_index = self.buckets.get_mut(&bucket)?;
+
+        let stats = &mut self.stats;
+        bucket_index.map.remove(key).map(|entry| {
+            stats.remove_entry(&entry);
+            bucket_index.stats.remove_entry(&entry);
             entry
         })
     }
@@ -63,46 +212,168 @@ impl Index {
             entry_pos: hint.entry_pos,
             entry_size: hint.entry_size(),
             sequence: hint.sequence,
+            expiry: hint.expiry,
         };
 
-        match self.map.entry(hint.key.to_vec()) {
-            HashMapEntry::Occupied(mut o) => {
-                if o.get().sequence <= hint.sequence {
-                    self.stats.remove_entry(o.get());
-                    if hint.deleted {
-                        o.remove();
-                    } else {
-                        self.stats.add_entry(&index_entry);
-                        o.insert(index_entry);
-                    }
+        let current_sequence = self.get(hint.bucket, &hint.key).map(|e| e.sequence);
+
+        match current_sequence {
+            Some(sequence) if sequence > hint.sequence => {
+                self.add_dead(hint.bucket, &index_entry);
+            }
+            _ => {
+                if hint.deleted {
+                    self.remove(hint.bucket, &hint.key);
+                } else if hint.is_expired(now_millis()) {
+                    // an expired entry shadows any older entries for the same key, just like a
+                    // tombstone, but it is also accounted as dead data
+                    self.remove(hint.bucket, &hint.key);
+                    self.add_dead(hint.bucket, &index_entry);
                 } else {
-                    self.stats.add_entry(&index_entry);
-                    self.stats.remove_entry(&index_entry);
+                    self.insert(hint.bucket, hint.key.into_owned(), index_entry);
+                }
+            }
+        }
+    }
+
+    /// Accounts for an entry which isn't live.
+    fn update_dead(&mut self, hint: &Hint, file_id: u32) {
+        let index_entry = IndexEntry {
+            file_id: file_id,
+            entry_pos: hint.entry_pos,
+            entry_size: hint.entry_size(),
+            sequence: hint.sequence,
+            expiry: hint.expiry,
+        };
+
+        self.add_dead(hint.bucket, &index_entry);
+    }
+
+    fn add_dead(&mut self, bucket: BucketId, index_entry: &IndexEntry) {
+        self.stats.add_entry(index_entry);
+        self.stats.remove_entry(index_entry);
+
+        if let Some(bucket_index) = self.buckets.get_mut(&bucket) {
+            bucket_index.stats.add_entry(index_entry);
+            bucket_index.stats.remove_entry(index_entry);
+        }
+    }
+
+    fn remove_expired(&mut self, now: u64) -> usize {
+        let expired: Vec<_> = self
+            .buckets
+            .iter()
+            .flat_map(|(&bucket, bucket_index)| {
+                bucket_index
+                    .map
+                    .iter()
+                    .filter(|&(_, index_entry)| index_entry.is_expired(now))
+                    .map(move |(key, _)| (bucket, key.clone()))
+            })
+            .collect();
+
+        for &(bucket, ref key) in &expired {
+            self.remove(bucket, key);
+        }
+
+        expired.len()
+    }
+
+    fn remove_files(&mut self, files: &[u32]) {
+        self.stats.remove_files(files);
+
+        for bucket_index in self.buckets.values_mut() {
+            bucket_index.stats.remove_files(files);
+        }
+    }
+
+    /// Returns the keys and index entries of all buckets.
+    fn iter_all<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a IndexEntry)> + 'a> {
+        Box::new(
+            self.buckets
+                .values()
+                .flat_map(|bucket_index| bucket_index.map.iter()),
+        )
+    }
+
+    fn iter<'a>(
+        &'a self,
+        bucket: BucketId,
+    ) -> Box<dyn Iterator<Item = (&'a Vec<u8>, &'a IndexEntry)> + 'a> {
+        match self.buckets.get(&bucket) {
+            Some(bucket_index) => bucket_index.map.iter(),
+            None => Box::new(iter::empty()),
+        }
+    }
+
+    /// Returns the number of keys of all buckets.
+    fn len(&self) -> usize {
+        self.buckets
+            .values()
+            .map(|bucket_index| bucket_index.map.len())
+            .sum()
+    }
+
+    fn bucket_stats(&self, bucket: BucketId) -> BucketStats {
+        match self.buckets.get(&bucket) {
+            Some(bucket_index) => {
+                let (live_bytes, dead_bytes) = bucket_index.stats.bytes();
+
+                BucketStats {
+                    keys: bucket_index.map.len() as u64,
+                    live_bytes: live_bytes,
+                    dead_bytes: dead_bytes,
                 }
             }
-            HashMapEntry::Vacant(e) => {
-                if !hint.deleted {
-                    self.stats.add_entry(&index_entry);
-                    e.insert(index_entry);
+            None => BucketStats {
+                keys: 0,
+                live_bytes: 0,
+                dead_bytes: 0,
+            },
+        }
+    }
+
+    fn next_key(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Option<&Vec<u8>> {
+        match self.buckets[&DEFAULT_BUCKET].map {
+            IndexMap::Ordered(ref map) => {
+                if is_empty_range(start, end) {
+                    None
+                } else {
+                    map.range::<[u8], _>((start, end)).next().map(|e| e.0)
                 }
             }
+            IndexMap::Hash(..) => unreachable!("range lookups require an ordered index"),
         }
     }
 
-    pub fn keys(&self) -> Keys<Vec<u8>, IndexEntry> {
-        self.map.keys()
+    fn is_ordered(&self) -> bool {
+        self.ordered
     }
 }
 
 struct CaskInner {
     current_sequence: SequenceNumber,
     index: Index,
+    buckets: Buckets,
     log: Log,
+    last_compaction: Option<SystemTime>,
+    snapshots: Snapshots,
+    subscribers: Subscribers,
+    replicas: LogSubscribers,
 }
 
 impl CaskInner {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        let value = match self.index.get(key) {
+        self.read(self.index.get(DEFAULT_BUCKET, key))
+    }
+
+    fn get_at(&self, key: &[u8], sequence: SequenceNumber) -> Result<Option<Vec<u8>>> {
+        self.read(self.snapshots.get(key, self.index.get(DEFAULT_BUCKET, key), sequence))
+    }
+
+    fn read(&self, index_entry: Option<&IndexEntry>) -> Result<Option<Vec<u8>>> {
+        let value = match index_entry {
+            Some(index_entry) if index_entry.is_expired(now_millis()) => None,
             Some(index_entry) => {
                 let entry = self
                     .log
@@ -124,39 +395,417 @@ impl CaskInner {
         Ok(value)
     }
 
-    fn put(&mut self, key: Vec<u8>, value: &[u8]) -> Result<()> {
-        let index_entry = {
-            let entry = Entry::new(self.current_sequence, &*key, value)?;
+    fn get_with_version(&self, key: &[u8]) -> Result<Option<(Vec<u8>, SequenceNumber)>> {
+        match self.index.get(DEFAULT_BUCKET, key) {
+            Some(index_entry) => Ok(self
+                .read(Some(index_entry))?
+                .map(|value| (value, index_entry.sequence))),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: &[u8], expiry: Option<u64>) -> Result<SequenceNumber> {
+        self.put_in(DEFAULT_BUCKET, key, value, expiry)
+    }
+
+    fn put_in(
+        &mut self,
+        bucket: BucketId,
+        key: Vec<u8>,
+        value: &[u8],
+        expiry: Option<u64>,
+    ) -> Result<SequenceNumber> {
+        let mut entry = Entry::new(self.current_sequence, key, value)?;
+        entry.expiry = expiry;
+        entry.bucket = bucket;
 
-            let (file_id, file_pos) = self.log.append_entry(&entry)?;
+        let (file_id, entry_pos) = self.log.append_entry(&entry)?;
 
-            self.current_sequence += 1;
+        self.current_sequence += 1;
 
-            IndexEntry {
-                file_id: file_id,
-                entry_pos: file_pos,
-                entry_size: entry.size(),
-                sequence: entry.sequence,
+        Ok(self.commit_entry(file_id, entry_pos, entry))
+    }
+
+    fn put_if_version(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        sequence: SequenceNumber,
+    ) -> Result<Option<SequenceNumber>> {
+        let matches = match self.index.get(DEFAULT_BUCKET, key) {
+            Some(index_entry) => {
+                index_entry.sequence == sequence && !index_entry.is_expired(now_millis())
+            }
+            None => false,
+        };
+
+        if matches {
+            self.put(key.to_vec(), value, None).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[cfg(any(feature = "memcached", feature = "server", test))]
+    fn update<F>(
+        &mut self,
+        key: &[u8],
+        meta: Option<BucketId>,
+        f: F,
+    ) -> Result<Option<SequenceNumber>>
+    where
+        F: FnOnce(Option<Version>) -> Option<(Vec<u8>, Option<u64>, Option<Vec<u8>>)>,
+    {
+        let current = self.get_version(key, meta)?;
+
+        let (value, expiry, meta_value) = match f(current) {
+            Some(update) => update,
+            None => return Ok(None),
+        };
+
+        let sequence = self.put(key.to_vec(), &value, expiry)?;
+
+        if let Some(bucket) = meta {
+            match meta_value {
+                Some(meta_value) => {
+                    // tagged with the sequence number of the value it belongs to
+                    let mut record = vec![0u8; 8];
+                    LittleEndian::write_u64(&mut record, sequence);
+                    record.extend_from_slice(&meta_value);
+                    self.put_in(bucket, key.to_vec(), &record, expiry)?;
+                }
+                None => {
+                    self.delete_in(bucket, key)?;
+                }
+            }
+        }
+
+        Ok(Some(sequence))
+    }
+
+    #[cfg(any(feature = "memcached", feature = "server", test))]
+    fn get_version(&self, key: &[u8], meta: Option<BucketId>) -> Result<Option<Version>> {
+        let index_entry = match self.index.get(DEFAULT_BUCKET, key) {
+            Some(index_entry) => index_entry,
+            None => return Ok(None),
+        };
+
+        let value = match self.read(Some(index_entry))? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        // metadata written along with an older value of the key, e.g. before the key was written
+        // through another interface, is stale
+        let meta = match meta {
+            Some(bucket) => self.read(self.index.get(bucket, key))?.and_then(|mut record| {
+                if record.len() >= 8 && LittleEndian::read_u64(&record) == index_entry.sequence {
+                    Some(record.split_off(8))
+                } else {
+                    None
+                }
+            }),
+            None => None,
+        };
+
+        Ok(Some(Version {
+            value: value,
+            sequence: index_entry.sequence,
+            expiry: index_entry.expiry,
+            meta: meta,
+        }))
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<Option<SequenceNumber>> {
+        self.delete_in(DEFAULT_BUCKET, key)
+    }
+
+    fn delete_in(&mut self, bucket: BucketId, key: &[u8]) -> Result<Option<SequenceNumber>> {
+        // like `read`, an expired key is absent
+        match self.index.get(bucket, key) {
+            Some(index_entry) if !index_entry.is_expired(now_millis()) => {}
+            _ => return Ok(None),
+        }
+
+        let mut entry = Entry::deleted(self.current_sequence, key);
+        entry.bucket = bucket;
+        let (file_id, entry_pos) = self.log.append_entry(&entry)?;
+
+        self.current_sequence += 1;
+
+        Ok(Some(self.commit_entry(file_id, entry_pos, entry)))
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let current = self.get(key)?;
+
+        if current.as_ref().map(|value| &value[..]) != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => {
+                self.put(key.to_vec(), value, None)?;
+            }
+            None => {
+                self.delete(key)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Inserts an entry into the index, keeping the entry it replaces around for live snapshots.
+    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) {
+        let sequence = index_entry.sequence;
+
+        if self.snapshots.is_empty() {
+            self.index.insert(DEFAULT_BUCKET, key, index_entry);
+        } else if let Some(old) = self.index.insert(DEFAULT_BUCKET, key.clone(), index_entry) {
+            self.snapshots.supersede(key, old, sequence);
+        }
+    }
+
+    /// Removes an entry from the index, keeping it around for live snapshots.
+    fn remove(&mut self, key: &[u8], sequence: SequenceNumber) {
+        if let Some(old) = self.index.remove(DEFAULT_BUCKET, key) {
+            if !self.snapshots.is_empty() {
+                self.snapshots.supersede(key.to_vec(), old, sequence);
             }
+        }
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut entries = Vec::with_capacity(batch.len());
+
+        let now = now_millis();
+        // the sequence numbers are only taken once the batch is appended, so that a batch which
+        // fails doesn't leave a gap
+        let mut sequence = self.current_sequence;
+
+        for op in batch.ops {
+            let entry = match op {
+                BatchOp::Put(key, value, ttl) => {
+                    let mut entry = Entry::new(sequence, key, value)?;
+                    entry.expiry = ttl.map(|ttl| expiry(now, ttl));
+                    entry
+                }
+                BatchOp::Delete(key) => Entry::deleted(sequence, key),
+            };
+
+            entries.push(entry);
+            sequence += 1;
+        }
+
+        let (file_id, entry_positions) = self.log.append_batch(&entries)?;
+        self.current_sequence = sequence;
+
+        self.commit_batch(file_id, entries, entry_positions);
+
+        Ok(())
+    }
+
+    /// Appends the entries of a record of the append stream of another `Cask`, skipping the ones
+    /// which have already been applied.
+    fn apply(&mut self, record: &LogRecord) -> Result<()> {
+        let (batch, entries) = record.entries()?;
+
+        let applied = self.current_sequence - 1;
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| entry.sequence > applied)
+            .collect();
+
+        let sequence = match entries.last() {
+            Some(entry) => entry.sequence,
+            None => return Ok(()),
         };
 
-        self.index.insert(key, index_entry);
+        if batch {
+            let (file_id, entry_positions) = self.log.append_batch(&entries)?;
+            self.commit_batch(file_id, entries, entry_positions);
+        } else {
+            for entry in entries {
+                let (file_id, entry_pos) = self.log.append_entry(&entry)?;
+                self.commit_entry(file_id, entry_pos, entry);
+            }
+        }
+
+        self.current_sequence = sequence + 1;
 
         Ok(())
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        if self.index.remove(key).is_some() {
-            let entry = Entry::deleted(self.current_sequence, key);
-            self.log.append_entry(&entry)?;
-            self.current_sequence += 1;
+    /// Appends imported entries as a batch, either assigning them new sequence numbers or keeping
+    /// theirs if `preserve_sequence` is set, in which case they must be in increasing order and
+    /// follow the last write.
+    #[cfg(feature = "export")]
+    fn import(&mut self, mut entries: Vec<Entry>, preserve_sequence: bool) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut last = self.current_sequence - 1;
+
+        for entry in &mut entries {
+            if !preserve_sequence {
+                entry.sequence = last + 1;
+            } else if entry.sequence <= last {
+                return Err(Error::InvalidSequence {
+                    last: last,
+                    found: entry.sequence,
+                });
+            }
+
+            last = entry.sequence;
         }
 
+        let (file_id, entry_positions) = self.log.append_batch(&entries)?;
+        self.commit_batch(file_id, entries, entry_positions);
+
+        self.current_sequence = last + 1;
+
         Ok(())
     }
 
-    pub fn keys(&self) -> Keys<Vec<u8>, IndexEntry> {
-        self.index.keys()
+    fn commit_entry(&mut self, file_id: u32, entry_pos: u64, entry: Entry) -> SequenceNumber {
+        self.replicas.notify_entry(file_id, entry_pos, &entry);
+        self.commit(file_id, entry_pos, entry)
+    }
+
+    fn commit_batch(&mut self, file_id: u32, entries: Vec<Entry>, entry_positions: Vec<u64>) {
+        self.replicas
+            .notify_batch(file_id, &entries, &entry_positions);
+
+        for (entry, entry_pos) in entries.into_iter().zip(entry_positions) {
+            self.commit(file_id, entry_pos, entry);
+        }
+    }
+
+    /// Makes an entry which has been appended to the log visible.
+    fn commit(&mut self, file_id: u32, entry_pos: u64, entry: Entry) -> SequenceNumber {
+        let sequence = entry.sequence;
+
+        if entry.bucket != DEFAULT_BUCKET {
+            self.commit_to_bucket(file_id, entry_pos, entry);
+        } else if entry.deleted {
+            self.subscribers.notify(sequence, &entry.key, None);
+            self.remove(&entry.key, sequence);
+        } else {
+            self.subscribers
+                .notify(sequence, &entry.key, Some(&entry.value));
+
+            let index_entry = IndexEntry {
+                file_id: file_id,
+                entry_pos: entry_pos,
+                entry_size: entry.size(),
+                sequence: sequence,
+                expiry: entry.expiry,
+            };
+
+            self.insert(entry.key.into_owned(), index_entry);
+        }
+
+        sequence
+    }
+
+    /// Makes an entry of a named bucket visible. Named buckets aren't covered by snapshots or by
+    /// the change feed.
+    fn commit_to_bucket(&mut self, file_id: u32, entry_pos: u64, entry: Entry) {
+        if self.buckets.is_dropped(entry.bucket) {
+            let index_entry = IndexEntry {
+                file_id: file_id,
+                entry_pos: entry_pos,
+                entry_size: entry.size(),
+                sequence: entry.sequence,
+                expiry: entry.expiry,
+            };
+
+            self.index.add_dead(entry.bucket, &index_entry);
+        } else if entry.deleted {
+            self.index.remove(entry.bucket, &entry.key);
+        } else {
+            let index_entry = IndexEntry {
+                file_id: file_id,
+                entry_pos: entry_pos,
+                entry_size: entry.size(),
+                sequence: entry.sequence,
+                expiry: entry.expiry,
+            };
+
+            self.index
+                .insert(entry.bucket, entry.key.into_owned(), index_entry);
+        }
+    }
+
+    fn next_in_range(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut start = start;
+
+        while let Some(key) = self.index.next_key(start, end) {
+            if let Some(value) = self.get(key)? {
+                return Ok(Some((key.clone(), value)));
+            }
+
+            start = Bound::Excluded(key);
+        }
+
+        Ok(None)
+    }
+
+    pub fn keys<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
+        let now = now_millis();
+        Box::new(
+            self.index
+                .iter(DEFAULT_BUCKET)
+                .filter(move |&(_, index_entry)| !index_entry.is_expired(now))
+                .map(|(key, _)| key),
+        )
+    }
+
+    /// Returns the keys and index entries that are visible at `sequence`, or the live ones if no
+    /// sequence is given.
+    fn visible(&self, sequence: Option<SequenceNumber>) -> Vec<(&Vec<u8>, &IndexEntry)> {
+        let now = now_millis();
+
+        let entries: Vec<_> = match sequence {
+            None => self.index.iter(DEFAULT_BUCKET).collect(),
+            Some(sequence) => {
+                let superseded = self
+                    .snapshots
+                    .keys()
+                    .filter(|key| self.index.get(DEFAULT_BUCKET, key).is_none())
+                    .map(|key| (key, None));
+
+                self.index
+                    .iter(DEFAULT_BUCKET)
+                    .map(|(key, index_entry)| (key, Some(index_entry)))
+                    .chain(superseded)
+                    .filter_map(|(key, current)| {
+                        self.snapshots
+                            .get(key, current, sequence)
+                            .map(|index_entry| (key, index_entry))
+                    })
+                    .collect()
+            }
+        };
+
+        entries
+            .into_iter()
+            .filter(|&(_, index_entry)| !index_entry.is_expired(now))
+            .collect()
     }
 }
 
@@ -164,13 +813,60 @@ impl CaskInner {
 ///
 /// This handle can be "cheaply" cloned and safely shared between threads. `Cask`s cannot be used
 /// concurrently by separate processes and this is ensured by using a file lock in the `Cask` dir.
-#[derive(Clone)]
+///
+/// The background sync, compaction and scrub threads are stopped once the last handle is dropped,
+/// or by calling `shutdown`.
 pub struct Cask {
     path: PathBuf,
     options: CaskOptions,
-    dropped: Arc<AtomicBool>,
+    background: Arc<Background>,
     inner: Arc<RwLock<CaskInner>>,
     compaction: Arc<Mutex<()>>,
+    /// Whether this handle is counted in `Background::handles`, which isn't the case for the
+    /// handles held by the background threads themselves.
+    counted: bool,
+}
+
+/// The state shared with the background threads of a `Cask`.
+struct Background {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    handles: AtomicUsize,
+}
+
+impl Background {
+    fn new() -> Background {
+        Background {
+            stopped: Mutex::new(false),
+            condvar: Condvar::new(),
+            threads: Mutex::new(Vec::new()),
+            handles: AtomicUsize::new(1),
+        }
+    }
+
+    /// Waits for `duration` to elapse, returning `false` early if the background threads have
+    /// been stopped in the meantime.
+    fn wait(&self, duration: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .condvar
+            .wait_timeout_while(stopped, duration, |stopped| !*stopped)
+            .unwrap();
+        !*stopped
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.condvar.notify_all();
+
+        let threads: Vec<_> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                warn!("Background thread panicked");
+            }
+        }
+    }
 }
 
 /// `Cask` configuration. Provides control over the properties and behavior of the `Cask` instance.
@@ -201,6 +897,12 @@ pub struct CaskOptions {
     fragmentation_threshold: f64,
     dead_bytes_threshold: u64,
     small_file_threshold: u64,
+    ordered_index: bool,
+    corruption_policy: CorruptionPolicy,
+    scrub: bool,
+    scrub_frequency: u64,
+    follower: bool,
+    read_only: bool,
 }
 
 /// Strategy used to synchronize writes to disk.
@@ -214,6 +916,19 @@ pub enum SyncStrategy {
     Interval(usize),
 }
 
+/// Policy used when a corrupt entry is found while loading a data file whose hint file is missing
+/// or invalid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CorruptionPolicy {
+    /// Fail to open the `Cask`, returning the `Error::CorruptEntry`.
+    Fail,
+    /// Skip the corrupt entry, scanning forward to the next valid entry in the file.
+    SkipEntry,
+    /// Move the whole data file aside into the `corrupt` subdirectory and ignore all of its
+    /// entries.
+    QuarantineFile,
+}
+
 impl Default for CaskOptions {
     fn default() -> CaskOptions {
         CaskOptions {
@@ -229,6 +944,12 @@ impl Default for CaskOptions {
             fragmentation_threshold: 0.4,
             dead_bytes_threshold: 128 * 1024 * 1024,
             small_file_threshold: 10 * 1024 * 1024,
+            ordered_index: false,
+            corruption_policy: CorruptionPolicy::Fail,
+            scrub: false,
+            scrub_frequency: 24 * 3600,
+            follower: false,
+            read_only: false,
         }
     }
 }
@@ -321,34 +1042,103 @@ impl CaskOptions {
         self
     }
 
-    /// Opens/creates a `Cask` at `path`.
-    pub fn open(&self, path: &str) -> Result<Cask> {
+    /// Keep keys sorted in the in-memory index, which makes `Cask::range` and `Cask::scan_prefix`
+    /// efficient at the cost of slower point lookups. Defaults to `false`.
+    pub fn ordered_index(&mut self, ordered_index: bool) -> &mut CaskOptions {
+        self.ordered_index = ordered_index;
+        self
+    }
+
+    /// Sets the policy used when a corrupt entry is found while loading a data file. Defaults to
+    /// `CorruptionPolicy::Fail`.
+    pub fn corruption_policy(&mut self, corruption_policy: CorruptionPolicy) -> &mut CaskOptions {
+        self.corruption_policy = corruption_policy;
+        self
+    }
+
+    /// Enable or disable background scrubbing, which periodically runs `Cask::verify` and logs any
+    /// problems found. Defaults to `false`.
+    pub fn scrub(&mut self, scrub: bool) -> &mut CaskOptions {
+        self.scrub = scrub;
+        self
+    }
+
+    /// Sets the frequency of background scrubbing, in seconds. Defaults to `86400`.
+    pub fn scrub_frequency(&mut self, scrub_frequency: u64) -> &mut CaskOptions {
+        self.scrub_frequency = scrub_frequency;
+        self
+    }
+
+    /// Opens the `Cask` as a replication follower, which only accepts writes through
+    /// `Cask::apply`. Defaults to `false`.
+    pub fn follower(&mut self, follower: bool) -> &mut CaskOptions {
+        self.follower = follower;
+        self
+    }
+
+    /// Opens the `Cask` in read-only mode, in which all writes fail with `Error::ReadOnly`, no
+    /// files are ever created or modified and no background sync or compaction takes place.
+    /// Defaults to `false`.
+    ///
+    /// A read-only `Cask` takes a shared lock on its directory, so that any number of readers can
+    /// open it while no writer can. If the `Cask` is already opened by a writer it is opened
+    /// without a lock, reads may then fail once the writer compacts the data files.
+    pub fn read_only(&mut self, read_only: bool) -> &mut CaskOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Opens/creates a `Cask` at `path`.
+    ///
+    /// Data files written before the format version was recorded, i.e. by version 0.7 of this
+    /// crate, are first rewritten in the current format, unless the `Cask` is opened read-only in
+    /// which case opening it fails with `Error::UnsupportedFormat`.
+    pub fn open(&self, path: &str) -> Result<Cask> {
         Cask::open(path, self.clone())
     }
 }
 
 impl Cask {
     /// Opens/creates a new `Cask`.
-    pub fn open(path: &str, options: CaskOptions) -> Result<Cask> {
+    pub fn open(path: &str, mut options: CaskOptions) -> Result<Cask> {
         info!("Opening database: {:?}", &path);
+
+        if options.read_only {
+            options.sync = SyncStrategy::Never;
+            options.compaction = false;
+        }
+
         let mut log = Log::open(
             path,
             options.create,
             options.sync == SyncStrategy::Always,
             options.max_file_size,
             options.file_pool_size,
+            options.read_only,
         )?;
-        let mut index = Index::new();
+        let buckets = Buckets::open(&log.path)?;
+
+        let mut index = Index::new(options.ordered_index);
+        for bucket in buckets.ids() {
+            index.create_bucket(bucket);
+        }
 
         let mut sequence = 0;
 
-        for file_id in log.files() {
+        let files = log.files();
+        let last_file_id = files.last().cloned();
+
+        for file_id in files {
             let mut f = |hint: Hint| {
                 if hint.sequence > sequence {
                     sequence = hint.sequence;
                 }
 
-                index.update(hint, file_id);
+                if buckets.is_dropped(hint.bucket) {
+                    index.update_dead(&hint, file_id);
+                } else {
+                    index.update(hint, file_id);
+                }
             };
 
             match log.hints(file_id)? {
@@ -358,8 +1148,41 @@ impl Cask {
                     }
                 }
                 None => {
-                    for hint in log.recreate_hints(file_id)? {
-                        f(hint?);
+                    // the newest data file won't have a valid hint file if the process crashed
+                    // while it was being written to
+                    if Some(file_id) == last_file_id {
+                        log.truncate_torn_tail(file_id)?;
+                    }
+
+                    match options.corruption_policy {
+                        CorruptionPolicy::Fail => {
+                            for hint in log.recreate_hints(file_id, false)? {
+                                f(hint?);
+                            }
+                        }
+                        CorruptionPolicy::SkipEntry => {
+                            for hint in log.recreate_hints(file_id, true)? {
+                                f(hint?);
+                            }
+                        }
+                        CorruptionPolicy::QuarantineFile => {
+                            // hints are only applied once the whole file is known to be valid
+                            let hints: Result<Vec<_>> =
+                                log.recreate_hints(file_id, false)?.collect();
+
+                            match hints {
+                                Ok(hints) => {
+                                    for hint in hints {
+                                        f(hint);
+                                    }
+                                }
+                                Err(ref err) if err.is_corruption() => {
+                                    warn!("Found corrupt data file {}: {}", file_id, err);
+                                    log.quarantine(file_id)?;
+                                }
+                                Err(err) => return Err(err),
+                            }
+                        }
                     }
                 }
             };
@@ -371,51 +1194,42 @@ impl Cask {
         let cask = Cask {
             path: log.path.clone(),
             options: options,
-            dropped: Arc::new(AtomicBool::new(false)),
+            background: Arc::new(Background::new()),
             inner: Arc::new(RwLock::new(CaskInner {
                 current_sequence: sequence + 1,
                 log: log,
                 index: index,
+                buckets: buckets,
+                last_compaction: None,
+                snapshots: Snapshots::new(),
+                subscribers: Subscribers::new(),
+                replicas: LogSubscribers::new(),
             })),
             compaction: Arc::new(Mutex::new(())),
+            counted: true,
         };
 
         if let SyncStrategy::Interval(millis) = cask.options.sync {
-            let cask = cask.clone();
-
-            thread::spawn(move || {
+            cask.spawn_background(move |cask| {
                 let duration = Duration::from_millis(millis as u64);
                 loop {
-                    if cask.dropped.load(Ordering::SeqCst) {
-                        info!(
-                            "Cask has been dropped, background file sync \
-                             thread is exiting"
-                        );
-                        break;
-                    }
-
                     debug!("Background file sync");
                     cask.inner.read().unwrap().log.sync().unwrap();
 
-                    thread::sleep(duration);
+                    if !cask.background.wait(duration) {
+                        info!("Cask has been shut down, background file sync thread is exiting");
+                        // make sure the writes since the last sync aren't lost
+                        cask.inner.read().unwrap().log.sync().unwrap();
+                        break;
+                    }
                 }
             });
         };
 
         if cask.options.compaction {
-            let cask = cask.clone();
-
-            thread::spawn(move || {
+            cask.spawn_background(|cask| {
                 let duration = Duration::from_secs(cask.options.compaction_check_frequency);
                 loop {
-                    if cask.dropped.load(Ordering::SeqCst) {
-                        info!(
-                            "Cask has been dropped, background compaction \
-                             thread is exiting"
-                        );
-                        break;
-                    }
-
                     info!("Compaction thread wake up");
 
                     let current_hour = time::PrimitiveDateTime::now().hour() as usize;
@@ -432,12 +1246,36 @@ impl Cask {
                             "Compaction outside defined window {:?}",
                             cask.options.compaction_window
                         );
-                        continue;
                     } else if let Err(err) = cask.compact() {
                         warn!("Error during compaction: {}", err);
                     }
 
-                    thread::sleep(duration);
+                    if !cask.background.wait(duration) {
+                        info!("Cask has been shut down, background compaction thread is exiting");
+                        break;
+                    }
+                }
+            });
+        }
+
+        if cask.options.scrub {
+            cask.spawn_background(|cask| {
+                let duration = Duration::from_secs(cask.options.scrub_frequency);
+                loop {
+                    if !cask.background.wait(duration) {
+                        info!("Cask has been shut down, background scrub thread is exiting");
+                        break;
+                    }
+
+                    info!("Scrub thread wake up");
+
+                    match cask.verify() {
+                        Ok(ref report) if !report.is_ok() => {
+                            error!("Scrub found problems: {:?}", report);
+                        }
+                        Ok(..) => {}
+                        Err(err) => warn!("Error during scrub: {}", err),
+                    }
                 }
             });
         }
@@ -445,7 +1283,37 @@ impl Cask {
         Ok(cask)
     }
 
-    fn compact_files_aux(&self, files: &[u32]) -> Result<(Vec<u32>, Vec<u32>)> {
+    /// Spawns a background thread running `f` with an uncounted handle, see `Background`.
+    fn spawn_background<F: FnOnce(Cask) + Send + 'static>(&self, f: F) {
+        let cask = Cask {
+            path: self.path.clone(),
+            options: self.options.clone(),
+            background: self.background.clone(),
+            inner: self.inner.clone(),
+            compaction: self.compaction.clone(),
+            counted: false,
+        };
+
+        let thread = thread::spawn(move || f(cask));
+        self.background.threads.lock().unwrap().push(thread);
+    }
+
+    /// Stops the background sync, compaction and scrub threads, waiting for them to finish any
+    /// work in progress, e.g. a compaction. The database can still be used afterwards, but it
+    /// isn't synced or compacted in the background anymore.
+    pub fn shutdown(&self) {
+        self.background.stop();
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.options.follower || self.options.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn compact_files_aux(&self, files: &[u32], now: u64) -> Result<(Vec<u32>, Vec<u32>)> {
         let active_file_id = { self.inner.read().unwrap().log.active_file_id };
 
         let compacted_files_hints = files.iter().flat_map(|&file_id| {
@@ -477,11 +1345,42 @@ impl Cask {
             for hint in hints {
                 let hint = hint?;
                 let inner = self.inner.read().unwrap();
-                let index_entry = inner.index.get(&*hint.key);
+                let index_entry = inner.index.get(hint.bucket, &*hint.key);
+                let live = match index_entry {
+                    Some(index_entry) => index_entry.sequence == hint.sequence,
+                    None => false,
+                };
+                let retained = if hint.deleted {
+                    None
+                } else {
+                    inner.snapshots.retained(&hint.key, hint.sequence)
+                };
 
-                if hint.deleted {
+                if !hint.deleted && live {
+                    inserts.push(hint)
+                } else if let Some(superseded_at) = retained {
+                    // superseded entries still visible to a snapshot are kept, if the key has been
+                    // deleted since a tombstone is written after them so that they aren't
+                    // resurrected when the `Cask` is opened again
                     if index_entry.is_none() {
-                        match deletes.entry(hint.key.to_vec()) {
+                        match deletes.entry((hint.bucket, hint.key.to_vec())) {
+                            HashMapEntry::Occupied(mut o) => {
+                                if *o.get() < superseded_at {
+                                    o.insert(superseded_at);
+                                }
+                            }
+                            HashMapEntry::Vacant(e) => {
+                                e.insert(superseded_at);
+                            }
+                        }
+                    }
+                    inserts.push(hint)
+                } else if hint.deleted || hint.is_expired(now) {
+                    // expired entries are replaced by a tombstone so that older entries for the
+                    // same key in other files can't be resurrected, which can't happen to the
+                    // entries of dropped buckets since they're skipped when opening the `Cask`
+                    if index_entry.is_none() && !inner.buckets.is_dropped(hint.bucket) {
+                        match deletes.entry((hint.bucket, hint.key.to_vec())) {
                             HashMapEntry::Occupied(mut o) => {
                                 if *o.get() < hint.sequence {
                                     o.insert(hint.sequence);
@@ -492,8 +1391,6 @@ impl Cask {
                             }
                         }
                     }
-                } else if index_entry.is_some() && index_entry.unwrap().sequence == hint.sequence {
-                    inserts.push(hint)
                 }
             }
 
@@ -510,17 +1407,24 @@ impl Cask {
             compacted_files.push(file_id);
         }
 
-        for (key, sequence) in deletes {
-            log_writer.write(&Entry::deleted(sequence, key))?;
+        for ((bucket, key), sequence) in deletes {
+            let mut entry = Entry::deleted(sequence, key);
+            entry.bucket = bucket;
+
+            let log_write = log_writer.write(&entry)?;
+
+            if let LogWrite::NewFile(file_id) = log_write {
+                new_files.push(file_id);
+            }
         }
 
         Ok((compacted_files, new_files))
     }
 
-    fn compact_files(&self, files: &[u32]) -> Result<()> {
+    fn compact_files(&self, files: &[u32], now: u64) -> Result<()> {
         info!("Compacting data files: {:?}", files);
 
-        let (ref compacted_files, ref new_files) = self.compact_files_aux(files)?;
+        let (ref compacted_files, ref new_files) = self.compact_files_aux(files, now)?;
 
         for &file_id in new_files {
             let hints = { self.inner.read().unwrap().log.hints(file_id)? };
@@ -528,7 +1432,19 @@ impl Cask {
             if let Some(hints) = hints {
                 for hint in hints {
                     let hint = hint?;
-                    self.inner.write().unwrap().index.update(hint, file_id);
+                    let mut inner = self.inner.write().unwrap();
+
+                    inner
+                        .snapshots
+                        .relocate(&hint.key, hint.sequence, file_id, hint.entry_pos);
+
+                    if hint.deleted || inner.index.get(hint.bucket, &hint.key).is_some() {
+                        inner.index.update(hint, file_id);
+                    } else {
+                        // a superseded entry retained for a snapshot of a key that has been
+                        // deleted since, which must not be put back into the index
+                        inner.index.update_dead(&hint, file_id);
+                    }
                 }
             };
         }
@@ -537,14 +1453,13 @@ impl Cask {
             .write()
             .unwrap()
             .index
-            .stats
             .remove_files(compacted_files);
 
-        self.inner
-            .write()
-            .unwrap()
-            .log
-            .swap_files(compacted_files, new_files)?;
+        {
+            let mut inner = self.inner.write().unwrap();
+            inner.log.swap_files(compacted_files, new_files)?;
+            inner.last_compaction = Some(SystemTime::now());
+        }
 
         // FIXME: print files not compacted
         info!(
@@ -557,8 +1472,19 @@ impl Cask {
 
     /// Trigger `Cask` log compaction.
     pub fn compact(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+
         let _lock = self.compaction.lock().unwrap();
 
+        let now = now_millis();
+
+        let expired = { self.inner.write().unwrap().index.remove_expired(now) };
+        if expired > 0 {
+            info!("Removed {} expired entries from the index", expired);
+        }
+
         let active_file_id = { self.inner.read().unwrap().log.active_file_id };
 
         let file_stats = { self.inner.read().unwrap().index.stats.file_stats() };
@@ -626,7 +1552,7 @@ impl Cask {
 
         if triggered {
             let files: Vec<_> = files.into_iter().collect();
-            self.compact_files(&files)?;
+            self.compact_files(&files, now)?;
         } else if !files.is_empty() {
             info!(
                 "Compaction of files {:?} aborted due to missing trigger",
@@ -639,71 +1565,2397 @@ impl Cask {
         Ok(())
     }
 
+    /// Verifies the integrity of the `Cask` by reading every entry in every data file and checking
+    /// its checksum, and by cross-checking every hint file against its data file and the in-memory
+    /// index. Compaction is held off while the verification is running, writes are not.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _lock = self.compaction.lock().unwrap();
+
+        let (files, active_file, mut index_entries, orphan_files) = {
+            let inner = self.inner.read().unwrap();
+
+            let files = inner.log.files();
+
+            let active_file = match inner.log.active_file_id {
+                Some(file_id) => Some((file_id, inner.log.file_size(file_id)?)),
+                None => None,
+            };
+
+            let mut index_entries = HashMap::new();
+            for (key, index_entry) in inner.index.iter_all() {
+                index_entries
+                    .entry(index_entry.file_id)
+                    .or_insert_with(Vec::new)
+                    .push((key.clone(), index_entry.entry_pos, index_entry.sequence));
+            }
+
+            let mut file_ids = files.clone();
+            file_ids.extend(active_file.map(|f| f.0));
+            let orphan_files = inner.log.orphan_files(&file_ids)?;
+
+            (files, active_file, index_entries, orphan_files)
+        };
+
+        let mut report = VerifyReport {
+            orphan_files: orphan_files,
+            ..VerifyReport::default()
+        };
+
+        for file_id in files {
+            let (entries, hint_file) = {
+                let log = &self.inner.read().unwrap().log;
+                let hint_file = HintFile {
+                    hints: log.hints(file_id).unwrap_or(None),
+                    exists: log.has_hint_file(file_id),
+                };
+                (log.entries(file_id)?, hint_file)
+            };
+
+            let index_entries = index_entries.remove(&file_id).unwrap_or_default();
+
+            verify_file(
+                file_id,
+                entries,
+                Some(hint_file),
+                &index_entries,
+                &mut report,
+            )?;
+        }
+
+        // the hint file of the active data file is only complete once the file is closed
+        if let Some((file_id, data_file_size)) = active_file {
+            let mut entries = { self.inner.read().unwrap().log.entries(file_id)? };
+            entries.set_len(data_file_size)?;
+
+            let index_entries = index_entries.remove(&file_id).unwrap_or_default();
+
+            verify_file(file_id, entries, None, &index_entries, &mut report)?;
+        }
+
+        // any remaining index entries point to files that don't exist
+        for (file_id, index_entries) in index_entries {
+            for (key, entry_pos, _) in index_entries {
+                report.index_problems.push(IndexProblem {
+                    key: key,
+                    file_id: file_id,
+                    entry_pos: entry_pos,
+                });
+            }
+        }
+
+        if report.is_ok() {
+            info!(
+                "Verified {} entries in {} data files",
+                report.entries, report.data_files
+            );
+        } else {
+            warn!(
+                "Verification found {} corrupt entries, {} hint file problems, {} index \
+                 problems and {} orphan files",
+                report.corrupt_entries.len(),
+                report.hint_file_problems.len(),
+                report.index_problems.len(),
+                report.orphan_files.len()
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Returns statistics about the `Cask` and each of its data files.
+    pub fn stats(&self) -> Result<CaskStats> {
+        let inner = self.inner.read().unwrap();
+
+        let mut file_ids = inner.log.files();
+        file_ids.extend(inner.log.active_file_id);
+
+        let mut files = Vec::with_capacity(file_ids.len());
+        let mut disk_size = 0;
+
+        for file_id in file_ids {
+            let size = inner.log.file_size(file_id)?;
+            disk_size += size + inner.log.hint_file_size(file_id)?;
+
+            let file_stats = match inner.index.stats.get(file_id) {
+                Some(stats) => FileStats {
+                    file_id: file_id,
+                    size: size,
+                    entries: stats.entries,
+                    dead_entries: stats.dead_entries,
+                    live_bytes: stats.bytes - stats.dead_bytes,
+                    dead_bytes: stats.dead_bytes,
+                    fragmentation: stats.dead_entries as f64 / stats.entries as f64,
+                },
+                None => FileStats {
+                    file_id: file_id,
+                    size: size,
+                    entries: 0,
+                    dead_entries: 0,
+                    live_bytes: 0,
+                    dead_bytes: 0,
+                    fragmentation: 0.0,
+                },
+            };
+
+            files.push(file_stats);
+        }
+
+        Ok(CaskStats {
+            keys: inner.index.len() as u64,
+            live_bytes: files.iter().map(|f| f.live_bytes).sum(),
+            dead_bytes: files.iter().map(|f| f.dead_bytes).sum(),
+            disk_size: disk_size,
+            sequence: inner.current_sequence - 1,
+            active_file_id: inner.log.active_file_id,
+            last_compaction: inner.last_compaction,
+            files: files,
+        })
+    }
+
     /// Returns the value corresponding to the key, if any.
     pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
         self.inner.read().unwrap().get(key.as_ref())
     }
 
-    /// Inserts a key-value pair into the map.
-    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
-        self.inner.write().unwrap().put(key.into(), value.as_ref())
+    /// Returns a reader over the value corresponding to the key, if any, which reads the value
+    /// from its data file as it's consumed instead of loading it in memory. See `ValueReader`.
+    pub fn get_reader<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<ValueReader>> {
+        let inner = self.inner.read().unwrap();
+
+        match inner.index.get(DEFAULT_BUCKET, key.as_ref()) {
+            Some(index_entry) if !index_entry.is_expired(now_millis()) => inner
+                .log
+                .value_reader(index_entry.file_id, index_entry.entry_pos),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the value corresponding to the key along with the sequence number of the write
+    /// which stored it, if any.
+    pub fn get_with_version<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+    ) -> Result<Option<(Vec<u8>, SequenceNumber)>> {
+        self.inner.read().unwrap().get_with_version(key.as_ref())
+    }
+
+    /// Inserts a key-value pair into the map, returning the sequence number assigned to the write.
+    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<SequenceNumber> {
+        self.check_writable()?;
+        self.inner
+            .write()
+            .unwrap()
+            .put(key.into(), value.as_ref(), None)
+    }
+
+    /// Inserts a key-value pair into the map which expires after `ttl`, returning the sequence
+    /// number assigned to the write. Once expired the key is treated as absent and its data is
+    /// reclaimed by compaction.
+    pub fn put_with_ttl<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<SequenceNumber> {
+        self.check_writable()?;
+        let expiry = expiry(now_millis(), ttl);
+        self.inner
+            .write()
+            .unwrap()
+            .put(key.into(), value.as_ref(), Some(expiry))
+    }
+
+    /// Inserts a key-value pair into the map only if the current value of the key was stored by
+    /// the write with sequence number `sequence` (see `get_with_version`), returning the sequence
+    /// number assigned to the write if it took place.
+    pub fn put_if_version<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        sequence: SequenceNumber,
+    ) -> Result<Option<SequenceNumber>> {
+        self.check_writable()?;
+        self.inner
+            .write()
+            .unwrap()
+            .put_if_version(key.as_ref(), value.as_ref(), sequence)
+    }
+
+    /// Atomically reads the current value of a key, if any, and replaces it with the value and
+    /// expiry timestamp (in milliseconds since the epoch) returned by `f`, returning the sequence
+    /// number assigned to the write. Nothing is written if `f` returns `None`.
+    ///
+    /// Other writes are blocked while `f` runs, so it should return quickly.
+    #[cfg(any(feature = "server", test))]
+    pub(crate) fn update<K, F>(&self, key: K, f: F) -> Result<Option<SequenceNumber>>
+    where
+        K: AsRef<[u8]>,
+        F: FnOnce(Option<Version>) -> Option<(Vec<u8>, Option<u64>)>,
+    {
+        self.check_writable()?;
+        self.inner
+            .write()
+            .unwrap()
+            .update(key.as_ref(), None, |current| {
+                f(current).map(|(value, expiry)| (value, expiry, None))
+            })
+    }
+
+    /// Like `update`, but also stores the metadata returned by `f`, if any, under the same key in
+    /// the bucket `meta`. The metadata is part of the `Version` passed to `f` and returned by
+    /// `get_with_meta` until the key is written again: writes that don't go through this method
+    /// leave it behind as stale, and it is ignored.
+    #[cfg(feature = "memcached")]
+    pub(crate) fn update_with_meta<K, F>(
+        &self,
+        key: K,
+        meta: &Bucket,
+        f: F,
+    ) -> Result<Option<SequenceNumber>>
+    where
+        K: AsRef<[u8]>,
+        F: FnOnce(Option<Version>) -> Option<(Vec<u8>, Option<u64>, Option<Vec<u8>>)>,
+    {
+        self.check_writable()?;
+        let mut inner = self.inner.write().unwrap();
+        meta.check(&inner)?;
+        inner.update(key.as_ref(), Some(meta.id), f)
     }
 
-    /// Removes a key from the map.
-    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+    /// Returns the current value of a key along with its metadata stored in the bucket `meta`,
+    /// see `update_with_meta`.
+    #[cfg(feature = "memcached")]
+    pub(crate) fn get_with_meta<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        meta: &Bucket,
+    ) -> Result<Option<Version>> {
+        let inner = self.inner.read().unwrap();
+        meta.check(&inner)?;
+        inner.get_version(key.as_ref(), Some(meta.id))
+    }
+
+    /// Removes a key from the map, returning the sequence number assigned to the write if the key
+    /// was present.
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<SequenceNumber>> {
+        self.check_writable()?;
         self.inner.write().unwrap().delete(key.as_ref())
     }
 
-    /// Returns all keys stored in the map.
-    pub fn keys(&self) -> Vec<Vec<u8>> {
-        self.inner.read().unwrap().keys().cloned().collect()
+    /// Atomically replaces the value of a key with `new` if its current value is `expected`,
+    /// returning whether the swap took place. `None` stands for an absent key, either as the
+    /// expected value or as the new one in which case the key is removed.
+    pub fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        self.inner
+            .write()
+            .unwrap()
+            .compare_and_swap(key.as_ref(), expected, new)
     }
-}
 
-impl Drop for Cask {
-    fn drop(&mut self) {
-        self.dropped.store(true, Ordering::SeqCst);
+    /// Inserts a key-value pair into the map only if the key isn't present, returning whether it
+    /// was inserted.
+    pub fn put_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value.as_ref()))
+    }
+
+    /// Removes a key from the map only if its current value is `expected`, returning whether it
+    /// was removed.
+    pub fn delete_if_equals<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: V,
+    ) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected.as_ref()), None)
+    }
+
+    /// Applies all writes in `batch` atomically, i.e. after a crash either all of them or none of
+    /// them will be visible.
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        self.inner.write().unwrap().write_batch(batch)
+    }
+
+    /// Creates a consistent copy of the `Cask` at `path`, which can be opened on its own, while
+    /// reads and writes keep being served. `path` must either not exist or be an empty directory.
+    ///
+    /// The active data file is closed first so that all data files are immutable, they are then
+    /// hard-linked into `path` (or copied if `path` is on a different file system). Compaction is
+    /// held off until the checkpoint is done.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let dest = path.as_ref();
+
+        if !dest.exists() {
+            fs::create_dir_all(dest)?;
+        } else if !dest.is_dir() || fs::read_dir(dest)?.next().is_some() {
+            return Err(Error::InvalidPath(dest.to_string_lossy().into_owned()));
+        }
+
         let _lock = self.compaction.lock().unwrap();
+
+        let files = {
+            let mut inner = self.inner.write().unwrap();
+            inner.log.rotate();
+            inner.buckets.copy_to(&dest.join(BUCKETS_FILE_NAME))?;
+            inner.log.files()
+        };
+
+        info!("Checkpointing data files {:?} to {:?}", files, dest);
+
+        copy_files(&self.path, dest, &files, true)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use cask::CaskOptions;
-    use std::fs;
+    /// Backs up the `Cask` into the backup directory `path`, only copying the data files which
+    /// aren't already part of the `previous` backup, and returns the manifest of the backup which
+    /// is also written into `path`. The backup can be restored with `BackupManifest::restore`.
+    ///
+    /// As with `checkpoint`, the active data file is closed first and compaction is held off
+    /// until the backup is done.
+    pub fn backup_incremental<P: AsRef<Path>>(
+        &self,
+        path: P,
+        previous: Option<&BackupManifest>,
+    ) -> Result<BackupManifest> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
 
-    #[test]
-    fn test_keys() {
-        let cask_result = CaskOptions::default()
-            .compaction_check_frequency(1)
-            .max_file_size(50 * 1024 * 1024)
-            .open("test.db");
+        let dest = path.as_ref();
 
-        assert!(cask_result.is_ok());
+        if !dest.exists() {
+            fs::create_dir_all(dest)?;
+        }
 
-        let cask = cask_result.unwrap();
+        let _lock = self.compaction.lock().unwrap();
 
-        let key1: &[u8] = &[0];
-        let key2: &[u8] = &[1];
-        let key3: &[u8] = &[2];
+        let id = backup::next_id(dest)?;
 
-        let val: &[u8] = &[0];
+        let (sequence, files) = {
+            let mut inner = self.inner.write().unwrap();
+            let sequence = inner.current_sequence - 1;
+            inner.log.rotate();
+            inner.buckets.copy_to(&backup::buckets_path(dest, id))?;
+            (sequence, inner.log.files())
+        };
 
-        assert!(cask.put(key1, val).is_ok());
-        assert!(cask.put(key2, val).is_ok());
-        assert!(cask.put(key3, val).is_ok());
-        assert!(cask.delete(key3).is_ok());
+        let manifest = BackupManifest::new(id, sequence, files, previous);
 
-        let mut keys = cask.keys();
+        info!(
+            "Backing up data files {:?} to {:?}, retired data files: {:?}",
+            manifest.added, dest, manifest.retired
+        );
 
-        //Keys are not guaranteed to be in order.
-        keys.sort();
+        // unlike checkpoints, backups never share data files with the `Cask`, so that they aren't
+        // affected by anything happening to its data files afterwards
+        copy_files(&self.path, dest, &manifest.added, false)?;
+        let manifest_path = manifest.write(dest)?;
 
-        assert_eq!(keys.len(), 2);
+        info!("Wrote backup manifest {:?}", manifest_path);
 
-        assert_eq!(keys[0], key1);
-        assert_eq!(keys[1], key2);
+        Ok(manifest)
+    }
 
-        assert!(fs::remove_dir_all("test.db").is_ok());
+    /// Writes the live key-value pairs to `writer` in the given `format`, in sequence order,
+    /// returning how many were exported. The export reflects the state of the `Cask` when it's
+    /// called, writes made in the meantime are left out.
+    #[cfg(feature = "export")]
+    pub fn export<W: Write>(&self, writer: W, format: ExportFormat) -> Result<u64> {
+        let snapshot = self.snapshot();
+
+        let mut entries: Vec<_> = {
+            let inner = self.inner.read().unwrap();
+            inner
+                .visible(Some(snapshot.sequence()))
+                .into_iter()
+                .map(|(key, index_entry)| {
+                    (index_entry.sequence, key.clone(), index_entry.expiry)
+                })
+                .collect()
+        };
+        entries.sort();
+
+        let mut writer = ExportWriter::new(writer, format)?;
+        let mut exported = 0;
+
+        for (sequence, key, expiry) in entries {
+            // the entry may have expired since
+            if let Some(value) = snapshot.get(&key)? {
+                let mut entry = Entry::new(sequence, key, value)?;
+                entry.expiry = expiry;
+
+                writer.write(&entry)?;
+                exported += 1;
+            }
+        }
+
+        writer.finish()?;
+
+        Ok(exported)
+    }
+
+    /// Imports the key-value pairs read from `reader` in the given `format`, as written by
+    /// `export`, returning how many were imported. They're written in batches, so an import which
+    /// fails part way leaves the pairs of the batches written until then.
+    ///
+    /// The imported pairs are assigned new sequence numbers unless `preserve_sequence` is set, in
+    /// which case they keep the sequence numbers of the export. These must be in increasing order
+    /// and greater than the sequence number of the last write, e.g. when importing into an empty
+    /// `Cask`, otherwise `Error::InvalidSequence` is returned.
+    #[cfg(feature = "export")]
+    pub fn import<R: Read>(
+        &self,
+        reader: R,
+        format: ExportFormat,
+        preserve_sequence: bool,
+    ) -> Result<u64> {
+        self.check_writable()?;
+
+        let reader = ExportReader::new(BufReader::new(reader), format)?;
+        let now = now_millis();
+
+        let mut entries = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        let mut imported = 0;
+
+        for record in reader {
+            let (sequence, entry) = record?;
+
+            if preserve_sequence && sequence.is_none() {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid export: missing sequence number",
+                )));
+            }
+
+            if entry.is_expired(now) {
+                continue;
+            }
+
+            entries.push(entry);
+
+            if entries.len() == IMPORT_BATCH_SIZE {
+                imported += entries.len() as u64;
+                self.inner.write().unwrap().import(entries, preserve_sequence)?;
+                entries = Vec::with_capacity(IMPORT_BATCH_SIZE);
+            }
+        }
+
+        imported += entries.len() as u64;
+        self.inner.write().unwrap().import(entries, preserve_sequence)?;
+
+        Ok(imported)
+    }
+
+    /// Returns the sequence number of the last write.
+    pub fn sequence(&self) -> SequenceNumber {
+        self.inner.read().unwrap().current_sequence - 1
+    }
+
+    /// Returns the append stream of this `Cask` starting after the write with sequence number
+    /// `sequence`, to be applied to a follower with `Cask::apply`.
+    ///
+    /// The entries still present in the data files are streamed first, like `changes_since` but
+    /// including the entries of named buckets, so a follower which is too far behind ends up with
+    /// the current state of the map but not with every intermediate write.
+    pub fn replicate(&self, sequence: SequenceNumber) -> Result<ReplicationStream> {
+        let live = self.inner.write().unwrap().replicas.subscribe();
+        let catch_up = Changes::new(&self.inner.read().unwrap().log, sequence, true)?;
+
+        Ok(ReplicationStream::new(catch_up, live, sequence))
+    }
+
+    /// Applies a record of the append stream of a leader `Cask` to this follower, see
+    /// `Cask::replicate`. Records which have already been applied are ignored.
+    ///
+    /// Fails with `Error::NotFollower` if this `Cask` wasn't opened with `CaskOptions::follower`,
+    /// since the records could otherwise mix with its own writes.
+    pub fn apply(&self, record: &LogRecord) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if !self.options.follower {
+            return Err(Error::NotFollower);
+        }
+
+        self.inner.write().unwrap().apply(record)
+    }
+
+    /// Returns a feed of all writes committed from now on.
+    ///
+    /// Changes are buffered until they are consumed, so a subscription should be dropped once it
+    /// isn't needed anymore. To observe every change after a given sequence number without gaps,
+    /// subscribe first and then replay the older changes with `changes_since`, skipping the changes
+    /// of the subscription which have already been replayed.
+    pub fn subscribe(&self) -> Subscription {
+        self.inner.write().unwrap().subscribers.subscribe()
+    }
+
+    /// Returns an iterator over the changes with a sequence number greater than `sequence` that
+    /// are still present in the data files, in sequence order. Like `subscribe`, it only covers the
+    /// default bucket.
+    ///
+    /// Compaction drops entries which have been updated or deleted, so the changes replayed may
+    /// not include every write ever made but they always lead to the current state of the map.
+    pub fn changes_since(&self, sequence: SequenceNumber) -> Result<Changes> {
+        Changes::new(&self.inner.read().unwrap().log, sequence, false)
+    }
+
+    /// Returns all keys stored in the map.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.inner.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns a lazy iterator over all key-value pairs stored in the map.
+    ///
+    /// Entries are read in the order they are laid out in the log rather than in key order, so that
+    /// iterating the whole map results in sequential reads of each data file.
+    pub fn iter(&self) -> Iter {
+        Iter::new(self.inner.clone(), self.snapshot())
+    }
+
+    /// Returns a snapshot of the map as of the last write, which keeps seeing the same data
+    /// regardless of the writes that happen afterwards.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.inner.clone())
+    }
+
+    /// Returns a lazy iterator over the key-value pairs whose keys are within `range`, in key
+    /// order. Values are only read from the log as the iterator advances.
+    ///
+    /// If the `Cask` wasn't opened with an ordered index the matching keys are collected and sorted
+    /// upfront.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Range {
+        Range::new(
+            self.inner.clone(),
+            to_owned_bound(range.start_bound()),
+            to_owned_bound(range.end_bound()),
+        )
+    }
+
+    /// Returns a lazy iterator over the key-value pairs whose keys start with `prefix`, in key
+    /// order.
+    pub fn scan_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> Range {
+        let prefix = prefix.as_ref();
+
+        let end = match prefix_successor(prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+
+        Range::new(self.inner.clone(), Bound::Included(prefix.to_vec()), end)
+    }
+
+    /// Returns a handle to the bucket named `name`, creating it if it doesn't exist yet.
+    ///
+    /// Buckets are separate keyspaces stored in the same data files as the keys of the `Cask`
+    /// itself, which make up the default bucket: the same key can be stored in several buckets
+    /// with different values. Snapshots, the change feed, iterators, export and import only cover
+    /// the default bucket.
+    ///
+    /// The names of the buckets are kept in a catalog next to the data files, which isn't part of
+    /// the replication stream: followers receive the entries of the buckets of their leader but
+    /// can't open them by name.
+    pub fn bucket(&self, name: &str) -> Result<Bucket> {
+        let mut inner = self.inner.write().unwrap();
+
+        let id = match inner.buckets.get(name) {
+            Some(id) => id,
+            None => {
+                self.check_writable()?;
+                let id = inner.buckets.create(name)?;
+                inner.index.create_bucket(id);
+                id
+            }
+        };
+
+        Ok(Bucket {
+            cask: self.clone(),
+            name: name.to_string(),
+            id: id,
+        })
+    }
+
+    /// Drops the bucket named `name` along with all of its keys, returning whether it existed.
+    ///
+    /// Only the bucket catalog is written to, the entries of the bucket are left in the data files
+    /// until compaction reclaims their space. Handles to the dropped bucket fail with
+    /// `Error::InvalidBucket` afterwards.
+    pub fn drop_bucket(&self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+
+        let dropped = {
+            let mut inner = self.inner.write().unwrap();
+
+            match inner.buckets.remove(name)? {
+                Some(id) => inner.index.drop_bucket(id),
+                None => return Ok(false),
+            }
+        };
+
+        // the keys of the bucket are freed once the lock has been released
+        drop(dropped);
+
+        Ok(true)
+    }
+
+    /// Returns the names of the buckets, in alphabetical order.
+    pub fn buckets(&self) -> Vec<String> {
+        self.inner.read().unwrap().buckets.names()
+    }
+}
+
+/// An iterator over a range of key-value pairs in a `Cask`, see `Cask::range` and
+/// `Cask::scan_prefix`.
+///
+/// The iterator doesn't hold any lock between calls to `next`, writes that happen concurrently
+/// may or may not be observed.
+pub struct Range {
+    inner: Arc<RwLock<CaskInner>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    keys: Option<VecDeque<Vec<u8>>>,
+}
+
+impl Range {
+    fn new(inner: Arc<RwLock<CaskInner>>, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Range {
+        let keys = {
+            let inner = inner.read().unwrap();
+
+            if inner.index.is_ordered() {
+                None
+            } else {
+                let mut keys: Vec<_> = inner
+                    .keys()
+                    .filter(|key| {
+                        (as_slice_bound(&start), as_slice_bound(&end)).contains(&key[..])
+                    })
+                    .cloned()
+                    .collect();
+                keys.sort();
+                Some(keys.into_iter().collect())
+            }
+        };
+
+        Range {
+            inner: inner,
+            start: start,
+            end: end,
+            keys: keys,
+        }
+    }
+}
+
+impl Iterator for Range {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let inner = self.inner.read().unwrap();
+
+        let next = match self.keys {
+            Some(ref mut keys) => {
+                let mut next = Ok(None);
+                while let Some(key) = keys.pop_front() {
+                    match inner.get(&key) {
+                        Ok(Some(value)) => {
+                            next = Ok(Some((key, value)));
+                            break;
+                        }
+                        Ok(None) => continue,
+                        Err(err) => {
+                            next = Err(err);
+                            break;
+                        }
+                    }
+                }
+                next
+            }
+            None => inner.next_in_range(as_slice_bound(&self.start), as_slice_bound(&self.end)),
+        };
+
+        match next {
+            Ok(Some((key, value))) => {
+                self.start = Bound::Excluded(key.clone());
+                Some(Ok((key, value)))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// An iterator over all key-value pairs in a `Cask`, see `Cask::iter`.
+///
+/// The iterator yields the entries that were live when it was created, writes that happen
+/// afterwards are not observed. It holds a snapshot until it is dropped, see `Cask::snapshot`.
+pub struct Iter {
+    inner: Arc<RwLock<CaskInner>>,
+    snapshot: Snapshot,
+    entries: VecDeque<(u32, u64, Vec<u8>)>,
+    reader: Option<(u32, Option<EntryReader>)>,
+}
+
+impl Iter {
+    fn new(inner: Arc<RwLock<CaskInner>>, snapshot: Snapshot) -> Iter {
+        let entries = {
+            let inner = inner.read().unwrap();
+
+            let mut entries: Vec<_> = inner
+                .visible(Some(snapshot.sequence))
+                .into_iter()
+                .map(|(key, index_entry)| {
+                    (index_entry.file_id, index_entry.entry_pos, key.clone())
+                })
+                .collect();
+            entries.sort();
+            entries.into_iter().collect()
+        };
+
+        Iter {
+            inner: inner,
+            snapshot: snapshot,
+            entries: entries,
+            reader: None,
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        while let Some((file_id, entry_pos, key)) = self.entries.pop_front() {
+            let current_file_id = self.reader.as_ref().map(|r| r.0);
+            if current_file_id != Some(file_id) {
+                let reader = match self.inner.read().unwrap().log.reader(file_id) {
+                    Ok(reader) => Some(reader),
+                    Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::NotFound => {
+                        // the file was removed by compaction in the meantime, its live entries
+                        // were moved to other files so we fall back to point lookups
+                        None
+                    }
+                    Err(err) => return Some(Err(err)),
+                };
+
+                self.reader = Some((file_id, reader));
+            }
+
+            let value = match self.reader {
+                Some((_, Some(ref mut reader))) => reader.read_entry(entry_pos).map(|entry| {
+                    if entry.deleted || entry.is_expired(now_millis()) {
+                        None
+                    } else {
+                        Some(entry.value.into_owned())
+                    }
+                }),
+                _ => self
+                    .inner
+                    .read()
+                    .unwrap()
+                    .get_at(&key, self.snapshot.sequence),
+            };
+
+            match value {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        None
+    }
+}
+
+/// A consistent, read-only view of a `Cask` as of a sequence number, see `Cask::snapshot`.
+///
+/// Writes that happen after the snapshot was taken are not observed. While a snapshot is alive,
+/// compaction retains the entries it can see, so snapshots should not be held onto for longer than
+/// needed.
+pub struct Snapshot {
+    inner: Arc<RwLock<CaskInner>>,
+    sequence: SequenceNumber,
+}
+
+impl Snapshot {
+    fn new(inner: Arc<RwLock<CaskInner>>) -> Snapshot {
+        let sequence = {
+            let mut inner = inner.write().unwrap();
+            let sequence = inner.current_sequence - 1;
+            inner.snapshots.register(sequence);
+            sequence
+        };
+
+        Snapshot {
+            inner: inner,
+            sequence: sequence,
+        }
+    }
+
+    /// Returns the sequence number of the last write visible to this snapshot.
+    pub fn sequence(&self) -> SequenceNumber {
+        self.sequence
+    }
+
+    /// Returns the value corresponding to the key as of this snapshot, if any.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .read()
+            .unwrap()
+            .get_at(key.as_ref(), self.sequence)
+    }
+
+    /// Returns all keys stored in the map as of this snapshot.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.inner
+            .read()
+            .unwrap()
+            .visible(Some(self.sequence))
+            .into_iter()
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Returns a lazy iterator over all key-value pairs stored in the map as of this snapshot, in
+    /// log order like `Cask::iter`.
+    pub fn iter(&self) -> Iter {
+        Iter::new(self.inner.clone(), self.clone())
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Snapshot {
+        self.inner.write().unwrap().snapshots.register(self.sequence);
+
+        Snapshot {
+            inner: self.inner.clone(),
+            sequence: self.sequence,
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner.write().unwrap().snapshots.release(self.sequence);
+    }
+}
+
+/// A handle to a named bucket of a `Cask`, see `Cask::bucket`.
+///
+/// This handle can be cloned and shared between threads like the `Cask` itself.
+#[derive(Clone)]
+pub struct Bucket {
+    cask: Cask,
+    name: String,
+    id: BucketId,
+}
+
+impl Bucket {
+    /// Returns the name of the bucket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the id of the bucket, which is stored in each of its entries.
+    pub fn id(&self) -> BucketId {
+        self.id
+    }
+
+    /// Returns the value corresponding to the key, if any.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        let inner = self.cask.inner.read().unwrap();
+        self.check(&inner)?;
+        inner.read(inner.index.get(self.id, key.as_ref()))
+    }
+
+    /// Inserts a key-value pair into the bucket, returning the sequence number assigned to the
+    /// write.
+    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<SequenceNumber> {
+        self.cask.check_writable()?;
+        let mut inner = self.cask.inner.write().unwrap();
+        self.check(&inner)?;
+        inner.put_in(self.id, key.into(), value.as_ref(), None)
+    }
+
+    /// Inserts a key-value pair into the bucket which expires after `ttl`, see
+    /// `Cask::put_with_ttl`.
+    pub fn put_with_ttl<K: Into<Vec<u8>>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<SequenceNumber> {
+        self.cask.check_writable()?;
+        let expiry = expiry(now_millis(), ttl);
+        let mut inner = self.cask.inner.write().unwrap();
+        self.check(&inner)?;
+        inner.put_in(self.id, key.into(), value.as_ref(), Some(expiry))
+    }
+
+    /// Removes a key from the bucket, returning the sequence number assigned to the write if the
+    /// key was present.
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<SequenceNumber>> {
+        self.cask.check_writable()?;
+        let mut inner = self.cask.inner.write().unwrap();
+        self.check(&inner)?;
+        inner.delete_in(self.id, key.as_ref())
+    }
+
+    /// Returns all keys stored in the bucket.
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let inner = self.cask.inner.read().unwrap();
+        self.check(&inner)?;
+
+        let now = now_millis();
+        Ok(inner
+            .index
+            .iter(self.id)
+            .filter(|&(_, index_entry)| !index_entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    /// Returns statistics about the keys of the bucket and the data they occupy.
+    pub fn stats(&self) -> Result<BucketStats> {
+        let inner = self.cask.inner.read().unwrap();
+        self.check(&inner)?;
+        Ok(inner.index.bucket_stats(self.id))
+    }
+
+    fn check(&self, inner: &CaskInner) -> Result<()> {
+        if inner.buckets.contains(self.id) {
+            Ok(())
+        } else {
+            Err(Error::InvalidBucket(self.name.clone()))
+        }
+    }
+}
+
+/// Returns the expiry timestamp of a write made at `now` with the given `ttl`, which saturates so
+/// that TTLs too large to be represented never expire.
+pub(crate) fn expiry(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(ttl.as_secs().saturating_mul(1000))
+        .saturating_add(u64::from(ttl.subsec_millis()))
+}
+
+fn to_owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn as_slice_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match *bound {
+        Bound::Included(ref key) => Bound::Included(key),
+        Bound::Excluded(ref key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn is_empty_range(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Returns the smallest key that is larger than all keys starting with `prefix`, if any.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+
+    while let Some(last) = successor.pop() {
+        if last < 0xff {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+
+    None
+}
+
+impl Clone for Cask {
+    fn clone(&self) -> Cask {
+        self.background.handles.fetch_add(1, Ordering::SeqCst);
+
+        Cask {
+            path: self.path.clone(),
+            options: self.options.clone(),
+            background: self.background.clone(),
+            inner: self.inner.clone(),
+            compaction: self.compaction.clone(),
+            counted: true,
+        }
+    }
+}
+
+impl Drop for Cask {
+    fn drop(&mut self) {
+        if self.counted && self.background.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.background.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use backup::{self, BackupManifest};
+    use batch::WriteBatch;
+    use changes::Change;
+    use data::{SequenceNumber, FORMAT_VERSION, LEGACY_FORMAT_VERSION, MAX_KEY_SIZE};
+    use cask::{CaskOptions, CorruptionPolicy, Range, SyncStrategy, Version};
+    use errors::Error;
+    #[cfg(feature = "export")]
+    use export::ExportFormat;
+    use inspect::{inspect_file, Checksum, FileKind};
+    use util::{now_millis, xxhash32};
+    use verify::{HintFileProblem, IndexProblem};
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_keys() {
+        let cask_result = CaskOptions::default()
+            .compaction_check_frequency(1)
+            .max_file_size(50 * 1024 * 1024)
+            .open("test.db");
+
+        assert!(cask_result.is_ok());
+
+        let cask = cask_result.unwrap();
+
+        let key1: &[u8] = &[0];
+        let key2: &[u8] = &[1];
+        let key3: &[u8] = &[2];
+
+        let val: &[u8] = &[0];
+
+        assert!(cask.put(key1, val).is_ok());
+        assert!(cask.put(key2, val).is_ok());
+        assert!(cask.put(key3, val).is_ok());
+        assert!(cask.delete(key3).is_ok());
+
+        let mut keys = cask.keys();
+
+        //Keys are not guaranteed to be in order.
+        keys.sort();
+
+        assert_eq!(keys.len(), 2);
+
+        assert_eq!(keys[0], key1);
+        assert_eq!(keys[1], key2);
+
+        assert!(fs::remove_dir_all("test.db").is_ok());
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let path = "test-write-batch.db";
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("a", "0").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put("a", "1").put("b", "1").delete("c");
+            cask.write_batch(batch).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), Some(b"1".to_vec()));
+
+            // a batch which fails doesn't take any sequence number
+            let mut batch = WriteBatch::new();
+            batch.put("d", "1").put(vec![0u8; MAX_KEY_SIZE as usize + 1], "1");
+            assert!(matches!(cask.write_batch(batch), Err(Error::InvalidKeySize(..))));
+            assert_eq!(cask.sequence(), 4);
+
+            let mut batch = WriteBatch::new();
+            batch.put("c", "2").delete("a");
+            cask.write_batch(batch).unwrap();
+        }
+
+        // simulate a crash during the last batch by truncating it and dropping the hint file
+        let data_file = OpenOptions::new()
+            .write(true)
+            .open(format!("{}/{:010}.cask.data", path, 1))
+            .unwrap();
+        let len = data_file.metadata().unwrap().len();
+        data_file.set_len(len - 1).unwrap();
+        fs::remove_file(format!("{}/{:010}.cask.hint", path, 1)).unwrap();
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), None);
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_range() {
+        for &(path, ordered) in &[("test-range.db", false), ("test-range-ordered.db", true)] {
+            let cask = CaskOptions::default()
+                .compaction(false)
+                .sync(SyncStrategy::Never)
+                .ordered_index(ordered)
+                .open(path)
+                .unwrap();
+
+            for key in &["user/1", "user/12", "user/2", "user0", "user/3", "users"] {
+                cask.put(*key, *key).unwrap();
+            }
+            cask.delete("user/2").unwrap();
+
+            let keys = |range: Range| -> Vec<Vec<u8>> { range.map(|r| r.unwrap().0).collect() };
+
+            assert_eq!(
+                keys(cask.scan_prefix("user/")),
+                vec![b"user/1".to_vec(), b"user/12".to_vec(), b"user/3".to_vec()]
+            );
+            assert_eq!(
+                keys(cask.range("user/12".."user0")),
+                vec![b"user/12".to_vec(), b"user/3".to_vec()]
+            );
+            assert_eq!(
+                keys(cask.range("user0"..)),
+                vec![b"user0".to_vec(), b"users".to_vec()]
+            );
+            assert!(keys(cask.range("users".."user0")).is_empty());
+
+            let (key, value) = cask.scan_prefix("users").next().unwrap().unwrap();
+            assert_eq!(key, value);
+
+            assert!(fs::remove_dir_all(path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_iter() {
+        let path = "test-iter.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(256)
+            .open(path)
+            .unwrap();
+
+        for i in 0..100u8 {
+            cask.put(vec![i % 50], vec![i]).unwrap();
+        }
+        cask.delete(vec![0]).unwrap();
+
+        let mut entries: Vec<_> = cask.iter().map(|r| r.unwrap()).collect();
+        entries.sort();
+
+        assert_eq!(entries.len(), 49);
+        for (i, (key, value)) in entries.into_iter().enumerate() {
+            assert_eq!(key, vec![i as u8 + 1]);
+            assert_eq!(value, vec![i as u8 + 51]);
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_iter_compaction() {
+        let path = "test-iter-compaction.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(32)
+            .open(path)
+            .unwrap();
+
+        cask.put("a", "1").unwrap();
+        cask.put("b", "1").unwrap();
+
+        let iter = cask.iter();
+
+        cask.put("a", "2").unwrap();
+        cask.delete("b").unwrap();
+
+        // the files the iterator would read from are gone, the entries it falls back to must
+        // still be the ones it was created with
+        cask.compact_files(&[1, 2], now_millis()).unwrap();
+
+        let mut entries: Vec<_> = iter.map(|e| e.unwrap()).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"1".to_vec()),
+            ]
+        );
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let path = "test-put-with-ttl.db";
+        let mut options = CaskOptions::default();
+        options
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(64)
+            .fragmentation_trigger(0.0);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("a", "old").unwrap();
+            cask.put_with_ttl("a", "new", Duration::from_millis(50))
+                .unwrap();
+            cask.put_with_ttl("b", "new", Duration::from_secs(3600))
+                .unwrap();
+            cask.put("c", "new").unwrap();
+            cask.put_with_ttl("d", "new", Duration::MAX).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"new".to_vec()));
+
+            thread::sleep(Duration::from_millis(100));
+
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.delete("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"new".to_vec()));
+            // a TTL too large to be represented never expires
+            assert_eq!(cask.get("d").unwrap(), Some(b"new".to_vec()));
+
+            let mut keys = cask.keys();
+            keys.sort();
+            assert_eq!(keys, vec![b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+            assert_eq!(cask.iter().count(), 3);
+
+            cask.compact().unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"new".to_vec()));
+        }
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), None);
+            assert_eq!(cask.get("b").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(cask.get("d").unwrap(), Some(b"new".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_torn_tail_recovery() {
+        let path = "test-torn-tail-recovery.db";
+        let data_file_path = format!("{}/{:010}.cask.data", path, 1);
+        let hint_file_path = format!("{}/{:010}.cask.hint", path, 1);
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        {
+            let cask = options.open(path).unwrap();
+            cask.put("a", "1").unwrap();
+            cask.put("b", "1").unwrap();
+        }
+
+        let valid_len = fs::metadata(&data_file_path).unwrap().len();
+
+        // simulate a crash during an append, leaving a partial entry and zeroed out data behind
+        {
+            let cask = options.open(path).unwrap();
+            cask.put("c", "1").unwrap();
+        }
+
+        let data_file_2 = format!("{}/{:010}.cask.data", path, 2);
+        let mut torn = fs::read(&data_file_2).unwrap();
+        torn.truncate(torn.len() - 1);
+        torn.extend_from_slice(&[0; 64]);
+
+        let mut data_file = OpenOptions::new()
+            .append(true)
+            .open(&data_file_path)
+            .unwrap();
+        data_file.write_all(&torn).unwrap();
+        fs::remove_file(&hint_file_path).unwrap();
+        fs::remove_file(&data_file_2).unwrap();
+        fs::remove_file(format!("{}/{:010}.cask.hint", path, 2)).unwrap();
+
+        {
+            let cask = options.open(path).unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), None);
+        }
+
+        assert_eq!(fs::metadata(&data_file_path).unwrap().len(), valid_len);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_corruption_policy() {
+        let path = "test-corruption-policy.db";
+        let data_file_path = format!("{}/{:010}.cask.data", path, 1);
+        let hint_file_path = format!("{}/{:010}.cask.hint", path, 1);
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        {
+            let cask = options.open(path).unwrap();
+            cask.put("a", "1").unwrap();
+            cask.put("b", "1").unwrap();
+            cask.put("c", "1").unwrap();
+        }
+
+        // flip a bit in the value of the second entry
+        let mut data = fs::read(&data_file_path).unwrap();
+        let entry_size = data.len() / 3;
+        data[2 * entry_size - 1] ^= 1;
+        fs::write(&data_file_path, &data).unwrap();
+        fs::remove_file(&hint_file_path).unwrap();
+
+        match options.open(path) {
+            Err(Error::CorruptEntry {
+                file_id, entry_pos, ..
+            }) => {
+                assert_eq!(file_id, 1);
+                assert_eq!(entry_pos, entry_size as u64);
+            }
+            _ => panic!("expected corrupt entry error"),
+        }
+
+        {
+            let cask = options
+                .corruption_policy(CorruptionPolicy::SkipEntry)
+                .open(path)
+                .unwrap();
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+            assert_eq!(cask.get("c").unwrap(), Some(b"1".to_vec()));
+        }
+
+        fs::remove_file(&hint_file_path).unwrap();
+
+        {
+            let cask = options
+                .corruption_policy(CorruptionPolicy::QuarantineFile)
+                .open(path)
+                .unwrap();
+
+            assert!(cask.keys().is_empty());
+        }
+
+        assert!(!Path::new(&data_file_path).exists());
+        assert!(Path::new(&format!("{}/corrupt/{:010}.cask.data", path, 1)).exists());
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_verify() {
+        let path = "test-verify.db";
+        let data_file_path = format!("{}/{:010}.cask.data", path, 1);
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(32)
+            .open(path)
+            .unwrap();
+
+        cask.put("a", "1").unwrap();
+        cask.put("b", "1").unwrap();
+        cask.put("c", "1").unwrap();
+        cask.delete("c").unwrap();
+
+        let report = cask.verify().unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.data_files, 4);
+        assert_eq!(report.entries, 4);
+        assert!(report.missing_hint_files.is_empty());
+
+        // a missing hint file isn't a problem
+        fs::remove_file(format!("{}/{:010}.cask.hint", path, 2)).unwrap();
+
+        let report = cask.verify().unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.missing_hint_files, vec![2]);
+
+        // flip a bit in the value of the first entry and leave an unknown file behind
+        let mut data = fs::read(&data_file_path).unwrap();
+        let len = data.len();
+        data[len - 1] ^= 1;
+        fs::write(&data_file_path, &data).unwrap();
+        fs::write(format!("{}/unknown", path), b"").unwrap();
+
+        let report = cask.verify().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt_entries.len(), 1);
+        assert_eq!(report.corrupt_entries[0].file_id, 1);
+        assert_eq!(report.corrupt_entries[0].entry_pos, 0);
+        assert_eq!(
+            report.hint_file_problems,
+            vec![HintFileProblem::Mismatch {
+                file_id: 1,
+                entry_pos: 0,
+            }]
+        );
+        assert_eq!(
+            report.index_problems,
+            vec![IndexProblem {
+                key: b"a".to_vec(),
+                file_id: 1,
+                entry_pos: 0,
+            }]
+        );
+        assert_eq!(report.orphan_files, vec![Path::new(path).join("unknown")]);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = "test-stats.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(64)
+            .open(path)
+            .unwrap();
+
+        let stats = cask.stats().unwrap();
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.sequence, 0);
+        assert!(stats.last_compaction.is_none());
+
+        cask.put("a", "1").unwrap();
+        cask.put("b", "1").unwrap();
+        cask.put("a", "2").unwrap();
+
+        let stats = cask.stats().unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.sequence, 3);
+        assert_eq!(stats.live_bytes, 2 * 32);
+        assert_eq!(stats.dead_bytes, 32);
+        assert_eq!(stats.active_file_id, Some(2));
+        assert_eq!(stats.files.len(), 2);
+        assert_eq!(stats.files[0].file_id, 1);
+        assert_eq!(stats.files[0].dead_entries, 1);
+        assert_eq!(stats.files[0].fragmentation, 0.5);
+        assert_eq!(stats.files[1].fragmentation, 0.0);
+        assert!(stats.disk_size >= 3 * 32);
+
+        cask.compact_files(&[1], now_millis()).unwrap();
+        assert!(cask.stats().unwrap().last_compaction.is_some());
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = "test-snapshot.db";
+        let mut options = CaskOptions::default();
+        options
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(32);
+
+        {
+            let cask = options.open(path).unwrap();
+
+            cask.put("a", "1").unwrap();
+            cask.put("b", "1").unwrap();
+
+            let snapshot = cask.snapshot();
+            assert_eq!(snapshot.sequence(), 2);
+
+            cask.put("a", "2").unwrap();
+            cask.delete("b").unwrap();
+            cask.put("c", "1").unwrap();
+
+            assert_eq!(snapshot.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(snapshot.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(snapshot.get("c").unwrap(), None);
+
+            // superseded entries visible to the snapshot survive compaction
+            cask.compact_files(&[1, 2], now_millis()).unwrap();
+
+            let mut keys = snapshot.keys();
+            keys.sort();
+            assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+
+            let mut entries: Vec<_> = snapshot.iter().map(|e| e.unwrap()).collect();
+            entries.sort();
+            assert_eq!(
+                entries,
+                vec![
+                    (b"a".to_vec(), b"1".to_vec()),
+                    (b"b".to_vec(), b"1".to_vec()),
+                ]
+            );
+
+            assert_eq!(cask.get("a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+        }
+
+        // the retained entries must not be resurrected
+        {
+            let cask = options.open(path).unwrap();
+            assert_eq!(cask.get("a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+            assert_eq!(cask.get("c").unwrap(), Some(b"1".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let path = "test-compare-and-swap.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        assert!(cask.put_if_absent("a", "1").unwrap());
+        assert!(!cask.put_if_absent("a", "2").unwrap());
+        assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+
+        assert!(!cask.compare_and_swap("a", Some(b"2"), Some(b"3")).unwrap());
+        assert!(cask.compare_and_swap("a", Some(b"1"), Some(b"3")).unwrap());
+        assert_eq!(cask.get("a").unwrap(), Some(b"3".to_vec()));
+
+        assert!(!cask.delete_if_equals("a", "1").unwrap());
+        assert!(cask.delete_if_equals("a", "3").unwrap());
+        assert_eq!(cask.get("a").unwrap(), None);
+
+        assert!(!cask.compare_and_swap("a", Some(b"3"), None).unwrap());
+        assert!(cask.compare_and_swap("a", None, Some(b"4")).unwrap());
+        assert_eq!(cask.get("a").unwrap(), Some(b"4".to_vec()));
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_versions() {
+        let path = "test-versions.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        assert_eq!(cask.put("a", "1").unwrap(), 1);
+        assert_eq!(cask.put("b", "1").unwrap(), 2);
+        assert_eq!(cask.get_with_version("a").unwrap(), Some((b"1".to_vec(), 1)));
+
+        assert_eq!(cask.put_if_version("a", "2", 2).unwrap(), None);
+        assert_eq!(cask.put_if_version("a", "2", 1).unwrap(), Some(3));
+        assert_eq!(cask.get_with_version("a").unwrap(), Some((b"2".to_vec(), 3)));
+        assert_eq!(cask.put_if_version("c", "1", 1).unwrap(), None);
+
+        assert_eq!(cask.delete("b").unwrap(), Some(4));
+        assert_eq!(cask.delete("b").unwrap(), None);
+        assert_eq!(cask.get_with_version("b").unwrap(), None);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_changes() {
+        let path = "test-changes.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(64)
+            .open(path)
+            .unwrap();
+
+        cask.put("a", "1").unwrap();
+
+        let subscription = cask.subscribe();
+
+        cask.put("b", "1").unwrap();
+        cask.delete("a").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put("c", "1").delete("b");
+        cask.write_batch(batch).unwrap();
+
+        let expected = vec![
+            Change {
+                sequence: 2,
+                key: b"b".to_vec(),
+                value: Some(b"1".to_vec()),
+            },
+            Change {
+                sequence: 3,
+                key: b"a".to_vec(),
+                value: None,
+            },
+            Change {
+                sequence: 4,
+                key: b"c".to_vec(),
+                value: Some(b"1".to_vec()),
+            },
+            Change {
+                sequence: 5,
+                key: b"b".to_vec(),
+                value: None,
+            },
+        ];
+
+        let changes: Vec<_> = (0..4).map(|_| subscription.try_next().unwrap()).collect();
+        assert_eq!(changes, expected);
+        assert!(subscription.try_next().is_none());
+
+        let changes: Vec<_> = cask.changes_since(1).unwrap().map(|c| c.unwrap()).collect();
+        assert_eq!(changes, expected);
+
+        // the writes to named buckets aren't changes of the default bucket
+        cask.bucket("sessions").unwrap().put("d", "1").unwrap();
+        assert!(subscription.try_next().is_none());
+
+        let changes: Vec<_> = cask.changes_since(0).unwrap().map(|c| c.unwrap()).collect();
+        assert_eq!(changes.len(), 5);
+        assert_eq!(&changes[1..], &expected[..]);
+
+        // but they're part of the replication stream
+        let mut stream = cask.replicate(5).unwrap();
+        assert_eq!(stream.try_next().unwrap().unwrap().sequence, 6);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_replication() {
+        let leader_path = "test-replication-leader.db";
+        let follower_path = "test-replication-follower.db";
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        let leader = options.open(leader_path).unwrap();
+        leader.put("a", "1").unwrap();
+        leader.put("b", "1").unwrap();
+
+        {
+            let follower = options.clone().follower(true).open(follower_path).unwrap();
+            assert!(matches!(follower.put("a", "2"), Err(Error::ReadOnly)));
+
+            let mut stream = leader.replicate(follower.sequence()).unwrap();
+
+            // only followers accept records
+            let record = stream.try_next().unwrap().unwrap();
+            assert!(matches!(leader.apply(&record), Err(Error::NotFollower)));
+            follower.apply(&record).unwrap();
+
+            leader.delete("a").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put("c", "1").put("d", "1");
+            leader.write_batch(batch).unwrap();
+
+            while let Some(record) = stream.try_next() {
+                follower.apply(&record.unwrap()).unwrap();
+            }
+
+            assert_eq!(follower.sequence(), 5);
+            assert_eq!(follower.get("a").unwrap(), None);
+            assert_eq!(follower.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(follower.get("d").unwrap(), Some(b"1".to_vec()));
+        }
+
+        // batches are replayed as a single record while catching up
+        {
+            let mut stream = leader.replicate(3).unwrap();
+            let record = stream.try_next().unwrap().unwrap();
+            assert_eq!(record.sequence, 5);
+            assert!(record.entries().unwrap().0);
+            assert!(stream.try_next().is_none());
+        }
+
+        leader.put("b", "2").unwrap();
+
+        // the follower resumes from the last write it applied
+        {
+            let follower = options.clone().follower(true).open(follower_path).unwrap();
+            assert_eq!(follower.sequence(), 5);
+
+            let mut stream = leader.replicate(follower.sequence()).unwrap();
+            let record = stream.try_next().unwrap().unwrap();
+            assert_eq!(record.sequence, 6);
+            assert!(stream.try_next().is_none());
+
+            follower.apply(&record).unwrap();
+            follower.apply(&record).unwrap();
+
+            assert_eq!(follower.sequence(), 6);
+            assert_eq!(follower.get("b").unwrap(), Some(b"2".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(leader_path).is_ok());
+        assert!(fs::remove_dir_all(follower_path).is_ok());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = "test-read-only.db";
+        let hint_file_path = format!("{}/{:010}.cask.hint", path, 1);
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        let mut read_only = options.clone();
+        read_only.read_only(true);
+
+        assert!(read_only.open(path).is_err());
+
+        {
+            let cask = options.open(path).unwrap();
+            cask.put("a", "1").unwrap();
+
+            // the writer holds the lock and its active hint file isn't finalized yet
+            let hint_file_len = fs::metadata(&hint_file_path).unwrap().len();
+
+            let reader = read_only.open(path).unwrap();
+            assert_eq!(reader.get("a").unwrap(), Some(b"1".to_vec()));
+            assert!(matches!(reader.put("b", "1"), Err(Error::ReadOnly)));
+            assert!(matches!(reader.delete("a"), Err(Error::ReadOnly)));
+            assert!(matches!(reader.compact(), Err(Error::ReadOnly)));
+
+            assert_eq!(fs::metadata(&hint_file_path).unwrap().len(), hint_file_len);
+        }
+
+        {
+            let reader1 = read_only.open(path).unwrap();
+            let reader2 = read_only.open(path).unwrap();
+            assert_eq!(reader1.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(reader2.get("a").unwrap(), Some(b"1".to_vec()));
+
+            // readers keep writers out
+            assert!(options.open(path).is_err());
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_format() {
+        let path = "test-format.db";
+        let format_file_path = format!("{}/cask.format", path);
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        {
+            let cask = options.open(path).unwrap();
+            cask.put("a", "1").unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(&format_file_path).unwrap(),
+            format!("cask-format {}\n", FORMAT_VERSION)
+        );
+        assert!(options.open(path).is_ok());
+
+        // data files written in another format are rejected instead of being misread
+        fs::write(&format_file_path, "cask-format 99\n").unwrap();
+        assert!(matches!(options.open(path), Err(Error::UnsupportedFormat(99))));
+
+        // as are those which predate the format file when opened read-only, they're upgraded
+        // otherwise (see `test_upgrade`) which fails for files in another format
+        fs::remove_file(&format_file_path).unwrap();
+        let mut read_only = options.clone();
+        read_only.read_only(true);
+        assert!(matches!(
+            read_only.open(path),
+            Err(Error::UnsupportedFormat(LEGACY_FORMAT_VERSION))
+        ));
+        assert!(options.open(path).is_err());
+        assert!(!Path::new(path).join("upgrade").exists());
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    /// Lays out an entry the way version `LEGACY_FORMAT_VERSION` did, `None` being a tombstone.
+    fn legacy_entry(sequence: SequenceNumber, key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+        let mut bytes = vec![0u8; 18];
+        LittleEndian::write_u64(&mut bytes[4..12], sequence);
+        LittleEndian::write_u16(&mut bytes[12..14], key.len() as u16);
+        LittleEndian::write_u32(&mut bytes[14..18], value.map_or(!0, |v| v.len() as u32));
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(value.unwrap_or(&[]));
+
+        let checksum = xxhash32(&bytes[4..]);
+        LittleEndian::write_u32(&mut bytes[..4], checksum);
+        bytes
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let path = "test-upgrade.db";
+        let hint_file_path = format!("{}/{:010}.cask.hint", path, 1);
+
+        // data files as written before the format file was introduced
+        fs::create_dir(path).unwrap();
+        let mut data = legacy_entry(1, b"a", Some(b"1"));
+        data.extend(legacy_entry(2, b"b", Some(b"1")));
+        fs::write(format!("{}/{:010}.cask.data", path, 1), data).unwrap();
+        let mut data = legacy_entry(3, b"a", Some(b"2"));
+        data.extend(legacy_entry(4, b"b", None));
+        fs::write(format!("{}/{:010}.cask.data", path, 2), data).unwrap();
+        fs::write(&hint_file_path, "legacy").unwrap();
+
+        // along with an upgrade which was interrupted before it completed
+        fs::create_dir(format!("{}/upgrade", path)).unwrap();
+        fs::write(format!("{}/upgrade/{:010}.cask.data", path, 1), "partial").unwrap();
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+        let mut read_only = options.clone();
+        read_only.read_only(true);
+
+        assert!(matches!(
+            read_only.open(path),
+            Err(Error::UnsupportedFormat(LEGACY_FORMAT_VERSION))
+        ));
+
+        {
+            let cask = options.open(path).unwrap();
+            assert_eq!(cask.get("a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("b").unwrap(), None);
+            assert_eq!(cask.put("c", "1").unwrap(), 5);
+        }
+
+        assert!(!Path::new(path).join("upgrade").exists());
+        assert_ne!(fs::read(&hint_file_path).ok(), Some(b"legacy".to_vec()));
+
+        {
+            let cask = read_only.open(path).unwrap();
+            assert_eq!(cask.get("a").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(cask.get("c").unwrap(), Some(b"1".to_vec()));
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let path = "test-checkpoint.db";
+        let checkpoint_path = "test-checkpoint.db/checkpoint";
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        let cask = options.open(path).unwrap();
+        cask.put("a", "1").unwrap();
+        cask.put("b", "1").unwrap();
+
+        cask.checkpoint(checkpoint_path).unwrap();
+        assert!(cask.checkpoint(checkpoint_path).is_err());
+
+        cask.put("b", "2").unwrap();
+        cask.put("c", "1").unwrap();
+        assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(cask.get("b").unwrap(), Some(b"2".to_vec()));
+
+        {
+            let checkpoint = options.open(checkpoint_path).unwrap();
+            assert_eq!(checkpoint.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(checkpoint.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(checkpoint.get("c").unwrap(), None);
+            assert!(checkpoint.verify().unwrap().is_ok());
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_backup() {
+        let path = "test-backup.db";
+        let backup_path = "test-backup.db/backup";
+        let full_path = "test-backup.db/full";
+        let incremental_path = "test-backup.db/incremental";
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        let cask = options.open(path).unwrap();
+        cask.put("a", "1").unwrap();
+        cask.put("b", "1").unwrap();
+
+        let full = cask.backup_incremental(backup_path, None).unwrap();
+        assert_eq!(full.id, 1);
+        assert_eq!(full.files, vec![1]);
+        assert_eq!(full.added, vec![1]);
+        assert!(full.retired.is_empty());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let data_file_path = format!("{}/{:010}.cask.data", path, 1);
+            assert_eq!(fs::metadata(data_file_path).unwrap().nlink(), 1);
+        }
+
+        cask.put("b", "2").unwrap();
+        cask.put("c", "1").unwrap();
+        cask.compact_files(&[1], now_millis()).unwrap();
+
+        let incremental = cask.backup_incremental(backup_path, Some(&full)).unwrap();
+        assert_eq!(incremental.retired, vec![1]);
+        assert!(!incremental.added.is_empty());
+        assert!(!incremental.files.contains(&1));
+
+        assert_eq!(incremental.id, 2);
+
+        let manifest_path = backup::manifest_path(Path::new(backup_path), incremental.id);
+        assert_eq!(BackupManifest::read(&manifest_path).unwrap(), incremental);
+
+        // a backup without writes since the previous one doesn't replace its manifest
+        let unchanged = cask.backup_incremental(backup_path, Some(&incremental)).unwrap();
+        assert_eq!(unchanged.id, 3);
+        assert_eq!(unchanged.sequence, incremental.sequence);
+        assert_eq!(BackupManifest::read(manifest_path).unwrap(), incremental);
+
+        full.restore(backup_path, full_path).unwrap();
+        incremental.restore(backup_path, incremental_path).unwrap();
+        assert!(full.restore(backup_path, full_path).is_err());
+
+        {
+            let restored = options.open(full_path).unwrap();
+            assert_eq!(restored.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(restored.get("b").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(restored.get("c").unwrap(), None);
+        }
+
+        {
+            let restored = options.open(incremental_path).unwrap();
+            assert_eq!(restored.get("a").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(restored.get("b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(restored.get("c").unwrap(), Some(b"1".to_vec()));
+            assert_eq!(restored.sequence(), incremental.sequence);
+            assert!(restored.verify().unwrap().is_ok());
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_inspect() {
+        let path = "test-inspect.db";
+        let data_file_path = format!("{}/{:010}.cask.data", path, 1);
+        let hint_file_path = format!("{}/{:010}.cask.hint", path, 1);
+
+        {
+            let cask = CaskOptions::default()
+                .compaction(false)
+                .sync(SyncStrategy::Never)
+                .open(path)
+                .unwrap();
+
+            cask.put("a", "1").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put("b", "1").delete("a");
+            cask.write_batch(batch).unwrap();
+        }
+
+        let inspection = inspect_file(&data_file_path).unwrap();
+        assert_eq!(inspection.kind, FileKind::Data);
+        assert!(inspection.error.is_none());
+        assert_eq!(inspection.checksum, None);
+        assert_eq!(
+            inspection
+                .records
+                .iter()
+                .map(|r| (r.offset, r.sequence, r.deleted, r.batch))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 1, false, false),
+                (32, 2, false, true),
+                (66, 2, false, false),
+                (98, 3, true, false),
+            ]
+        );
+        assert!(inspection
+            .records
+            .iter()
+            .all(|r| r.checksum == Some(Checksum::Valid)));
+
+        let inspection = inspect_file(&hint_file_path).unwrap();
+        assert_eq!(inspection.kind, FileKind::Hint);
+        assert_eq!(inspection.checksum, Some(Checksum::Valid));
+        assert_eq!(
+            inspection
+                .records
+                .iter()
+                .map(|r| (r.entry_pos, r.key_size, r.value_size, r.deleted))
+                .collect::<Vec<_>>(),
+            vec![(Some(0), 1, 1, false), (Some(66), 1, 1, false), (Some(98), 1, 0, true)]
+        );
+
+        // flip a bit in the value of the first entry, decoding carries on past it
+        let mut data = fs::read(&data_file_path).unwrap();
+        data[31] ^= 1;
+        fs::write(&data_file_path, &data).unwrap();
+
+        let inspection = inspect_file(&data_file_path).unwrap();
+        assert!(inspection.error.is_none());
+        assert_eq!(inspection.records.len(), 4);
+        assert!(matches!(
+            inspection.records[0].checksum,
+            Some(Checksum::Invalid { .. })
+        ));
+        assert_eq!(inspection.records[0].key_size, 1);
+        assert_eq!(inspection.records[1].checksum, Some(Checksum::Valid));
+
+        // corrupt and then remove the trailing checksum of the hint file
+        let mut hints = fs::read(&hint_file_path).unwrap();
+        let len = hints.len();
+        hints[len - 1] ^= 1;
+        fs::write(&hint_file_path, &hints).unwrap();
+
+        let inspection = inspect_file(&hint_file_path).unwrap();
+        assert!(matches!(inspection.checksum, Some(Checksum::Invalid { .. })));
+        assert_eq!(inspection.records.len(), 3);
+
+        fs::write(&hint_file_path, &hints[..len - 4]).unwrap();
+
+        let inspection = inspect_file(&hint_file_path).unwrap();
+        assert_eq!(inspection.checksum, Some(Checksum::Missing));
+        assert_eq!(inspection.records.len(), 3);
+
+        assert!(inspect_file(format!("{}/unknown", path)).is_err());
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "export")]
+    fn test_export_import() {
+        let path = "test-export-import.db";
+        let json_path = "test-export-import.db/json";
+        let binary_path = "test-export-import.db/binary";
+
+        let mut options = CaskOptions::default();
+        options.compaction(false).sync(SyncStrategy::Never);
+
+        let cask = options.open(path).unwrap();
+        cask.put("a", "1").unwrap();
+        cask.put("b", "1").unwrap();
+        cask.put_with_ttl("c", "1", Duration::from_secs(3600)).unwrap();
+        cask.put("b", "2").unwrap();
+        cask.delete("a").unwrap();
+
+        let mut json = Vec::new();
+        assert_eq!(cask.export(&mut json, ExportFormat::JsonLines).unwrap(), 2);
+
+        let json = String::from_utf8(json).unwrap();
+        let lines: Vec<_> = json.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(r#"{"key":"Yw==","value":"MQ==","sequence":3,"expiry":"#));
+        assert_eq!(lines[1], r#"{"key":"Yg==","value":"Mg==","sequence":4}"#);
+
+        let mut binary = Vec::new();
+        assert_eq!(cask.export(&mut binary, ExportFormat::Binary).unwrap(), 2);
+
+        {
+            let imported = options.open(json_path).unwrap();
+            assert_eq!(
+                imported
+                    .import(json.as_bytes(), ExportFormat::JsonLines, true)
+                    .unwrap(),
+                2
+            );
+            assert_eq!(imported.sequence(), 4);
+            assert_eq!(imported.get_with_version("b").unwrap(), Some((b"2".to_vec(), 4)));
+            assert_eq!(imported.get("c").unwrap(), Some(b"1".to_vec()));
+
+            // the sequence numbers of the export don't follow the last write anymore
+            match imported.import(json.as_bytes(), ExportFormat::JsonLines, true) {
+                Err(Error::InvalidSequence { last: 4, found: 3 }) => {}
+                result => panic!("unexpected result: {:?}", result),
+            }
+
+            // unknown fields are ignored, whatever their value
+            let line = concat!(
+                r#" { "value" : "Mw==", "other": [{"a": -1.5e3}, "\ud83d\ude00"], "#,
+                r#""expiry": null, "key": "Yg==" } "#,
+                "\n\n"
+            );
+            assert_eq!(
+                imported
+                    .import(line.as_bytes(), ExportFormat::JsonLines, false)
+                    .unwrap(),
+                1
+            );
+            assert_eq!(imported.get_with_version("b").unwrap(), Some((b"3".to_vec(), 5)));
+            assert!(imported.import(line.as_bytes(), ExportFormat::JsonLines, true).is_err());
+        }
+
+        {
+            let imported = options.open(binary_path).unwrap();
+            assert_eq!(
+                imported
+                    .import(&binary[..], ExportFormat::Binary, false)
+                    .unwrap(),
+                2
+            );
+            assert_eq!(imported.sequence(), 2);
+            assert_eq!(imported.get("b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(imported.get("c").unwrap(), Some(b"1".to_vec()));
+
+            let truncated = &binary[..binary.len() - 1];
+            assert!(imported.import(json.as_bytes(), ExportFormat::Binary, false).is_err());
+            assert!(imported.import(truncated, ExportFormat::Binary, false).is_err());
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_get_reader() {
+        let path = "test-get-reader.db";
+        let data_file_path = format!("{}/{:010}.cask.data", path, 1);
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let value: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        cask.put("a", &value).unwrap();
+        cask.put("b", "1").unwrap();
+        cask.delete("b").unwrap();
+
+        let mut reader = cask.get_reader("a").unwrap().unwrap();
+        assert_eq!(reader.len(), value.len() as u64);
+
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert!(read == value);
+
+        assert!(cask.get_reader("b").unwrap().is_none());
+        assert!(cask.get_reader("c").unwrap().is_none());
+
+        // flip a bit in the value, which is only noticed once it has been read to the end
+        let mut data = fs::read(&data_file_path).unwrap();
+        data[1000] ^= 1;
+        fs::write(&data_file_path, &data).unwrap();
+
+        let mut reader = cask.get_reader("a").unwrap().unwrap();
+        let mut read = Vec::new();
+        let err = reader.read_to_end(&mut read).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_update() {
+        let path = "test-update.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let sequence = cask
+            .update("a", |current| {
+                assert!(current.is_none());
+                Some((b"1".to_vec(), Some(u64::MAX)))
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            cask.update("a", |current| {
+                assert_eq!(
+                    current,
+                    Some(Version {
+                        value: b"1".to_vec(),
+                        sequence: sequence,
+                        expiry: Some(u64::MAX),
+                        meta: None,
+                    })
+                );
+                None
+            })
+            .unwrap(),
+            None
+        );
+
+        // an expired key is passed as absent
+        cask.update("a", |_| Some((b"2".to_vec(), Some(1)))).unwrap();
+        cask.update("a", |current| {
+            assert!(current.is_none());
+            None
+        })
+        .unwrap();
+        assert_eq!(cask.get("a").unwrap(), None);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let path = "test-shutdown.db";
+        let cask = CaskOptions::default()
+            .compaction_check_frequency(3600)
+            .sync(SyncStrategy::Interval(3_600_000))
+            .open(path)
+            .unwrap();
+
+        // dropping a clone doesn't stop the background threads of the other handles
+        drop(cask.clone());
+        assert!(!*cask.background.stopped.lock().unwrap());
+
+        // the background threads are woken up instead of finishing their wait
+        let start = Instant::now();
+        cask.shutdown();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(cask.background.threads.lock().unwrap().is_empty());
+
+        cask.put("a", "1").unwrap();
+        assert_eq!(cask.get("a").unwrap(), Some(b"1".to_vec()));
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_buckets() {
+        let path = "test-buckets.db";
+        let mut options = CaskOptions::default();
+        options
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .max_file_size(64);
+
+        let cask = options.open(path).unwrap();
+
+        cask.put("key", "default").unwrap();
+        let users = cask.bucket("users").unwrap();
+        let sessions = cask.bucket("sessions").unwrap();
+        users.put("key", "user").unwrap();
+        sessions.put("key", "session").unwrap();
+        sessions.put("other", "session").unwrap();
+
+        // the same key is stored separately in each bucket
+        assert_eq!(cask.get("key").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(users.get("key").unwrap(), Some(b"user".to_vec()));
+        assert_eq!(sessions.get("key").unwrap(), Some(b"session".to_vec()));
+        assert_eq!(cask.keys(), vec![b"key".to_vec()]);
+        let mut keys = sessions.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![b"key".to_vec(), b"other".to_vec()]);
+
+        assert_eq!(cask.buckets(), vec!["sessions", "users"]);
+        assert_eq!(cask.bucket("users").unwrap().id(), users.id());
+        match cask.bucket("") {
+            Err(Error::InvalidBucket(..)) => {}
+            _ => panic!("expected an invalid bucket error"),
+        }
+
+        assert!(users.delete("key").unwrap().is_some());
+        assert!(users.delete("key").unwrap().is_none());
+        assert_eq!(users.get("key").unwrap(), None);
+        assert_eq!(cask.get("key").unwrap(), Some(b"default".to_vec()));
+
+        let stats = users.stats().unwrap();
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.dead_bytes, 37);
+        let stats = sessions.stats().unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.live_bytes, 40 + 42);
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(cask.stats().unwrap().keys, 3);
+
+        drop(users);
+        drop(sessions);
+        drop(cask);
+
+        // the buckets and their keys are recovered when the `Cask` is opened again
+        let cask = options.open(path).unwrap();
+        let sessions = cask.bucket("sessions").unwrap();
+        assert_eq!(sessions.get("other").unwrap(), Some(b"session".to_vec()));
+        assert_eq!(sessions.stats().unwrap().live_bytes, 40 + 42);
+        assert!(cask.bucket("users").unwrap().keys().unwrap().is_empty());
+        assert_eq!(cask.get("key").unwrap(), Some(b"default".to_vec()));
+
+        let dead_bytes = cask.stats().unwrap().dead_bytes;
+        assert!(cask.drop_bucket("sessions").unwrap());
+        assert!(!cask.drop_bucket("sessions").unwrap());
+        assert_eq!(cask.stats().unwrap().dead_bytes, dead_bytes + 40 + 42);
+        assert_eq!(cask.buckets(), vec!["users"]);
+        match sessions.get("key") {
+            Err(Error::InvalidBucket(..)) => {}
+            _ => panic!("expected an invalid bucket error"),
+        }
+        match sessions.put("key", "session") {
+            Err(Error::InvalidBucket(..)) => {}
+            _ => panic!("expected an invalid bucket error"),
+        }
+
+        drop(sessions);
+
+        // a bucket created with the name of a dropped one starts out empty
+        let sessions = cask.bucket("sessions").unwrap();
+        assert!(sessions.keys().unwrap().is_empty());
+        sessions.put("new", "session").unwrap();
+
+        // compaction reclaims the entries of the dropped bucket
+        let files = cask.inner.read().unwrap().log.files();
+        cask.compact_files(&files, now_millis()).unwrap();
+        let stats = cask.stats().unwrap();
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(stats.keys, 2);
+
+        drop(sessions);
+        drop(cask);
+
+        let cask = options.open(path).unwrap();
+        assert_eq!(cask.bucket("sessions").unwrap().keys().unwrap(), vec![b"new".to_vec()]);
+        assert_eq!(cask.get("key").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(cask.stats().unwrap().dead_bytes, 0);
+
+        drop(cask);
+        assert!(fs::remove_dir_all(path).is_ok());
     }
 }
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+use std::vec::Vec;
+
+use data::{BucketId, Entry, SequenceNumber, DEFAULT_BUCKET};
+use errors::Result;
+use log::{Entries, EntryReader, Hints, Log};
+
+/// A committed write: the insertion of `value` for `key`, or the removal of `key` if `value` is
+/// `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub sequence: SequenceNumber,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+}
+
+/// A feed of the writes committed to a `Cask` after it was created, see `Cask::subscribe`.
+///
+/// Iterating a subscription blocks until the next change is available and ends once the `Cask`
+/// is closed.
+pub struct Subscription {
+    receiver: Receiver<Change>,
+}
+
+impl Subscription {
+    /// Returns the next change if one is available, without blocking.
+    pub fn try_next(&self) -> Option<Change> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Returns the next change, waiting at most `timeout` for one to become available.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<Change> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        self.receiver.recv().ok()
+    }
+}
+
+pub struct Subscribers {
+    senders: Vec<Sender<Change>>,
+}
+
+impl Subscribers {
+    pub fn new() -> Subscribers {
+        Subscribers {
+            senders: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Subscription {
+        let (sender, receiver) = channel();
+        self.senders.push(sender);
+
+        Subscription { receiver: receiver }
+    }
+
+    /// Sends a change to all subscribers, forgetting about the ones which have gone away.
+    pub fn notify(&mut self, sequence: SequenceNumber, key: &[u8], value: Option<&[u8]>) {
+        if self.senders.is_empty() {
+            return;
+        }
+
+        let change = Change {
+            sequence: sequence,
+            key: key.to_vec(),
+            value: value.map(|value| value.to_vec()),
+        };
+
+        self.senders
+            .retain(|sender| sender.send(change.clone()).is_ok());
+    }
+}
+
+/// The data file of entries read by `Changes::next_batch`, whether they form a batch, their
+/// positions and the entries themselves.
+pub(crate) type Batch<'a> = (u32, bool, Vec<u64>, Vec<Entry<'a>>);
+
+/// An iterator over the changes still present in the data files of a `Cask`, in sequence order,
+/// see `Cask::changes_since`.
+pub struct Changes {
+    since: SequenceNumber,
+    all_buckets: bool,
+    /// The hints, or entries if there are no hints, of each data file, which are only read on the
+    /// first call to `next` so that the `Cask` isn't locked meanwhile.
+    sources: Option<Vec<(u32, Source)>>,
+    entries: VecDeque<(u32, u64)>,
+    readers: HashMap<u32, EntryReader>,
+}
+
+enum Source {
+    Hints(Hints<'static>),
+    Entries(Entries<'static>),
+}
+
+impl Changes {
+    /// Opens the data files of `log`, along with their hint files, without reading them yet. Only
+    /// the changes of the default bucket are returned unless `all_buckets` is set.
+    pub fn new(log: &Log, since: SequenceNumber, all_buckets: bool) -> Result<Changes> {
+        let mut file_ids = log.files();
+        file_ids.extend(log.active_file_id);
+
+        let mut sources = Vec::with_capacity(file_ids.len());
+        let mut readers = HashMap::new();
+
+        for file_id in file_ids {
+            let source = match log.hints(file_id)? {
+                Some(hints) => Source::Hints(hints),
+                None => Source::Entries(log.entries(file_id)?),
+            };
+
+            sources.push((file_id, source));
+
+            // the data file is opened right away so that it can still be read if compaction
+            // removes it in the meantime
+            readers.insert(file_id, log.reader(file_id)?);
+        }
+
+        Ok(Changes {
+            since: since,
+            all_buckets: all_buckets,
+            sources: Some(sources),
+            entries: VecDeque::new(),
+            readers: readers,
+        })
+    }
+
+    /// Reads the positions of the changes from the hints or entries of the data files. Only the
+    /// positions are kept in memory, the entries are read as the iterator advances.
+    fn load(&mut self, sources: Vec<(u32, Source)>) -> Result<()> {
+        let since = self.since;
+        let mut entries = Vec::new();
+
+        for (file_id, source) in sources {
+            match source {
+                Source::Hints(hints) => {
+                    for hint in hints {
+                        let hint = hint?;
+                        if hint.sequence > since && self.includes(hint.bucket) {
+                            entries.push((hint.sequence, file_id, hint.entry_pos));
+                        }
+                    }
+                }
+                Source::Entries(data_entries) => {
+                    for (entry_pos, entry) in data_entries {
+                        let entry = entry?;
+                        if !entry.batch && entry.sequence > since && self.includes(entry.bucket)
+                        {
+                            entries.push((entry.sequence, file_id, entry_pos));
+                        }
+                    }
+                }
+            }
+        }
+
+        // entries copied by an ongoing compaction show up twice with the same sequence number
+        entries.sort();
+        entries.dedup_by_key(|e| e.0);
+
+        self.readers
+            .retain(|file_id, _| entries.iter().any(|e| e.1 == *file_id));
+        self.entries = entries
+            .into_iter()
+            .map(|(_, file_id, entry_pos)| (file_id, entry_pos))
+            .collect();
+
+        Ok(())
+    }
+
+    fn includes(&self, bucket: BucketId) -> bool {
+        self.all_buckets || bucket == DEFAULT_BUCKET
+    }
+
+    /// Returns the next entry along with the data file and position it was read from.
+    pub(crate) fn next_entry<'a>(&mut self) -> Option<Result<(u32, u64, Entry<'a>)>> {
+        if let Some(sources) = self.sources.take() {
+            if let Err(err) = self.load(sources) {
+                return Some(Err(err));
+            }
+        }
+
+        self.entries.pop_front().map(|(file_id, entry_pos)| {
+            let entry = self.readers
+                .get_mut(&file_id)
+                .expect("reader for data file")
+                .read_entry(entry_pos)?;
+
+            Ok((file_id, entry_pos, entry))
+        })
+    }
+
+    /// Returns the next entry like `next_entry`, along with the following entries of its batch if
+    /// it's the first entry of a batch. Whether it returned a batch is returned along with the
+    /// data file and the positions of the entries.
+    ///
+    /// Batches are recognized by the batch marker preceding their entries in the data file they
+    /// were written to, so the entries of a batch which have been copied by compaction are
+    /// returned one at a time.
+    pub(crate) fn next_batch<'a>(&mut self) -> Option<Result<Batch<'a>>> {
+        let (file_id, entry_pos, entry) = match self.next_entry()? {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let marker = self.readers
+            .get_mut(&file_id)
+            .expect("reader for data file")
+            .read_batch_marker(entry_pos);
+
+        let batch_size = match marker {
+            Ok(Some(ref marker)) if marker.sequence == entry.sequence => {
+                marker.batch_size().unwrap_or(0)
+            }
+            Ok(_) => 0,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let mut next_pos = entry_pos + entry.size();
+        let mut entry_positions = vec![entry_pos];
+        let mut entries = vec![entry];
+
+        while entries.len() < batch_size as usize
+            && self.entries.front() == Some(&(file_id, next_pos))
+        {
+            let entry = match self.next_entry()? {
+                Ok((_, _, entry)) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+
+            entry_positions.push(next_pos);
+            next_pos += entry.size();
+            entries.push(entry);
+        }
+
+        Some(Ok((file_id, batch_size > 0, entry_positions, entries)))
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Result<Change>> {
+        self.next_entry().map(|entry| {
+            entry.map(|(_, _, entry)| Change {
+                sequence: entry.sequence,
+                value: if entry.deleted {
+                    None
+                } else {
+                    Some(entry.value.into_owned())
+                },
+                key: entry.key.into_owned(),
+            })
+        })
+    }
+}
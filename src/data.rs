@@ -1,4 +1,6 @@
 use std::borrow::Cow;
+use std::cmp;
+use std::io;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::result::Result::{Err, Ok};
@@ -8,19 +10,43 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use errors::{Error, Result};
 use util::{XxHash32, xxhash32};
 
-const ENTRY_STATIC_SIZE: usize = 18; // checksum(4) + sequence(8) + key_size(2) + value_size(4)
+/// Version of the layout of entries and hints, which is recorded in the `Cask` dir and bumped
+/// whenever the layout changes. Data files without a recorded version predate it and are of
+/// version `LEGACY_FORMAT_VERSION`.
+///
+/// Version 2 added the expiry timestamp of entries and hints, and version 3 their bucket id.
+pub const FORMAT_VERSION: u32 = 3;
+pub const LEGACY_FORMAT_VERSION: u32 = 1;
+
+// checksum(4) + sequence(8) + expiry(8) + bucket(4) + key_size(2) + value_size(4)
+pub const ENTRY_STATIC_SIZE: usize = 30;
+// checksum(4) + sequence(8) + key_size(2) + value_size(4)
+pub const LEGACY_ENTRY_STATIC_SIZE: usize = 18;
+const ENTRY_NO_EXPIRY: u64 = 0;
 const ENTRY_TOMBSTONE: u32 = !0;
-pub const MAX_VALUE_SIZE: u32 = !0 - 1;
+const ENTRY_BATCH: u32 = !0 - 1;
+const BATCH_SIZE_SIZE: usize = 4; // number of entries in the batch(4)
+const MAX_PREALLOCATED_VALUE_SIZE: usize = 1024 * 1024;
+pub const MAX_VALUE_SIZE: u32 = !0 - 2;
 pub const MAX_KEY_SIZE: u16 = !0;
 
 pub type SequenceNumber = u64;
 
+/// Identifies the bucket an entry belongs to, see `Cask::bucket`.
+pub type BucketId = u32;
+
+/// The bucket of the keys written through the `Cask` itself.
+pub const DEFAULT_BUCKET: BucketId = 0;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Entry<'a> {
     pub key: Cow<'a, [u8]>,
     pub value: Cow<'a, [u8]>,
     pub sequence: SequenceNumber,
+    pub expiry: Option<u64>,
+    pub bucket: BucketId,
     pub deleted: bool,
+    pub batch: bool,
 }
 
 impl<'a> Entry<'a> {
@@ -44,7 +70,10 @@ impl<'a> Entry<'a> {
             key: k,
             value: v,
             sequence: sequence,
+            expiry: None,
+            bucket: DEFAULT_BUCKET,
             deleted: false,
+            batch: false,
         })
     }
 
@@ -56,7 +85,42 @@ impl<'a> Entry<'a> {
             key: Cow::from(key),
             value: Cow::Borrowed(&[]),
             sequence: sequence,
+            expiry: None,
+            bucket: DEFAULT_BUCKET,
             deleted: true,
+            batch: false,
+        }
+    }
+
+    /// Creates a batch marker, which precedes the `size` entries that make up an atomic batch. The
+    /// batch is only considered committed if all of its entries can be read back.
+    pub fn batch(sequence: SequenceNumber, size: u32) -> Entry<'a> {
+        let mut value = Vec::with_capacity(BATCH_SIZE_SIZE);
+        value.write_u32::<LittleEndian>(size).unwrap();
+
+        Entry {
+            key: Cow::Borrowed(&[]),
+            value: Cow::from(value),
+            sequence: sequence,
+            expiry: None,
+            bucket: DEFAULT_BUCKET,
+            deleted: false,
+            batch: true,
+        }
+    }
+
+    /// Returns `true` if the entry has an expiry timestamp (in milliseconds since the epoch) which
+    /// is not after `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expiry, now)
+    }
+
+    /// Returns the number of entries in the batch, if this entry is a batch marker.
+    pub fn batch_size(&self) -> Option<u32> {
+        if self.batch {
+            Cursor::new(&*self.value).read_u32::<LittleEndian>().ok()
+        } else {
+            None
         }
     }
 
@@ -69,11 +133,17 @@ impl<'a> Entry<'a> {
         let mut cursor = Cursor::new(Vec::with_capacity(self.size() as usize));
         cursor.set_position(4);
         cursor.write_u64::<LittleEndian>(self.sequence)?;
+        cursor.write_u64::<LittleEndian>(self.expiry.unwrap_or(ENTRY_NO_EXPIRY))?;
+        cursor.write_u32::<LittleEndian>(self.bucket)?;
         cursor.write_u16::<LittleEndian>(self.key.len() as u16)?;
 
         if self.deleted {
             cursor.write_u32::<LittleEndian>(ENTRY_TOMBSTONE)?;
             cursor.write_all(&self.key)?;
+        } else if self.batch {
+            cursor.write_u32::<LittleEndian>(ENTRY_BATCH)?;
+            cursor.write_all(&self.key)?;
+            cursor.write_all(&self.value)?;
         } else {
             cursor.write_u32::<LittleEndian>(self.value.len() as u32)?;
             cursor.write_all(&self.key)?;
@@ -91,10 +161,14 @@ impl<'a> Entry<'a> {
         let mut cursor = Cursor::new(Vec::with_capacity(ENTRY_STATIC_SIZE));
         cursor.set_position(4);
         cursor.write_u64::<LittleEndian>(self.sequence)?;
+        cursor.write_u64::<LittleEndian>(self.expiry.unwrap_or(ENTRY_NO_EXPIRY))?;
+        cursor.write_u32::<LittleEndian>(self.bucket)?;
         cursor.write_u16::<LittleEndian>(self.key.len() as u16)?;
 
         if self.deleted {
             cursor.write_u32::<LittleEndian>(ENTRY_TOMBSTONE)?;
+        } else if self.batch {
+            cursor.write_u32::<LittleEndian>(ENTRY_BATCH)?;
         } else {
             cursor.write_u32::<LittleEndian>(self.value.len() as u32)?;
         }
@@ -136,10 +210,13 @@ impl<'a> Entry<'a> {
         }
 
         let sequence = cursor.read_u64::<LittleEndian>()?;
+        let expiry = cursor.read_u64::<LittleEndian>()?;
+        let bucket = cursor.read_u32::<LittleEndian>()?;
         let key_size = cursor.read_u16::<LittleEndian>()?;
         let value_size = cursor.read_u32::<LittleEndian>()?;
 
         let deleted = value_size == ENTRY_TOMBSTONE;
+        let batch = value_size == ENTRY_BATCH;
 
         let value = if deleted {
             let empty: &[u8] = &[];
@@ -154,7 +231,10 @@ impl<'a> Entry<'a> {
             ),
             value: value,
             sequence: sequence,
+            expiry: to_expiry(expiry),
+            bucket: bucket,
             deleted: value_size == ENTRY_TOMBSTONE,
+            batch: batch,
         })
     }
 
@@ -165,6 +245,8 @@ impl<'a> Entry<'a> {
         let mut cursor = Cursor::new(header);
         let checksum = cursor.read_u32::<LittleEndian>()?;
         let sequence = cursor.read_u64::<LittleEndian>()?;
+        let expiry = cursor.read_u64::<LittleEndian>()?;
+        let bucket = cursor.read_u32::<LittleEndian>()?;
         let key_size = cursor.read_u16::<LittleEndian>()?;
         let value_size = cursor.read_u32::<LittleEndian>()?;
 
@@ -172,14 +254,17 @@ impl<'a> Entry<'a> {
         reader.read_exact(&mut key)?;
 
         let deleted = value_size == ENTRY_TOMBSTONE;
+        let batch = value_size == ENTRY_BATCH;
 
         let value = if deleted {
             let empty: &[u8] = &[];
             Cow::from(empty)
-        } else {
-            let mut value = vec![0u8; value_size as usize];
+        } else if batch {
+            let mut value = vec![0u8; BATCH_SIZE_SIZE];
             reader.read_exact(&mut value)?;
             Cow::from(value)
+        } else {
+            Cow::from(read_value(reader, value_size as usize)?)
         };
 
         let hash = {
@@ -201,7 +286,101 @@ impl<'a> Entry<'a> {
             key: Cow::from(key),
             value: value,
             sequence: sequence,
+            expiry: to_expiry(expiry),
+            bucket: bucket,
             deleted: deleted,
+            batch: batch,
+        })
+    }
+
+    /// Reads an entry laid out in version `LEGACY_FORMAT_VERSION`, which has neither an expiry
+    /// timestamp nor a bucket id.
+    pub fn from_read_legacy<R: Read>(reader: &mut R) -> Result<Entry<'a>> {
+        let mut header = vec![0u8; LEGACY_ENTRY_STATIC_SIZE];
+        reader.read_exact(&mut header)?;
+
+        let mut cursor = Cursor::new(header);
+        let checksum = cursor.read_u32::<LittleEndian>()?;
+        let sequence = cursor.read_u64::<LittleEndian>()?;
+        let key_size = cursor.read_u16::<LittleEndian>()?;
+        let value_size = cursor.read_u32::<LittleEndian>()?;
+
+        let mut key = vec![0u8; key_size as usize];
+        reader.read_exact(&mut key)?;
+
+        let deleted = value_size == ENTRY_TOMBSTONE;
+
+        let value = if deleted {
+            Vec::new()
+        } else {
+            read_value(reader, value_size as usize)?
+        };
+
+        let hash = {
+            let mut hasher = XxHash32::new();
+            hasher.update(&cursor.get_ref()[4..]);
+            hasher.update(&key);
+            hasher.update(&value);
+            hasher.get()
+        };
+
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found: hash,
+            });
+        }
+
+        if deleted {
+            Ok(Entry::deleted(sequence, key))
+        } else {
+            Entry::new(sequence, key, value)
+        }
+    }
+}
+
+/// The fixed size header of an entry in a data file, which is followed by the key and value.
+#[derive(Debug)]
+pub struct EntryHeader {
+    pub sequence: SequenceNumber,
+    pub expiry: Option<u64>,
+    pub bucket: BucketId,
+    pub key_size: u16,
+    /// Size of the value following the key, which is 0 for tombstones.
+    pub value_size: u32,
+    pub deleted: bool,
+    pub batch: bool,
+}
+
+impl EntryHeader {
+    /// Decodes the header at the start of `bytes`, without verifying the checksum of the entry.
+    pub fn from_bytes(bytes: &[u8]) -> Result<EntryHeader> {
+        let mut cursor = Cursor::new(bytes);
+        cursor.set_position(4); // checksum
+
+        let sequence = cursor.read_u64::<LittleEndian>()?;
+        let expiry = cursor.read_u64::<LittleEndian>()?;
+        let bucket = cursor.read_u32::<LittleEndian>()?;
+        let key_size = cursor.read_u16::<LittleEndian>()?;
+        let value_size = cursor.read_u32::<LittleEndian>()?;
+
+        let deleted = value_size == ENTRY_TOMBSTONE;
+        let batch = value_size == ENTRY_BATCH;
+
+        Ok(EntryHeader {
+            sequence: sequence,
+            expiry: to_expiry(expiry),
+            bucket: bucket,
+            key_size: key_size,
+            value_size: if deleted {
+                0
+            } else if batch {
+                BATCH_SIZE_SIZE as u32
+            } else {
+                value_size
+            },
+            deleted: deleted,
+            batch: batch,
         })
     }
 }
@@ -211,6 +390,8 @@ pub struct Hint<'a> {
     pub entry_pos: u64,
     pub value_size: u32,
     pub sequence: SequenceNumber,
+    pub expiry: Option<u64>,
+    pub bucket: BucketId,
     pub deleted: bool,
 }
 
@@ -221,6 +402,8 @@ impl<'a> Hint<'a> {
             entry_pos: entry_pos,
             value_size: e.value.len() as u32,
             sequence: e.sequence,
+            expiry: e.expiry,
+            bucket: e.bucket,
             deleted: e.deleted,
         }
     }
@@ -231,6 +414,8 @@ impl<'a> Hint<'a> {
             entry_pos: entry_pos,
             value_size: e.value.len() as u32,
             sequence: e.sequence,
+            expiry: e.expiry,
+            bucket: e.bucket,
             deleted: e.deleted,
         }
     }
@@ -239,8 +424,14 @@ impl<'a> Hint<'a> {
         ENTRY_STATIC_SIZE as u64 + self.key.len() as u64 + self.value_size as u64
     }
 
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expiry, now)
+    }
+
     pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
         writer.write_u64::<LittleEndian>(self.sequence)?;
+        writer.write_u64::<LittleEndian>(self.expiry.unwrap_or(ENTRY_NO_EXPIRY))?;
+        writer.write_u32::<LittleEndian>(self.bucket)?;
         writer.write_u16::<LittleEndian>(self.key.len() as u16)?;
 
         if self.deleted {
@@ -257,6 +448,8 @@ impl<'a> Hint<'a> {
 
     pub fn from_read<R: Read>(reader: &mut R) -> Result<Hint<'a>> {
         let sequence = reader.read_u64::<LittleEndian>()?;
+        let expiry = reader.read_u64::<LittleEndian>()?;
+        let bucket = reader.read_u32::<LittleEndian>()?;
         let key_size = reader.read_u16::<LittleEndian>()?;
         let value_size = reader.read_u32::<LittleEndian>()?;
         let entry_pos = reader.read_u64::<LittleEndian>()?;
@@ -271,16 +464,49 @@ impl<'a> Hint<'a> {
             entry_pos: entry_pos,
             value_size: if deleted { 0 } else { value_size },
             sequence: sequence,
+            expiry: to_expiry(expiry),
+            bucket: bucket,
             deleted: value_size == ENTRY_TOMBSTONE,
         })
     }
 }
 
+// the value size hasn't been validated yet at this point, so the buffer is grown as data is read
+// instead of being allocated upfront, which could exhaust memory on a corrupt header.
+fn read_value<R: Read>(reader: &mut R, value_size: usize) -> Result<Vec<u8>> {
+    let mut value = Vec::with_capacity(cmp::min(value_size, MAX_PREALLOCATED_VALUE_SIZE));
+    reader.take(value_size as u64).read_to_end(&mut value)?;
+
+    if value.len() < value_size {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "failed to read entry value",
+        )));
+    }
+
+    Ok(value)
+}
+
+fn to_expiry(expiry: u64) -> Option<u64> {
+    if expiry == ENTRY_NO_EXPIRY {
+        None
+    } else {
+        Some(expiry)
+    }
+}
+
+fn is_expired(expiry: Option<u64>, now: u64) -> bool {
+    match expiry {
+        Some(expiry) => expiry <= now,
+        None => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
-    use data::Entry;
+    use data::{Entry, Hint};
 
     #[test]
     fn test_serialization() {
@@ -290,7 +516,7 @@ mod tests {
         let entry = Entry::new(sequence, key, value).unwrap();
         let deleted_entry = Entry::deleted(sequence, key);
 
-        assert_eq!(entry.to_bytes().unwrap().len(), 24);
+        assert_eq!(entry.to_bytes().unwrap().len(), 36);
 
         assert_eq!(
             entry,
@@ -317,6 +543,50 @@ mod tests {
         assert_eq!(deleted_entry, Entry::from_bytes(&v).unwrap());
     }
 
+    #[test]
+    fn test_batch() {
+        let batch = Entry::batch(0, 3);
+
+        assert!(batch.batch);
+        assert_eq!(batch.batch_size(), Some(3));
+        assert_eq!(batch.to_bytes().unwrap().len(), 34);
+
+        assert_eq!(
+            batch,
+            Entry::from_bytes(&batch.to_bytes().unwrap()).unwrap()
+        );
+        assert_eq!(
+            batch,
+            Entry::from_read(&mut Cursor::new(batch.to_bytes().unwrap())).unwrap()
+        );
+        let mut v = Vec::new();
+        batch.write_bytes(&mut v).unwrap();
+        assert_eq!(batch, Entry::from_bytes(&v).unwrap());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let key: &[u8] = &[0, 0, 0];
+        let value: &[u8] = &[0, 0, 0];
+        let mut entry = Entry::new(0, key, value).unwrap();
+        entry.expiry = Some(1000);
+        entry.bucket = 7;
+
+        assert_eq!(
+            entry,
+            Entry::from_read(&mut Cursor::new(entry.to_bytes().unwrap())).unwrap()
+        );
+
+        assert!(!entry.is_expired(999));
+        assert!(entry.is_expired(1000));
+
+        let mut v = Vec::new();
+        Hint::new(&entry, 0).write_bytes(&mut v).unwrap();
+        let hint = Hint::from_read(&mut Cursor::new(v)).unwrap();
+        assert_eq!(hint.expiry, Some(1000));
+        assert_eq!(hint.bucket, 7);
+    }
+
     #[test]
     fn test_deleted() {
         let sequence = 0;
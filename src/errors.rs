@@ -5,7 +5,7 @@ use std::fmt::{Display, Formatter};
 use std::io;
 use std::result;
 
-use data::{MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use data::{SequenceNumber, FORMAT_VERSION, MAX_KEY_SIZE, MAX_VALUE_SIZE};
 
 /// Basic type to represent all possible errors that can occur when interacting with a `Cask`.
 #[derive(Debug)]
@@ -20,8 +20,36 @@ pub enum Error {
     InvalidValueSize(usize),
     /// Invalid checksum found, potential data corruption.
     InvalidChecksum { expected: u32, found: u32 },
+    /// Corrupt entry found at position `entry_pos` of the data file `file_id`.
+    CorruptEntry {
+        file_id: u32,
+        entry_pos: u64,
+        cause: Box<Error>,
+    },
     /// Invalid path provided.
     InvalidPath(String),
+    /// Tried to write to a `Cask` opened in read-only mode or as a replication follower.
+    ReadOnly,
+    /// Tried to apply a replication record to a `Cask` which wasn't opened as a follower.
+    NotFollower,
+    /// Tried to import an entry whose sequence number isn't greater than the sequence number of
+    /// the last write.
+    InvalidSequence {
+        last: SequenceNumber,
+        found: SequenceNumber,
+    },
+    /// Tried to perform an operation on an `AsyncCask` which has been shut down.
+    ShutDown,
+    /// Failed to encode a key or value of a `TypedCask`.
+    Encode(Box<dyn error::Error + Send + Sync>),
+    /// Failed to decode a key or value of a `TypedCask`, e.g. because it was written with another
+    /// type or codec.
+    Decode(Box<dyn error::Error + Send + Sync>),
+    /// The data files were written in another format version, e.g. by a newer version of this
+    /// crate, and can't be read. See `CaskOptions::open` for the data files of older versions.
+    UnsupportedFormat(u32),
+    /// Invalid bucket name, or tried to use a bucket which has been dropped.
+    InvalidBucket(String),
 }
 
 /// Value returned from potentially-error operations.
@@ -47,7 +75,49 @@ impl Display for Error {
                 "Invalid checksum, expected: {}, found: {}",
                 expected, found
             ),
+            Error::CorruptEntry {
+                file_id,
+                entry_pos,
+                ref cause,
+            } => write!(
+                f,
+                "Corrupt entry in file {} at position {}: {}",
+                file_id, entry_pos, cause
+            ),
             Error::InvalidPath(ref path) => write!(f, "Invalid path provided: {}", path),
+            Error::ReadOnly => write!(f, "Cask is read-only"),
+            Error::NotFollower => write!(f, "Cask is not a replication follower"),
+            Error::InvalidSequence { last, found } => write!(
+                f,
+                "Invalid sequence number, last: {}, found: {}",
+                last, found
+            ),
+            Error::ShutDown => write!(f, "Cask has been shut down"),
+            Error::Encode(ref err) => write!(f, "Encode error: {}", err),
+            Error::Decode(ref err) => write!(f, "Decode error: {}", err),
+            Error::UnsupportedFormat(version) => write!(
+                f,
+                "Unsupported data format version, expected: {}, found: {}",
+                FORMAT_VERSION, version
+            ),
+            Error::InvalidBucket(ref name) => write!(f, "Invalid bucket: {:?}", name),
+        }
+    }
+}
+
+impl Error {
+    /// Returns `true` if this error was caused by invalid data, e.g. a checksum mismatch or a
+    /// truncated entry, rather than by a failure to perform IO.
+    pub(crate) fn is_corruption(&self) -> bool {
+        match *self {
+            Error::Io(ref err) => {
+                err.kind() == io::ErrorKind::UnexpectedEof || err.kind() == io::ErrorKind::InvalidData
+            }
+            Error::InvalidChecksum { .. } |
+            Error::InvalidKeySize(..) |
+            Error::InvalidValueSize(..) |
+            Error::CorruptEntry { .. } => true,
+            _ => false,
         }
     }
 }
@@ -64,15 +134,26 @@ impl error::Error for Error {
             Error::Io(ref err) => err.description(),
             Error::InvalidFileId(..) => "Invalid file id",
             Error::InvalidChecksum { .. } => "Invalid checksum",
+            Error::CorruptEntry { .. } => "Corrupt entry",
             Error::InvalidKeySize(..) => "Invalid key size",
             Error::InvalidValueSize(..) => "Invalid value size",
             Error::InvalidPath(..) => "Invalid path",
+            Error::ReadOnly => "Cask is read-only",
+            Error::NotFollower => "Cask is not a replication follower",
+            Error::InvalidSequence { .. } => "Invalid sequence number",
+            Error::ShutDown => "Cask has been shut down",
+            Error::Encode(..) => "Encode error",
+            Error::Decode(..) => "Decode error",
+            Error::UnsupportedFormat(..) => "Unsupported data format version",
+            Error::InvalidBucket(..) => "Invalid bucket",
         }
     }
 
     fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             Error::Io(ref err) => Some(err),
+            Error::CorruptEntry { ref cause, .. } => Some(&**cause),
+            Error::Encode(ref err) | Error::Decode(ref err) => Some(&**err),
             _ => None,
         }
     }
@@ -0,0 +1,226 @@
+use std::io::{self, BufRead, Read, Write};
+use std::vec::Vec;
+
+use base64;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde_json::{self, Map, Value};
+
+use data::{Entry, SequenceNumber};
+use errors::{Error, Result};
+
+const BINARY_MAGIC: &'static [u8] = b"CASKEXP1";
+
+/// Format of the data written by `Cask::export` and read by `Cask::import`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// One JSON object per line, with the key and value encoded as base64 strings:
+    /// `{"key":"aGVsbG8=","value":"d29ybGQ=","sequence":1}`, along with the expiry timestamp (in
+    /// milliseconds since the epoch) of entries written with a TTL. `sequence` and `expiry` are
+    /// optional when importing, and any other field is ignored.
+    JsonLines,
+    /// A header followed by one record per key-value pair: the sequence number (8 bytes), expiry
+    /// timestamp (8 bytes, 0 if none), key size (2 bytes) and value size (4 bytes), all little
+    /// endian, followed by the key and value.
+    Binary,
+}
+
+/// Writes the records of an export.
+pub struct ExportWriter<W: Write> {
+    writer: W,
+    format: ExportFormat,
+}
+
+impl<W: Write> ExportWriter<W> {
+    pub fn new(mut writer: W, format: ExportFormat) -> Result<ExportWriter<W>> {
+        if format == ExportFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+        }
+
+        Ok(ExportWriter {
+            writer: writer,
+            format: format,
+        })
+    }
+
+    pub fn write(&mut self, entry: &Entry) -> Result<()> {
+        match self.format {
+            ExportFormat::JsonLines => {
+                write!(
+                    self.writer,
+                    "{{\"key\":\"{}\",\"value\":\"{}\",\"sequence\":{}",
+                    base64::encode(&entry.key),
+                    base64::encode(&entry.value),
+                    entry.sequence
+                )?;
+
+                if let Some(expiry) = entry.expiry {
+                    write!(self.writer, ",\"expiry\":{}", expiry)?;
+                }
+
+                writeln!(self.writer, "}}")?;
+            }
+            ExportFormat::Binary => {
+                self.writer.write_u64::<LittleEndian>(entry.sequence)?;
+                self.writer
+                    .write_u64::<LittleEndian>(entry.expiry.unwrap_or(0))?;
+                self.writer.write_u16::<LittleEndian>(entry.key.len() as u16)?;
+                self.writer
+                    .write_u32::<LittleEndian>(entry.value.len() as u32)?;
+                self.writer.write_all(&entry.key)?;
+                self.writer.write_all(&entry.value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the records of an export, as entries. The sequence number of the records which don't
+/// have one is `None`.
+pub struct ExportReader<R: BufRead> {
+    reader: R,
+    format: ExportFormat,
+    line: usize,
+}
+
+impl<R: BufRead> ExportReader<R> {
+    pub fn new(mut reader: R, format: ExportFormat) -> Result<ExportReader<R>> {
+        if format == ExportFormat::Binary {
+            let mut magic = [0u8; 8];
+            reader.read_exact(&mut magic)?;
+
+            if magic != BINARY_MAGIC {
+                return Err(invalid_export("missing header"));
+            }
+        }
+
+        Ok(ExportReader {
+            reader: reader,
+            format: format,
+            line: 0,
+        })
+    }
+
+    fn read_json_record(&mut self) -> Result<Option<(Option<SequenceNumber>, Entry<'static>)>> {
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            self.line += 1;
+
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let object: Map<String, Value> = serde_json::from_str(&line)
+            .map_err(|err| self.invalid_line(&err.to_string()))?;
+
+        // unknown fields are ignored
+        let key = match object.get("key") {
+            Some(&Value::String(ref s)) => Some(decode_base64(s)?),
+            Some(_) => return Err(self.invalid_line("invalid field key")),
+            None => None,
+        };
+        let value = match object.get("value") {
+            Some(&Value::String(ref s)) => Some(decode_base64(s)?),
+            Some(_) => return Err(self.invalid_line("invalid field value")),
+            None => None,
+        };
+        let sequence = self.number_field(&object, "sequence")?;
+        let expiry = self.number_field(&object, "expiry")?;
+
+        match (key, value) {
+            (Some(key), Some(value)) => {
+                let mut entry = Entry::new(sequence.unwrap_or(0), key, value)?;
+                entry.expiry = expiry;
+                Ok(Some((sequence, entry)))
+            }
+            _ => Err(self.invalid_line("missing key or value")),
+        }
+    }
+
+    fn read_binary_record(&mut self) -> Result<Option<(Option<SequenceNumber>, Entry<'static>)>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let sequence = self.reader.read_u64::<LittleEndian>()?;
+        let expiry = self.reader.read_u64::<LittleEndian>()?;
+        let key_size = self.reader.read_u16::<LittleEndian>()?;
+        let value_size = self.reader.read_u32::<LittleEndian>()?;
+
+        let mut key = vec![0u8; key_size as usize];
+        self.reader.read_exact(&mut key)?;
+
+        let mut value = Vec::new();
+        (&mut self.reader)
+            .take(u64::from(value_size))
+            .read_to_end(&mut value)?;
+        if value.len() != value_size as usize {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated export record",
+            )));
+        }
+
+        let mut entry = Entry::new(sequence, key, value)?;
+        if expiry != 0 {
+            entry.expiry = Some(expiry);
+        }
+
+        Ok(Some((Some(sequence), entry)))
+    }
+
+    /// Returns the unsigned integer field `name` of `object`, which is optional and may be null.
+    fn number_field(&self, object: &Map<String, Value>, name: &str) -> Result<Option<u64>> {
+        match object.get(name) {
+            None | Some(&Value::Null) => Ok(None),
+            Some(value) => match value.as_u64() {
+                Some(n) => Ok(Some(n)),
+                None => Err(self.invalid_line(&format!("invalid field {}", name))),
+            },
+        }
+    }
+
+    fn invalid_line(&self, msg: &str) -> Error {
+        invalid_export(&format!("line {}: {}", self.line, msg))
+    }
+}
+
+impl<R: BufRead> Iterator for ExportReader<R> {
+    type Item = Result<(Option<SequenceNumber>, Entry<'static>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.format {
+            ExportFormat::JsonLines => self.read_json_record(),
+            ExportFormat::Binary => self.read_binary_record(),
+        };
+
+        match record {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>> {
+    base64::decode(s).map_err(|_| invalid_export("invalid base64"))
+}
+
+fn invalid_export(msg: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid export: {}", msg),
+    ))
+}
@@ -0,0 +1,692 @@
+//! An HTTP server exposing a `Cask` through a small REST API, with JSON responses:
+//!
+//! - `GET /keys/{key}` returns the value of `key` as the response body.
+//! - `PUT /keys/{key}` stores the request body as the value of `key`.
+//! - `DELETE /keys/{key}` deletes `key`.
+//! - `GET /keys?prefix={prefix}` lists the live keys starting with `prefix` (all of them if no
+//!   prefix is given), in key order.
+//! - `GET /stats` returns the statistics of `Cask::stats`, with the time of the last compaction
+//!   in seconds since the epoch.
+//! - `POST /compact` triggers log compaction.
+//!
+//! Keys are percent-encoded in paths and query strings, and listed percent-encoded as well so
+//! that they can be used in paths as is. Values are streamed from the data files to the client,
+//! see `Cask::get_reader`, while request bodies are read in full since a value is written as a
+//! single entry. Bodies larger than `DEFAULT_MAX_BODY_SIZE`, or the size given to
+//! `serve_with_max_body_size`, are refused with a 413 response.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use std::net::TcpListener;
+//!
+//! use cask::CaskOptions;
+//! use cask::http;
+//!
+//! let cask = CaskOptions::default().open("cask.db").unwrap();
+//! let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
+//!
+//! http::serve(&cask, listener).unwrap();
+//! ```
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::thread;
+use std::time::UNIX_EPOCH;
+use std::vec::Vec;
+
+use cask::Cask;
+use data::MAX_VALUE_SIZE;
+use errors::{Error, Result};
+
+/// Maximum size of the request line and of each header.
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+/// Maximum number of headers of a request.
+const MAX_HEADERS: usize = 100;
+
+/// Maximum size of a request body used by `serve`, in bytes.
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Serves `cask` to the HTTP clients connecting to `listener`. Each client is served by its own
+/// thread.
+pub fn serve(cask: &Cask, listener: TcpListener) -> Result<()> {
+    serve_with_max_body_size(cask, listener, DEFAULT_MAX_BODY_SIZE)
+}
+
+/// Like `serve`, but refuses request bodies larger than `max_body_size` bytes instead of
+/// `DEFAULT_MAX_BODY_SIZE`. Bodies are buffered in memory, so this bounds the memory used by
+/// each client.
+pub fn serve_with_max_body_size(
+    cask: &Cask,
+    listener: TcpListener,
+    max_body_size: u64,
+) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let cask = cask.clone();
+
+        thread::spawn(move || {
+            if let Err(err) = handle_client(&cask, stream, max_body_size) {
+                warn!("Closed connection to HTTP client: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    content_length: Option<u64>,
+    chunked: bool,
+    close: bool,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Response {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn json(status: u16, body: String) -> Response {
+        let mut response = Response::new(status);
+        response
+            .headers
+            .push(("Content-Type", "application/json".to_owned()));
+        response.body = body.into_bytes();
+        response
+    }
+
+    fn error(status: u16, msg: &str) -> Response {
+        Response::json(status, format!("{{\"error\":{}}}", json_string(msg)))
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, close: bool) -> Result<()> {
+        write_head(writer, self.status, &self.headers, self.body.len() as u64, close)?;
+        writer.write_all(&self.body)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn handle_client(cask: &Cask, stream: TcpStream, max_body_size: u64) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                Response::error(400, &err.to_string()).write(&mut writer, true)?;
+                return Err(err);
+            }
+        };
+
+        if request.chunked {
+            // the body can't be skipped, so the connection can't be used anymore
+            Response::error(411, "chunked request bodies aren't supported")
+                .write(&mut writer, true)?;
+            return Ok(());
+        }
+
+        let key = if request.path.starts_with("/keys/") {
+            Some(percent_decode(&request.path["/keys/".len()..]))
+        } else {
+            None
+        };
+
+        let response = match (&request.method[..], &request.path[..], key) {
+            ("GET", _, Some(Some(ref key))) if !key.is_empty() => {
+                skip_body(&mut reader, &request)?;
+
+                match cask.get_reader(key) {
+                    Ok(Some(mut value)) => {
+                        let headers = vec![("Content-Type", "application/octet-stream".to_owned())];
+                        write_head(&mut writer, 200, &headers, value.len(), request.close)?;
+
+                        // the response can't be failed once its head has been sent, so the
+                        // connection is closed instead
+                        io::copy(&mut value, &mut writer)?;
+                        writer.flush()?;
+
+                        if request.close {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    Ok(None) => Response::error(404, "key not found"),
+                    Err(err) => error_response(&err),
+                }
+            }
+            ("PUT", _, Some(Some(ref key))) if !key.is_empty() => {
+                match request.content_length {
+                    Some(len) if len <= max_body_size && len <= u64::from(MAX_VALUE_SIZE) => {
+                        let mut value = Vec::new();
+                        (&mut reader).take(len).read_to_end(&mut value)?;
+                        if value.len() as u64 != len {
+                            return Err(Error::Io(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "truncated request body",
+                            )));
+                        }
+
+                        match cask.put(key.clone(), &value) {
+                            Ok(sequence) => {
+                                Response::json(200, format!("{{\"sequence\":{}}}", sequence))
+                            }
+                            Err(err) => error_response(&err),
+                        }
+                    }
+                    Some(_) => {
+                        Response::error(413, "value too large").write(&mut writer, true)?;
+                        return Ok(());
+                    }
+                    None => {
+                        Response::error(411, "missing Content-Length").write(&mut writer, true)?;
+                        return Ok(());
+                    }
+                }
+            }
+            ("DELETE", _, Some(Some(ref key))) if !key.is_empty() => {
+                skip_body(&mut reader, &request)?;
+
+                match cask.delete(key) {
+                    Ok(Some(sequence)) => {
+                        Response::json(200, format!("{{\"sequence\":{}}}", sequence))
+                    }
+                    Ok(None) => Response::error(404, "key not found"),
+                    Err(err) => error_response(&err),
+                }
+            }
+            (_, _, Some(Some(ref key))) if !key.is_empty() => {
+                skip_body(&mut reader, &request)?;
+                method_not_allowed("GET, PUT, DELETE")
+            }
+            (_, _, Some(_)) => {
+                skip_body(&mut reader, &request)?;
+                Response::error(400, "invalid key")
+            }
+            (method, "/keys", None) => {
+                skip_body(&mut reader, &request)?;
+
+                if method == "GET" {
+                    list_keys(cask, request.query.as_ref().map(|q| &q[..]))
+                } else {
+                    method_not_allowed("GET")
+                }
+            }
+            (method, "/stats", None) => {
+                skip_body(&mut reader, &request)?;
+
+                if method == "GET" {
+                    match cask.stats() {
+                        Ok(stats) => Response::json(200, stats_json(&stats)),
+                        Err(err) => error_response(&err),
+                    }
+                } else {
+                    method_not_allowed("GET")
+                }
+            }
+            (method, "/compact", None) => {
+                skip_body(&mut reader, &request)?;
+
+                if method == "POST" {
+                    match cask.compact() {
+                        Ok(()) => Response::new(204),
+                        Err(err) => error_response(&err),
+                    }
+                } else {
+                    method_not_allowed("POST")
+                }
+            }
+            _ => {
+                skip_body(&mut reader, &request)?;
+                Response::error(404, "not found")
+            }
+        };
+
+        response.write(&mut writer, request.close)?;
+
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+fn list_keys(cask: &Cask, query: Option<&str>) -> Response {
+    let mut prefix = Vec::new();
+
+    for param in query.unwrap_or("").split('&') {
+        let mut parts = param.splitn(2, '=');
+        if let (Some("prefix"), Some(value)) = (parts.next(), parts.next()) {
+            match percent_decode(&value.replace('+', " ")) {
+                Some(value) => prefix = value,
+                None => return Response::error(400, "invalid prefix"),
+            }
+        }
+    }
+
+    let mut keys: Vec<_> = cask
+        .keys()
+        .into_iter()
+        .filter(|key| key.starts_with(&prefix))
+        .collect();
+    keys.sort();
+
+    let keys: Vec<_> = keys
+        .iter()
+        .map(|key| format!("\"{}\"", percent_encode(key)))
+        .collect();
+
+    Response::json(200, format!("{{\"keys\":[{}]}}", keys.join(",")))
+}
+
+fn stats_json(stats: &::stats::CaskStats) -> String {
+    let files: Vec<_> = stats
+        .files
+        .iter()
+        .map(|file| {
+            format!(
+                "{{\"file_id\":{},\"size\":{},\"entries\":{},\"dead_entries\":{},\
+                 \"live_bytes\":{},\"dead_bytes\":{},\"fragmentation\":{}}}",
+                file.file_id,
+                file.size,
+                file.entries,
+                file.dead_entries,
+                file.live_bytes,
+                file.dead_bytes,
+                file.fragmentation
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"keys\":{},\"live_bytes\":{},\"dead_bytes\":{},\"disk_size\":{},\"sequence\":{},\
+         \"active_file_id\":{},\"last_compaction\":{},\"files\":[{}]}}",
+        stats.keys,
+        stats.live_bytes,
+        stats.dead_bytes,
+        stats.disk_size,
+        stats.sequence,
+        match stats.active_file_id {
+            Some(file_id) => file_id.to_string(),
+            None => "null".to_owned(),
+        },
+        // in seconds since the epoch
+        match stats
+            .last_compaction
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        {
+            Some(since_epoch) => since_epoch.as_secs().to_string(),
+            None => "null".to_owned(),
+        },
+        files.join(",")
+    )
+}
+
+fn method_not_allowed(allow: &str) -> Response {
+    let mut response = Response::error(405, "method not allowed");
+    response.headers.push(("Allow", allow.to_owned()));
+    response
+}
+
+fn error_response(err: &Error) -> Response {
+    let status = match *err {
+        Error::ReadOnly => 403,
+        Error::InvalidKeySize(..) => 400,
+        Error::InvalidValueSize(..) => 413,
+        _ => 500,
+    };
+
+    Response::error(status, &err.to_string())
+}
+
+fn write_head<W: Write>(
+    writer: &mut W,
+    status: u16,
+    headers: &[(&'static str, String)],
+    content_length: u64,
+    close: bool,
+) -> Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\n", status, reason(status))?;
+
+    for &(name, ref value) in headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+
+    if status != 204 {
+        write!(writer, "Content-Length: {}\r\n", content_length)?;
+    }
+    if close {
+        write!(writer, "Connection: close\r\n")?;
+    }
+
+    write!(writer, "\r\n")?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Reads the head of a request, returning `None` once the client has gone away.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>> {
+    let mut line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    // clients may send empty lines between requests
+    while line.is_empty() {
+        line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+    }
+
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(bad_request("invalid request line")),
+    };
+
+    let mut target = target.splitn(2, '?');
+    let mut request = Request {
+        method: method.to_owned(),
+        path: target.next().unwrap().to_owned(),
+        query: target.next().map(|query| query.to_owned()),
+        content_length: None,
+        chunked: false,
+        close: version != "HTTP/1.1",
+    };
+
+    for _ in 0..MAX_HEADERS + 1 {
+        let line = read_line(reader)?.ok_or_else(|| bad_request("unexpected end of stream"))?;
+        if line.is_empty() {
+            return Ok(Some(request));
+        }
+
+        let mut header = line.splitn(2, ':');
+        let (name, value) = match (header.next(), header.next()) {
+            (Some(name), Some(value)) => (name.trim().to_lowercase(), value.trim()),
+            _ => return Err(bad_request("invalid header")),
+        };
+
+        match &name[..] {
+            "content-length" => {
+                request.content_length = Some(
+                    value
+                        .parse()
+                        .map_err(|_| bad_request("invalid Content-Length"))?,
+                )
+            }
+            "transfer-encoding" => request.chunked = !value.eq_ignore_ascii_case("identity"),
+            "connection" => {
+                if value.eq_ignore_ascii_case("close") {
+                    request.close = true;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    request.close = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Err(bad_request("too many headers"))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut line = Vec::new();
+
+    if reader
+        .take(MAX_LINE_SIZE as u64)
+        .read_until(b'\n', &mut line)? == 0
+    {
+        return Ok(None);
+    }
+
+    if line.last() != Some(&b'\n') {
+        return Err(bad_request("line too long"));
+    }
+
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("invalid UTF-8"))
+}
+
+fn skip_body<R: Read>(reader: &mut R, request: &Request) -> Result<()> {
+    if let Some(len) = request.content_length {
+        io::copy(&mut reader.take(len), &mut io::sink())?;
+    }
+
+    Ok(())
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let b = u8::from_str_radix(str::from_utf8(hex).ok()?, 16).ok()?;
+            decoded.push(b);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(decoded)
+}
+
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+
+    for &b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    encoded
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+fn bad_request(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    use cask::{CaskOptions, SyncStrategy};
+    use http::{serve, serve_with_max_body_size};
+
+    fn request(addr: SocketAddr, method: &str, target: &str, body: &[u8]) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            target,
+            body.len()
+        ).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+
+        let head_len = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..head_len].to_vec()).unwrap();
+        let status_line = head.lines().next().unwrap().to_owned();
+
+        (status_line, response[head_len + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_http() {
+        let path = "test-http.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(&cask, listener));
+
+        let large: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+
+        let (status, body) = request(addr, "PUT", "/keys/a", b"1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, b"{\"sequence\":1}");
+        request(addr, "PUT", "/keys/a%20b", &large);
+        request(addr, "PUT", "/keys/b", b"2");
+
+        assert_eq!(
+            request(addr, "GET", "/keys/a", b""),
+            ("HTTP/1.1 200 OK".to_owned(), b"1".to_vec())
+        );
+        let (status, body) = request(addr, "GET", "/keys/a%20b", b"");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body == large);
+        assert_eq!(
+            request(addr, "GET", "/keys/c", b"").0,
+            "HTTP/1.1 404 Not Found"
+        );
+
+        assert_eq!(
+            request(addr, "GET", "/keys?prefix=a", b"").1,
+            b"{\"keys\":[\"a\",\"a%20b\"]}"
+        );
+        assert_eq!(
+            request(addr, "GET", "/keys", b"").1,
+            b"{\"keys\":[\"a\",\"a%20b\",\"b\"]}"
+        );
+
+        assert_eq!(
+            request(addr, "DELETE", "/keys/a", b""),
+            ("HTTP/1.1 200 OK".to_owned(), b"{\"sequence\":4}".to_vec())
+        );
+        assert_eq!(
+            request(addr, "DELETE", "/keys/a", b"").0,
+            "HTTP/1.1 404 Not Found"
+        );
+
+        let (status, body) = request(addr, "GET", "/stats", b"");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("{\"keys\":2,"));
+        assert!(body.contains("\"last_compaction\":null,"));
+
+        assert_eq!(
+            request(addr, "POST", "/compact", b"").0,
+            "HTTP/1.1 204 No Content"
+        );
+        assert_eq!(
+            request(addr, "GET", "/compact", b"").0,
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+        assert_eq!(request(addr, "GET", "/", b"").0, "HTTP/1.1 404 Not Found");
+
+        // requests on a persistent connection
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /keys/b HTTP/1.1\r\n\r\n").unwrap();
+        stream
+            .write_all(b"GET /keys/b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+
+    #[test]
+    fn test_http_max_body_size() {
+        let path = "test-http-max-body-size.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        {
+            let cask = cask.clone();
+            thread::spawn(move || serve_with_max_body_size(&cask, listener, 16));
+        }
+
+        assert_eq!(
+            request(addr, "PUT", "/keys/a", &[0; 16]).0,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(
+            request(addr, "PUT", "/keys/b", &[0; 17]).0,
+            "HTTP/1.1 413 Payload Too Large"
+        );
+        assert_eq!(cask.get("a").unwrap(), Some(vec![0; 16]));
+        assert_eq!(cask.get("b").unwrap(), None);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+//! Low-level decoding of single data and hint files, for debugging corruption.
+//!
+//! Unlike opening a `Cask`, inspecting a file doesn't stop at the first corrupt entry: entries
+//! whose checksum doesn't match are reported and decoding carries on with the next entry, until
+//! the file ends or an entry can't be decoded at all. Hint files are additionally checked against
+//! the xxhash32 checksum appended when they're finished.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use cask::inspect;
+//!
+//! let inspection = inspect::inspect_file("cask.db/1.cask.data").unwrap();
+//! print!("{}", inspection);
+//! ```
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read};
+use std::path::Path;
+use std::vec::Vec;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use data::{BucketId, Entry, EntryHeader, Hint, SequenceNumber, DEFAULT_BUCKET};
+use errors::{Error, Result};
+use log::{DATA_FILE_EXTENSION, HINT_FILE_EXTENSION};
+use util::xxhash32;
+
+/// The kind of file being inspected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    Data,
+    Hint,
+}
+
+/// The status of a checksum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Checksum {
+    Valid,
+    Invalid { expected: u32, found: u32 },
+    /// There's no checksum, e.g. the hint file of the active data file which isn't finished yet.
+    Missing,
+}
+
+/// A single decoded entry of a data file, or hint of a hint file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Position of the record in the file.
+    pub offset: u64,
+    pub sequence: SequenceNumber,
+    pub key_size: u16,
+    /// Size of the value, which is 0 for tombstones.
+    pub value_size: u32,
+    pub expiry: Option<u64>,
+    /// Id of the bucket of the entry, see `Cask::bucket`.
+    pub bucket: BucketId,
+    pub deleted: bool,
+    /// Whether the entry is a batch marker, which is never the case for hints.
+    pub batch: bool,
+    /// Position of the entry in the data file, for hints.
+    pub entry_pos: Option<u64>,
+    /// Status of the checksum of the entry, for data files. Hints have no checksum of their own,
+    /// the hint file as a whole has one, see `FileInspection::checksum`.
+    pub checksum: Option<Checksum>,
+}
+
+/// The result of inspecting a data or hint file, see `inspect_file`.
+#[derive(Debug)]
+pub struct FileInspection {
+    pub kind: FileKind,
+    pub records: Vec<Record>,
+    /// Position at which decoding stopped and the reason why, if the file couldn't be decoded to
+    /// the end.
+    pub error: Option<(u64, Error)>,
+    /// Status of the trailing checksum, for hint files.
+    pub checksum: Option<Checksum>,
+}
+
+/// Decodes the data (`.cask.data`) or hint (`.cask.hint`) file at `path`, which doesn't need to
+/// belong to an open `Cask`.
+pub fn inspect_file<P: AsRef<Path>>(path: P) -> Result<FileInspection> {
+    let path = path.as_ref();
+    let file_name = path.to_string_lossy().into_owned();
+
+    if file_name.ends_with(DATA_FILE_EXTENSION) {
+        inspect_data_file(File::open(path)?)
+    } else if file_name.ends_with(HINT_FILE_EXTENSION) {
+        inspect_hint_file(File::open(path)?)
+    } else {
+        Err(Error::InvalidPath(file_name))
+    }
+}
+
+fn inspect_data_file(data_file: File) -> Result<FileInspection> {
+    let mut reader = BufReader::new(data_file);
+    let mut records = Vec::new();
+    let mut offset = 0;
+    let mut error = None;
+
+    while !reader.fill_buf()?.is_empty() {
+        let mut recorder = Recorder {
+            reader: &mut reader,
+            bytes: Vec::new(),
+        };
+
+        let record = match Entry::from_read(&mut recorder) {
+            Ok(entry) => Ok(Record {
+                offset: offset,
+                sequence: entry.sequence,
+                key_size: entry.key.len() as u16,
+                value_size: entry.value.len() as u32,
+                expiry: entry.expiry,
+                bucket: entry.bucket,
+                deleted: entry.deleted,
+                batch: entry.batch,
+                entry_pos: None,
+                checksum: Some(Checksum::Valid),
+            }),
+            // the whole entry has been read before its checksum is verified, so its header can be
+            // decoded from the bytes that were read
+            Err(Error::InvalidChecksum { expected, found }) => {
+                EntryHeader::from_bytes(&recorder.bytes).map(|header| Record {
+                    offset: offset,
+                    sequence: header.sequence,
+                    key_size: header.key_size,
+                    value_size: header.value_size,
+                    expiry: header.expiry,
+                    bucket: header.bucket,
+                    deleted: header.deleted,
+                    batch: header.batch,
+                    entry_pos: None,
+                    checksum: Some(Checksum::Invalid {
+                        expected: expected,
+                        found: found,
+                    }),
+                })
+            }
+            Err(err) => Err(err),
+        };
+
+        match record {
+            Ok(record) => records.push(record),
+            Err(err) => {
+                error = Some((offset, err));
+                break;
+            }
+        }
+
+        offset += recorder.bytes.len() as u64;
+    }
+
+    Ok(FileInspection {
+        kind: FileKind::Data,
+        records: records,
+        error: error,
+        checksum: None,
+    })
+}
+
+fn inspect_hint_file(mut hint_file: File) -> Result<FileInspection> {
+    let mut buf = Vec::new();
+    hint_file.read_to_end(&mut buf)?;
+
+    // a hint file which wasn't finished has no trailing checksum, which is told apart from an
+    // invalid checksum by whether the whole file decodes to hints
+    let (records, error) = decode_hints(&buf);
+    if error.is_none() || buf.len() < 4 {
+        return Ok(FileInspection {
+            kind: FileKind::Hint,
+            records: records,
+            error: error,
+            checksum: Some(Checksum::Missing),
+        });
+    }
+
+    let hints_len = buf.len() - 4;
+    let expected = Cursor::new(&buf[hints_len..]).read_u32::<LittleEndian>()?;
+    let found = xxhash32(&buf[..hints_len]);
+
+    let (records, error) = decode_hints(&buf[..hints_len]);
+
+    Ok(FileInspection {
+        kind: FileKind::Hint,
+        records: records,
+        error: error,
+        checksum: Some(if expected == found {
+            Checksum::Valid
+        } else {
+            Checksum::Invalid {
+                expected: expected,
+                found: found,
+            }
+        }),
+    })
+}
+
+fn decode_hints(buf: &[u8]) -> (Vec<Record>, Option<(u64, Error)>) {
+    let mut cursor = Cursor::new(buf);
+    let mut records = Vec::new();
+
+    while cursor.position() < buf.len() as u64 {
+        let offset = cursor.position();
+
+        match Hint::from_read(&mut cursor) {
+            Ok(hint) => records.push(Record {
+                offset: offset,
+                sequence: hint.sequence,
+                key_size: hint.key.len() as u16,
+                value_size: hint.value_size,
+                expiry: hint.expiry,
+                bucket: hint.bucket,
+                deleted: hint.deleted,
+                batch: false,
+                entry_pos: Some(hint.entry_pos),
+                checksum: None,
+            }),
+            Err(err) => return (records, Some((offset, err))),
+        }
+    }
+
+    (records, None)
+}
+
+/// Keeps the bytes read through it, so that a corrupt entry can still be decoded.
+struct Recorder<'a, R: 'a> {
+    reader: &'a mut R,
+    bytes: Vec<u8>,
+}
+
+impl<'a, R: Read> Read for Recorder<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Checksum::Valid => write!(f, "valid"),
+            Checksum::Invalid { expected, found } => {
+                write!(f, "invalid (expected: {}, found: {})", expected, found)
+            }
+            Checksum::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "offset: {}, sequence: {}, key size: {}, value size: {}, tombstone: {}",
+            self.offset, self.sequence, self.key_size, self.value_size, self.deleted
+        )?;
+
+        if self.batch {
+            write!(f, ", batch marker")?;
+        }
+        if let Some(expiry) = self.expiry {
+            write!(f, ", expiry: {}", expiry)?;
+        }
+        if self.bucket != DEFAULT_BUCKET {
+            write!(f, ", bucket: {}", self.bucket)?;
+        }
+        if let Some(entry_pos) = self.entry_pos {
+            write!(f, ", entry position: {}", entry_pos)?;
+        }
+        if let Some(checksum) = self.checksum {
+            write!(f, ", checksum: {}", checksum)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for FileInspection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for record in &self.records {
+            writeln!(f, "{}", record)?;
+        }
+
+        if let Some((offset, ref err)) = self.error {
+            writeln!(f, "failed to decode record at offset {}: {}", offset, err)?;
+        }
+        if let Some(checksum) = self.checksum {
+            writeln!(f, "hint file checksum: {}", checksum)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
 //! `Cask` is a key-value store backed by a log-structured hash table which is inspired by
 //! [bitcask](https://github.com/basho/bitcask/).
 //!
-//! Keys are indexed in a `HashMap` and values are written to an append-only log. To avoid the log
-//! from getting filled with stale data (updated/deleted entries) a compaction process runs in the
-//! background which rewrites the log by removing dead entries and merging log files.
+//! Keys are indexed in a `HashMap` (or a `BTreeMap`, if ordered iteration is needed) and values
+//! are written to an append-only log. To avoid the log from getting filled with stale data
+//! (updated/deleted entries) a compaction process runs in the background which rewrites the log by
+//! removing dead entries and merging log files.
 //!
 //! # Examples
 //!
@@ -33,18 +34,64 @@
 extern crate lazy_static;
 #[macro_use]
 extern crate log as logrs;
+extern crate base64;
+#[cfg(feature = "serde")]
+extern crate bincode;
 extern crate byteorder;
 extern crate fs2;
 extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(any(feature = "serde", feature = "export"))]
+extern crate serde_json;
 extern crate time;
+#[cfg(feature = "async")]
+extern crate tokio;
 extern crate twox_hash;
 
+#[cfg(feature = "async")]
+mod async_cask;
+mod backup;
+mod batch;
+mod bucket;
 mod cask;
+mod changes;
 mod data;
 pub mod errors;
+#[cfg(feature = "export")]
+mod export;
 mod file_pool;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod inspect;
 mod log;
+#[cfg(feature = "memcached")]
+pub mod memcached;
+pub mod replication;
+#[cfg(feature = "server")]
+pub mod server;
+mod snapshot;
 mod stats;
+#[cfg(feature = "serde")]
+pub mod typed;
 mod util;
+mod verify;
 
-pub use cask::{Cask, CaskOptions, SyncStrategy};
+#[cfg(feature = "async")]
+pub use async_cask::{AsyncCask, Pending};
+pub use backup::BackupManifest;
+pub use batch::WriteBatch;
+pub use cask::{Bucket, Cask, CaskOptions, CorruptionPolicy, Iter, Range, Snapshot, SyncStrategy};
+pub use changes::{Change, Changes, Subscription};
+pub use data::{BucketId, SequenceNumber};
+#[cfg(feature = "export")]
+pub use export::ExportFormat;
+pub use log::ValueReader;
+pub use replication::{LogRecord, ReplicationStream};
+pub use stats::{BucketStats, CaskStats, FileStats};
+#[cfg(feature = "serde")]
+pub use typed::TypedCask;
+pub use verify::{CorruptEntry, HintFileProblem, IndexProblem, VerifyReport};
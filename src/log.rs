@@ -1,7 +1,10 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{Cursor, SeekFrom, Take};
+use std::io;
+use std::io::{BufReader, BufWriter, Cursor, SeekFrom, Take};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::result::Result::Ok;
@@ -12,20 +15,32 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use fs2::FileExt;
 use regex::Regex;
 
-use data::{Entry, Hint};
+use bucket::BUCKETS_FILE_NAME;
+use data::{
+    Entry, EntryHeader, Hint, ENTRY_STATIC_SIZE, FORMAT_VERSION, LEGACY_ENTRY_STATIC_SIZE,
+    LEGACY_FORMAT_VERSION, MAX_VALUE_SIZE,
+};
 use errors::{Error, Result};
 use file_pool::FilePool;
 use util::{Sequence, XxHash32, get_file_handle, human_readable_byte_count, xxhash32};
 
-const DATA_FILE_EXTENSION: &'static str = "cask.data";
-const HINT_FILE_EXTENSION: &'static str = "cask.hint";
+pub const DATA_FILE_EXTENSION: &'static str = "cask.data";
+pub const HINT_FILE_EXTENSION: &'static str = "cask.hint";
 const LOCK_FILE_NAME: &'static str = "cask.lock";
+const FORMAT_FILE_NAME: &'static str = "cask.format";
+const FORMAT_HEADER: &'static str = "cask-format";
+const CORRUPT_DIR_NAME: &'static str = "corrupt";
+const UPGRADE_DIR_NAME: &'static str = "upgrade";
+/// Size of the chunks of a data file scanned for entry headers when resyncing.
+const RESYNC_WINDOW_SIZE: u64 = 64 * 1024;
 
 pub struct Log {
     pub path: PathBuf,
     max_file_size: usize,
-    lock_file: File,
+    read_only: bool,
+    lock_file: Option<File>,
     files: Vec<u32>,
+    valid_lens: HashMap<u32, u64>,
     file_id_seq: Arc<Sequence>,
     file_pool: Mutex<FilePool>,
     log_writer: LogWriter,
@@ -39,11 +54,12 @@ impl Log {
         sync: bool,
         max_file_size: usize,
         file_pool_size: usize,
+        read_only: bool,
     ) -> Result<Log> {
         let path_str = path;
         let path = PathBuf::from(path);
 
-        if create {
+        if create && !read_only {
             if path.exists() && !path.is_dir() {
                 return Err(Error::InvalidPath(path_str.to_string()));
             } else if !path.exists() {
@@ -55,11 +71,18 @@ impl Log {
             }
         }
 
-        let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
-        lock_file.try_lock_exclusive()?;
+        let lock_file = if read_only {
+            lock_shared(&path)?
+        } else {
+            let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
+            lock_file.try_lock_exclusive()?;
+            Some(lock_file)
+        };
 
         let files = find_data_files(&path)?;
 
+        check_format(&path, !files.is_empty(), read_only)?;
+
         let current_file_id = if files.is_empty() {
             0
         } else {
@@ -75,8 +98,10 @@ impl Log {
         Ok(Log {
             path: path,
             max_file_size: max_file_size,
+            read_only: read_only,
             lock_file: lock_file,
             files: files,
+            valid_lens: HashMap::new(),
             file_id_seq: file_id_seq,
             file_pool: Mutex::new(FilePool::new(file_pool_size)),
             log_writer: log_writer,
@@ -101,6 +126,14 @@ impl Log {
         res
     }
 
+    pub fn hint_file_size(&self, file_id: u32) -> Result<u64> {
+        match fs::metadata(get_hint_file_path(&self.path, file_id)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+
     pub fn files(&self) -> Vec<u32> {
         self.files.clone()
     }
@@ -109,11 +142,17 @@ impl Log {
         let data_file_path = get_data_file_path(&self.path, file_id);
         info!("Loading data file: {:?}", data_file_path);
         let data_file = get_file_handle(&data_file_path, false)?;
-        let data_file_size = data_file.metadata()?.len();
+        let data_file_size = match self.valid_lens.get(&file_id) {
+            Some(&valid_len) => valid_len,
+            None => data_file.metadata()?.len(),
+        };
 
         Ok(Entries {
+            file_id: file_id,
             data_file: data_file.take(data_file_size),
             data_file_pos: 0,
+            data_file_size: data_file_size,
+            last_entry_pos: 0,
             phantom: PhantomData,
         })
     }
@@ -134,16 +173,206 @@ impl Log {
         })
     }
 
-    pub fn recreate_hints<'a>(&mut self, file_id: u32) -> Result<RecreateHints<'a>> {
+    pub fn has_hint_file(&self, file_id: u32) -> bool {
+        get_hint_file_path(&self.path, file_id).is_file()
+    }
+
+    /// Returns all files in the log directory which aren't data or hint files for any of the
+    /// given `file_ids`, the lock file, the format file, the bucket catalog or the directory of
+    /// quarantined files.
+    pub fn orphan_files(&self, file_ids: &[u32]) -> Result<Vec<PathBuf>> {
+        let mut known = HashSet::new();
+        known.insert(self.path.join(LOCK_FILE_NAME));
+        known.insert(self.path.join(FORMAT_FILE_NAME));
+        known.insert(self.path.join(CORRUPT_DIR_NAME));
+        known.insert(self.path.join(BUCKETS_FILE_NAME));
+
+        for &file_id in file_ids {
+            known.insert(get_data_file_path(&self.path, file_id));
+            known.insert(get_hint_file_path(&self.path, file_id));
+        }
+
+        let mut orphan_files = Vec::new();
+
+        for file in fs::read_dir(&self.path)? {
+            let path = file?.path();
+            if !known.contains(&path) {
+                orphan_files.push(path);
+            }
+        }
+
+        orphan_files.sort();
+
+        Ok(orphan_files)
+    }
+
+    /// Re-creates the hint file for `file_id` from its entries. If `skip_corrupt` is set, corrupt
+    /// entries are skipped by scanning forward to the next valid entry, otherwise the iterator
+    /// yields an error and the hint file is left invalid. In read-only mode the hints are only
+    /// read from the entries and no hint file is written.
+    pub fn recreate_hints<'a>(
+        &mut self,
+        file_id: u32,
+        skip_corrupt: bool,
+    ) -> Result<RecreateHints<'a>> {
         let hint_file_path = get_hint_file_path(&self.path, file_id);
         warn!("Re-creating hint file: {:?}", hint_file_path);
 
-        let hint_writer = HintWriter::new(&self.path, file_id)?;
+        let hint_writer = if self.read_only {
+            None
+        } else {
+            Some(HintWriter::new(&self.path, file_id)?)
+        };
         let entries = self.entries(file_id)?;
 
         Ok(RecreateHints {
             hint_writer: hint_writer,
             entries: entries,
+            batch: VecDeque::new(),
+            skip_corrupt: skip_corrupt,
+            failed: false,
+        })
+    }
+
+    /// Moves the data file `file_id` into the `corrupt` directory, removing it from the log. In
+    /// read-only mode the file is only removed from the log.
+    pub fn quarantine(&mut self, file_id: u32) -> Result<()> {
+        if self.read_only {
+            warn!("Ignoring corrupt data file {}", file_id);
+
+            if let Ok(idx) = self.files.binary_search(&file_id) {
+                self.files.remove(idx);
+            }
+
+            return Ok(());
+        }
+
+        let corrupt_path = self.path.join(CORRUPT_DIR_NAME);
+        if !corrupt_path.exists() {
+            fs::create_dir(&corrupt_path)?;
+        }
+
+        let data_file_path = get_data_file_path(&self.path, file_id);
+        let quarantined_path = get_data_file_path(&corrupt_path, file_id);
+
+        warn!(
+            "Quarantining corrupt data file {:?} to {:?}",
+            data_file_path, quarantined_path
+        );
+
+        fs::rename(&data_file_path, &quarantined_path)?;
+        let _ = fs::remove_file(get_hint_file_path(&self.path, file_id));
+
+        if let Ok(idx) = self.files.binary_search(&file_id) {
+            self.files.remove(idx);
+        }
+
+        Ok(())
+    }
+
+    /// Detects a partially written entry (or batch) at the end of a data file, e.g. due to a crash
+    /// during an append, and truncates the file back to the last valid entry. Returns the number of
+    /// bytes that were discarded. In read-only mode the file is left untouched and the torn tail
+    /// is just ignored when reading entries.
+    pub fn truncate_torn_tail(&mut self, file_id: u32) -> Result<u64> {
+        let data_file_path = get_data_file_path(&self.path, file_id);
+
+        let mut entries = self.entries(file_id)?;
+        let mut valid_len = 0;
+        let mut torn = None;
+
+        while let Some((entry_pos, entry)) = entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    torn = Some((entry_pos, err.to_string()));
+                    break;
+                }
+            };
+
+            if let Some(batch_size) = entry.batch_size() {
+                let mut batch_len = entry.size();
+
+                for _ in 0..batch_size {
+                    match entries.next() {
+                        Some((_, Ok(ref entry))) if !entry.batch => batch_len += entry.size(),
+                        Some((_, Ok(..))) | None => {
+                            torn = Some((entry_pos, "incomplete batch".to_string()));
+                            break;
+                        }
+                        Some((_, Err(err))) => {
+                            torn = Some((entry_pos, format!("incomplete batch ({})", err)));
+                            break;
+                        }
+                    }
+                }
+
+                if torn.is_some() {
+                    break;
+                }
+
+                valid_len = entry_pos + batch_len;
+            } else {
+                valid_len = entry_pos + entry.size();
+            }
+        }
+
+        let (torn_pos, reason) = match torn {
+            Some(torn) => torn,
+            None => return Ok(0),
+        };
+
+        // only the tail of the file can be torn, if there's any data after the invalid entry that
+        // isn't zeroed out (file systems may extend a file before its data is written) then this
+        // is corruption rather than an interrupted append and it is left untouched.
+        if entries.data_file.limit() > 0 && !is_zeroed(&mut entries.data_file)? {
+            return Ok(0);
+        }
+
+        let data_file_size = entries.data_file_size;
+
+        if self.read_only {
+            warn!(
+                "Found torn entry at position {} of data file {:?} ({}), ignoring {} from \
+                 position {} to {}",
+                torn_pos,
+                data_file_path,
+                reason,
+                human_readable_byte_count((data_file_size - valid_len) as usize, true),
+                valid_len,
+                data_file_size
+            );
+
+            self.valid_lens.insert(file_id, valid_len);
+            return Ok(data_file_size - valid_len);
+        }
+
+        let data_file = OpenOptions::new().write(true).open(&data_file_path)?;
+
+        warn!(
+            "Found torn entry at position {} of data file {:?} ({}), discarding {} from \
+             position {} to {}",
+            torn_pos,
+            data_file_path,
+            reason,
+            human_readable_byte_count((data_file_size - valid_len) as usize, true),
+            valid_len,
+            data_file_size
+        );
+
+        data_file.set_len(valid_len)?;
+        data_file.sync_all()?;
+
+        Ok(data_file_size - valid_len)
+    }
+
+    pub fn reader(&self, file_id: u32) -> Result<EntryReader> {
+        let data_file = get_file_handle(&get_data_file_path(&self.path, file_id), false)?;
+
+        Ok(EntryReader {
+            file_id: file_id,
+            data_file: BufReader::new(data_file),
+            data_file_pos: 0,
         })
     }
 
@@ -158,30 +387,89 @@ impl Log {
             })?;
 
         data_file.seek(SeekFrom::Start(entry_pos))?;
-        let res = Entry::from_read(&mut data_file);
+        let res = Entry::from_read(&mut data_file).map_err(|err| corrupt(file_id, entry_pos, err));
 
         self.file_pool.lock().unwrap().put(file_id, data_file);
 
         res
     }
 
+    /// Returns a reader over the value of the entry at `entry_pos` of the data file `file_id`, or
+    /// `None` if the entry is a tombstone.
+    pub fn value_reader(&self, file_id: u32, entry_pos: u64) -> Result<Option<ValueReader>> {
+        let data_file = get_file_handle(&get_data_file_path(&self.path, file_id), false)?;
+        let mut data_file = BufReader::new(data_file);
+        data_file.seek(SeekFrom::Start(entry_pos))?;
+
+        let mut header = vec![0u8; ENTRY_STATIC_SIZE];
+        data_file.read_exact(&mut header)?;
+        let entry_header = EntryHeader::from_bytes(&header)?;
+
+        if entry_header.deleted || entry_header.batch {
+            return Ok(None);
+        }
+
+        let mut key = vec![0u8; entry_header.key_size as usize];
+        data_file.read_exact(&mut key)?;
+
+        let mut hasher = XxHash32::new();
+        hasher.update(&header[4..]);
+        hasher.update(&key);
+
+        let checksum = Cursor::new(&header).read_u32::<LittleEndian>()?;
+
+        Ok(Some(ValueReader {
+            data_file: data_file.take(u64::from(entry_header.value_size)),
+            len: u64::from(entry_header.value_size),
+            hasher: hasher,
+            checksum: checksum,
+        }))
+    }
+
     pub fn append_entry<'a>(&mut self, entry: &Entry<'a>) -> Result<(u32, u64)> {
         Ok(match self.log_writer.write(entry)? {
             LogWrite::NewFile(file_id) => {
-                if let Some(active_file_id) = self.active_file_id {
-                    self.add_file(active_file_id);
-                }
-                self.active_file_id = Some(file_id);
-                info!(
-                    "New active data file {:?}",
-                    self.log_writer.entry_writer()?.data_file_path
-                );
+                self.set_active_file(file_id)?;
                 (file_id, 0)
             }
             LogWrite::Ok(entry_pos) => (self.active_file_id.unwrap(), entry_pos),
         })
     }
 
+    pub fn append_batch<'a>(&mut self, entries: &[Entry<'a>]) -> Result<(u32, Vec<u64>)> {
+        let (log_write, entry_positions) = self.log_writer.write_batch(entries)?;
+
+        Ok(match log_write {
+            LogWrite::NewFile(file_id) => {
+                self.set_active_file(file_id)?;
+                (file_id, entry_positions)
+            }
+            LogWrite::Ok(..) => (self.active_file_id.unwrap(), entry_positions),
+        })
+    }
+
+    /// Closes the active data file, finalizing its hint file, so that the next write goes to a new
+    /// data file.
+    pub fn rotate(&mut self) {
+        self.log_writer.close();
+
+        if let Some(active_file_id) = self.active_file_id.take() {
+            self.add_file(active_file_id);
+        }
+    }
+
+    fn set_active_file(&mut self, file_id: u32) -> Result<()> {
+        if let Some(active_file_id) = self.active_file_id {
+            self.add_file(active_file_id);
+        }
+        self.active_file_id = Some(file_id);
+        info!(
+            "New active data file {:?}",
+            self.log_writer.entry_writer()?.data_file_path
+        );
+        Ok(())
+    }
+
     pub fn writer(&self) -> LogWriter {
         LogWriter::new(
             &self.path,
@@ -224,7 +512,9 @@ impl Log {
 
 impl Drop for Log {
     fn drop(&mut self) {
-        let _ = self.lock_file.unlock();
+        if let Some(ref lock_file) = self.lock_file {
+            let _ = lock_file.unlock();
+        }
     }
 }
 
@@ -279,9 +569,15 @@ impl LogWriter {
         Ok(file_id)
     }
 
-    pub fn write(&mut self, entry: &Entry) -> Result<LogWrite> {
+    fn close(&mut self) {
+        if let Some(entry_writer) = self.entry_writer.take() {
+            info!("Closed data file {:?}", entry_writer.data_file_path);
+        }
+    }
+
+    fn rotate(&mut self, size: u64) -> Result<Option<u32>> {
         Ok(if self.entry_writer.is_none() || // FIXME: clean up
-              self.entry_writer.as_ref().unwrap().data_file_pos + entry.size() >
+              self.entry_writer.as_ref().unwrap().data_file_pos + size >
               self.max_file_size as u64
         {
 
@@ -293,15 +589,43 @@ impl LogWriter {
                 );
             }
 
-            let file_id = self.new_entry_writer()?;
-            let entry_pos = self.entry_writer.as_mut().unwrap().write(entry)?;
+            Some(self.new_entry_writer()?)
+        } else {
+            None
+        })
+    }
 
-            assert_eq!(entry_pos, 0);
+    pub fn write(&mut self, entry: &Entry) -> Result<LogWrite> {
+        let new_file_id = self.rotate(entry.size())?;
+        let entry_pos = self.entry_writer.as_mut().unwrap().write(entry)?;
 
-            LogWrite::NewFile(file_id)
-        } else {
-            let entry_pos = self.entry_writer.as_mut().unwrap().write(entry)?;
-            LogWrite::Ok(entry_pos)
+        Ok(match new_file_id {
+            Some(file_id) => {
+                assert_eq!(entry_pos, 0);
+                LogWrite::NewFile(file_id)
+            }
+            None => LogWrite::Ok(entry_pos),
+        })
+    }
+
+    /// Writes all `entries` to the same data file, preceded by a batch marker. Returns the
+    /// positions of the written entries, the marker itself is not indexed.
+    pub fn write_batch(&mut self, entries: &[Entry]) -> Result<(LogWrite, Vec<u64>)> {
+        let marker = Entry::batch(entries[0].sequence, entries.len() as u32);
+        let size = entries.iter().fold(marker.size(), |acc, e| acc + e.size());
+
+        let new_file_id = self.rotate(size)?;
+        let (marker_pos, entry_positions) = self.entry_writer
+            .as_mut()
+            .unwrap()
+            .write_batch(&marker, entries)?;
+
+        Ok(match new_file_id {
+            Some(file_id) => {
+                assert_eq!(marker_pos, 0);
+                (LogWrite::NewFile(file_id), entry_positions)
+            }
+            None => (LogWrite::Ok(marker_pos), entry_positions),
         })
     }
 
@@ -356,6 +680,41 @@ impl EntryWriter {
 
         Ok(entry_pos)
     }
+
+    pub fn write_batch<'a>(
+        &mut self,
+        marker: &Entry<'a>,
+        entries: &[Entry<'a>],
+    ) -> Result<(u64, Vec<u64>)> {
+        let marker_pos = self.data_file_pos;
+
+        // the whole batch is written at once so that a failure can only leave a partially written
+        // batch at the end of the data file, where it will be discarded on recovery.
+        let mut buf = Vec::new();
+        marker.write_bytes(&mut buf)?;
+
+        let mut entry_positions = Vec::with_capacity(entries.len());
+        let mut entry_pos = marker_pos + marker.size();
+        for entry in entries {
+            entry.write_bytes(&mut buf)?;
+            entry_positions.push(entry_pos);
+            entry_pos += entry.size();
+        }
+
+        self.data_file.write_all(&buf)?;
+
+        for (entry, &entry_pos) in entries.iter().zip(entry_positions.iter()) {
+            self.hint_writer.write(&Hint::new(entry, entry_pos))?;
+        }
+
+        if self.sync {
+            self.data_file.sync_data()?;
+        }
+
+        self.data_file_pos = entry_pos;
+
+        Ok((marker_pos, entry_positions))
+    }
 }
 
 impl Drop for EntryWriter {
@@ -367,6 +726,7 @@ impl Drop for EntryWriter {
 struct HintWriter {
     hint_file: File,
     hint_file_hasher: XxHash32,
+    discarded: bool,
 }
 
 impl HintWriter {
@@ -376,6 +736,7 @@ impl HintWriter {
         Ok(HintWriter {
             hint_file: hint_file,
             hint_file_hasher: XxHash32::new(),
+            discarded: false,
         })
     }
 
@@ -384,26 +745,220 @@ impl HintWriter {
         hint.write_bytes(&mut self.hint_file_hasher)?;
         Ok(())
     }
+
+    /// Leaves the hint file without a checksum so that it is considered invalid.
+    pub fn discard(&mut self) {
+        self.discarded = true;
+    }
 }
 
 impl Drop for HintWriter {
     fn drop(&mut self) {
-        let _ = self.hint_file.write_u32::<LittleEndian>(
-            self.hint_file_hasher.get(),
-        );
+        if !self.discarded {
+            let _ = self.hint_file.write_u32::<LittleEndian>(
+                self.hint_file_hasher.get(),
+            );
+        }
+    }
+}
+
+/// Reads entries from a single data file, buffering reads so that entries at increasing positions
+/// are read sequentially.
+pub struct EntryReader {
+    file_id: u32,
+    data_file: BufReader<File>,
+    data_file_pos: u64,
+}
+
+impl EntryReader {
+    pub fn read_entry<'a>(&mut self, entry_pos: u64) -> Result<Entry<'a>> {
+        self.seek(entry_pos)?;
+
+        let entry = Entry::from_read(&mut self.data_file);
+
+        match entry {
+            Ok(ref entry) => self.data_file_pos += entry.size(),
+            Err(_) => {
+                // the position within the file is unknown after a failed read
+                self.data_file_pos = self.data_file.stream_position()?;
+            }
+        }
+
+        entry.map_err(|err| corrupt(self.file_id, entry_pos, err))
+    }
+
+    /// Returns the batch marker right before `entry_pos`, if there's one. Only the bytes that a
+    /// batch marker takes are read.
+    pub fn read_batch_marker<'a>(&mut self, entry_pos: u64) -> Result<Option<Entry<'a>>> {
+        let marker_size = Entry::batch(0, 0).size();
+        if entry_pos < marker_size {
+            return Ok(None);
+        }
+
+        self.seek(entry_pos - marker_size)?;
+
+        let mut bytes = vec![0u8; marker_size as usize];
+        if let Err(err) = self.data_file.read_exact(&mut bytes) {
+            self.data_file_pos = self.data_file.stream_position()?;
+            return Err(Error::Io(err));
+        }
+        self.data_file_pos = entry_pos;
+
+        match Entry::from_read(&mut &bytes[..]) {
+            Ok(marker) if marker.batch => Ok(Some(marker)),
+            _ => Ok(None),
+        }
+    }
+
+    fn seek(&mut self, pos: u64) -> Result<()> {
+        if pos != self.data_file_pos {
+            self.data_file
+                .seek_relative(pos as i64 - self.data_file_pos as i64)?;
+            self.data_file_pos = pos;
+        }
+
+        Ok(())
+    }
+}
+
+/// A reader over a value stored in a data file, see `Cask::get_reader`.
+///
+/// The checksum of the entry is verified once the value has been read to the end, reading fails
+/// with an `io::ErrorKind::InvalidData` error then if it doesn't match.
+pub struct ValueReader {
+    data_file: Take<BufReader<File>>,
+    len: u64,
+    hasher: XxHash32,
+    checksum: u32,
+}
+
+impl ValueReader {
+    /// Returns the size of the value.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data_file.read(buf)?;
+        self.hasher.update(&buf[..read]);
+
+        if read == 0 && !buf.is_empty() {
+            if self.data_file.limit() > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "failed to read entry value",
+                ));
+            }
+
+            let hash = self.hasher.get();
+            if hash != self.checksum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    Error::InvalidChecksum {
+                        expected: self.checksum,
+                        found: hash,
+                    }.to_string(),
+                ));
+            }
+        }
+
+        Ok(read)
     }
 }
 
 pub struct Entries<'a> {
+    file_id: u32,
     data_file: Take<File>,
     data_file_pos: u64,
+    data_file_size: u64,
+    last_entry_pos: u64,
     phantom: PhantomData<&'a ()>,
 }
 
+impl<'a> Entries<'a> {
+    fn is_exhausted(&self) -> bool {
+        self.data_file.limit() == 0
+    }
+
+    /// Limits the iteration to the first `len` bytes of the data file.
+    pub fn set_len(&mut self, len: u64) -> Result<()> {
+        self.data_file_size = cmp::min(len, self.data_file_size);
+        let data_file_pos = self.data_file_pos;
+        self.seek(data_file_pos)
+    }
+
+    /// Scans forward from the position following the last entry that was read for the next
+    /// position holding a valid entry, from where iteration will resume. Returns the number of
+    /// bytes that were skipped.
+    ///
+    /// Candidate positions are first checked against a window of the file read in chunks, only
+    /// those holding a header whose key and value fit in the file are read in full.
+    pub fn resync(&mut self) -> Result<u64> {
+        let header_size = ENTRY_STATIC_SIZE as u64;
+        let mut entry_pos = self.last_entry_pos + 1;
+        let mut window = Vec::new();
+        let mut window_pos = entry_pos;
+
+        while entry_pos + header_size <= self.data_file_size {
+            if entry_pos + header_size > window_pos + window.len() as u64 {
+                self.seek(entry_pos)?;
+                window.clear();
+                (&mut self.data_file)
+                    .take(RESYNC_WINDOW_SIZE)
+                    .read_to_end(&mut window)?;
+                window_pos = entry_pos;
+
+                if window.len() < ENTRY_STATIC_SIZE {
+                    break;
+                }
+            }
+
+            let offset = (entry_pos - window_pos) as usize;
+            let header = EntryHeader::from_bytes(&window[offset..offset + ENTRY_STATIC_SIZE])?;
+            let entry_end = entry_pos
+                + header_size
+                + u64::from(header.key_size)
+                + u64::from(header.value_size);
+
+            if header.value_size <= MAX_VALUE_SIZE && entry_end <= self.data_file_size {
+                self.seek(entry_pos)?;
+
+                if Entry::from_read(&mut self.data_file).is_ok() {
+                    break;
+                }
+            }
+
+            entry_pos += 1;
+        }
+
+        // no entry can start so close to the end of the file
+        if entry_pos + header_size > self.data_file_size || window.len() < ENTRY_STATIC_SIZE {
+            entry_pos = self.data_file_size;
+        }
+
+        self.seek(entry_pos)?;
+
+        Ok(entry_pos - self.last_entry_pos)
+    }
+
+    fn seek(&mut self, pos: u64) -> Result<()> {
+        self.data_file.get_mut().seek(SeekFrom::Start(pos))?;
+        self.data_file.set_limit(self.data_file_size - pos);
+        self.data_file_pos = pos;
+        Ok(())
+    }
+}
+
 impl<'a> Iterator for Entries<'a> {
     type Item = (u64, Result<Entry<'a>>);
 
-    // TODO: candidate for corruption handling
     fn next(&mut self) -> Option<(u64, Result<Entry<'a>>)> {
         let limit = self.data_file.limit();
         if limit == 0 {
@@ -415,13 +970,14 @@ impl<'a> Iterator for Entries<'a> {
             let read = limit - self.data_file.limit();
 
             self.data_file_pos += read;
+            self.last_entry_pos = entry_pos;
 
             let entry = match entry {
                 Ok(entry) => {
                     assert_eq!(entry.size(), read);
                     Ok(entry)
                 }
-                e => e,
+                Err(err) => Err(corrupt(self.file_id, entry_pos, err)),
             };
 
             Some((entry_pos, entry))
@@ -447,18 +1003,103 @@ impl<'a> Iterator for Hints<'a> {
 }
 
 pub struct RecreateHints<'a> {
-    hint_writer: HintWriter,
+    hint_writer: Option<HintWriter>,
     entries: Entries<'a>,
+    batch: VecDeque<Hint<'a>>,
+    skip_corrupt: bool,
+    failed: bool,
+}
+
+impl<'a> RecreateHints<'a> {
+    fn read_batch(&mut self, batch_pos: u64, batch_size: u32) -> Result<()> {
+        for _ in 0..batch_size {
+            let err = match self.entries.next() {
+                Some((entry_pos, Ok(entry))) => {
+                    if !entry.batch {
+                        self.batch.push_back(Hint::from(entry, entry_pos));
+                        continue;
+                    }
+
+                    Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "batch marker found within a batch",
+                    ))
+                }
+                Some((_, Err(err))) => err,
+                None => Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "incomplete batch",
+                )),
+            };
+
+            self.batch.clear();
+            return Err(corrupt(self.entries.file_id, batch_pos, err));
+        }
+
+        Ok(())
+    }
+
+    fn skip_corrupt(&mut self, err: &Error) -> Result<()> {
+        let skipped = self.entries.resync()?;
+        warn!(
+            "Skipping {} of corrupt data: {}",
+            human_readable_byte_count(skipped as usize, true),
+            err
+        );
+        Ok(())
+    }
+
+    fn fail(&mut self, err: Error) -> Option<Result<Hint<'a>>> {
+        self.failed = true;
+        if let Some(ref mut hint_writer) = self.hint_writer {
+            hint_writer.discard();
+        }
+        Some(Err(err))
+    }
 }
 
 impl<'a> Iterator for RecreateHints<'a> {
     type Item = Result<Hint<'a>>;
 
     fn next(&mut self) -> Option<Result<Hint<'a>>> {
-        self.entries.next().map(|e| {
-            let (entry_pos, entry) = e;
-            let hint = Hint::from(entry?, entry_pos);
-            self.hint_writer.write(&hint)?;
+        if self.failed {
+            return None;
+        }
+
+        while self.batch.is_empty() {
+            let err = match self.entries.next() {
+                Some((entry_pos, Ok(entry))) => match entry.batch_size() {
+                    Some(batch_size) => match self.read_batch(entry_pos, batch_size) {
+                        Ok(()) => continue,
+                        Err(ref err) if self.entries.is_exhausted() => {
+                            // a crash during the append of the batch
+                            warn!("Discarding incomplete batch: {}", err);
+                            return None;
+                        }
+                        Err(err) => err,
+                    },
+                    None => {
+                        self.batch.push_back(Hint::from(entry, entry_pos));
+                        continue;
+                    }
+                },
+                Some((_, Err(err))) => err,
+                None => return None,
+            };
+
+            if !self.skip_corrupt || !err.is_corruption() {
+                return self.fail(err);
+            }
+
+            if let Err(err) = self.skip_corrupt(&err) {
+                return self.fail(err);
+            }
+        }
+
+        self.batch.pop_front().map(|hint| {
+            if let Some(ref mut hint_writer) = self.hint_writer {
+                hint_writer.write(&hint)?;
+            }
             Ok(hint)
         })
     }
@@ -470,6 +1111,218 @@ impl<'a> Drop for RecreateHints<'a> {
     }
 }
 
+/// Copies the data files `file_ids` of the `Cask` at `path`, along with their hint files, into
+/// `dest`. If `link` is set data files are hard-linked unless `dest` is on a different file
+/// system, hint files are always copied since they may be re-created in place.
+pub fn copy_files(path: &Path, dest: &Path, file_ids: &[u32], link: bool) -> Result<()> {
+    let format_file_path = path.join(FORMAT_FILE_NAME);
+    if format_file_path.is_file() {
+        fs::copy(&format_file_path, dest.join(FORMAT_FILE_NAME))?;
+    }
+
+    for &file_id in file_ids {
+        let data_file_path = get_data_file_path(path, file_id);
+        let dest_data_file_path = get_data_file_path(dest, file_id);
+
+        if !link || fs::hard_link(&data_file_path, &dest_data_file_path).is_err() {
+            fs::copy(&data_file_path, &dest_data_file_path)?;
+        }
+
+        let hint_file_path = get_hint_file_path(path, file_id);
+        if hint_file_path.is_file() {
+            fs::copy(&hint_file_path, get_hint_file_path(dest, file_id))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the data files of the `Cask` dir `path` are in the current format, which is
+/// recorded when the first data file is about to be written. Data files in the legacy format are
+/// upgraded unless `read_only` is set, see `upgrade`.
+fn check_format(path: &Path, has_data_files: bool, read_only: bool) -> Result<()> {
+    if !read_only {
+        finish_upgrade(path)?;
+    }
+
+    let format_file_path = path.join(FORMAT_FILE_NAME);
+
+    let version = match fs::read_to_string(&format_file_path) {
+        Ok(contents) => {
+            let mut fields = contents.split_whitespace();
+
+            match (fields.next(), fields.next().and_then(|v| v.parse().ok())) {
+                (Some(FORMAT_HEADER), Some(version)) => version,
+                _ if !has_data_files => FORMAT_VERSION,
+                _ => {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid format file",
+                    )))
+                }
+            }
+        }
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            if has_data_files {
+                LEGACY_FORMAT_VERSION
+            } else {
+                FORMAT_VERSION
+            }
+        }
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    if version == LEGACY_FORMAT_VERSION && !read_only {
+        return upgrade(path);
+    }
+
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedFormat(version));
+    }
+
+    if !has_data_files && !read_only {
+        write_format_file(path)?;
+    }
+
+    Ok(())
+}
+
+fn write_format_file(path: &Path) -> Result<()> {
+    let format_file_path = path.join(FORMAT_FILE_NAME);
+
+    // written through a temporary file, so that a crash can't leave it half-written
+    let tmp_path = format_file_path.with_extension("format.tmp");
+    {
+        let mut format_file = File::create(&tmp_path)?;
+        writeln!(format_file, "{} {}", FORMAT_HEADER, FORMAT_VERSION)?;
+        format_file.sync_all()?;
+    }
+    fs::rename(&tmp_path, &format_file_path)?;
+
+    Ok(())
+}
+
+/// Rewrites the data files of the `Cask` dir `path` from the legacy format to the current one, in
+/// place and keeping their ids. Entries are copied as is, with no expiry and in the default
+/// bucket, and the legacy hint files are removed so that they get re-created.
+///
+/// The rewritten files are staged in the `upgrade` dir along with a format file, which marks the
+/// upgrade as complete, and only then moved over the legacy ones. A failure or a crash before that
+/// point leaves the legacy files untouched, and a crash afterwards is recovered from by
+/// `finish_upgrade`.
+fn upgrade(path: &Path) -> Result<()> {
+    let upgrade_path = path.join(UPGRADE_DIR_NAME);
+    fs::create_dir(&upgrade_path)?;
+
+    if let Err(err) = rewrite_legacy_files(path, &upgrade_path) {
+        fs::remove_dir_all(&upgrade_path)?;
+        return Err(err);
+    }
+
+    write_format_file(&upgrade_path)?;
+    finish_upgrade(path)
+}
+
+fn rewrite_legacy_files(path: &Path, upgrade_path: &Path) -> Result<()> {
+    let files = find_data_files(path)?;
+    info!("Upgrading {} data files to format version {}", files.len(), FORMAT_VERSION);
+
+    for &file_id in &files {
+        let data_file = File::open(get_data_file_path(path, file_id))?;
+        let data_file_len = data_file.metadata()?.len();
+        let mut reader = BufReader::new(data_file);
+        let mut writer = BufWriter::new(File::create(get_data_file_path(upgrade_path, file_id))?);
+
+        let mut entry_pos = 0;
+        while entry_pos < data_file_len {
+            let entry = Entry::from_read_legacy(&mut reader)
+                .map_err(|err| corrupt(file_id, entry_pos, err))?;
+            entry.write_bytes(&mut writer)?;
+
+            entry_pos +=
+                (LEGACY_ENTRY_STATIC_SIZE + entry.key.len() + entry.value.len()) as u64;
+        }
+
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Completes an upgrade of the `Cask` dir `path` which was interrupted after its data files were
+/// rewritten, or discards one which was interrupted before. Does nothing if there's no upgrade in
+/// progress.
+fn finish_upgrade(path: &Path) -> Result<()> {
+    let upgrade_path = path.join(UPGRADE_DIR_NAME);
+
+    if !upgrade_path.is_dir() {
+        return Ok(());
+    }
+
+    if upgrade_path.join(FORMAT_FILE_NAME).is_file() {
+        // the legacy hint files are removed first, since they'd be misread alongside the
+        // rewritten data files
+        for file_id in find_data_files(path)? {
+            let hint_file_path = get_hint_file_path(path, file_id);
+            if hint_file_path.is_file() {
+                fs::remove_file(&hint_file_path)?;
+            }
+        }
+
+        for file_id in find_data_files(&upgrade_path)? {
+            fs::rename(
+                get_data_file_path(&upgrade_path, file_id),
+                get_data_file_path(path, file_id),
+            )?;
+        }
+
+        fs::rename(
+            upgrade_path.join(FORMAT_FILE_NAME),
+            path.join(FORMAT_FILE_NAME),
+        )?;
+    }
+
+    fs::remove_dir_all(&upgrade_path)?;
+
+    Ok(())
+}
+
+/// Takes a shared lock on the `Cask` dir at `path`, if it isn't already locked by a writer.
+fn lock_shared(path: &Path) -> Result<Option<File>> {
+    let lock_file = match File::open(path.join(LOCK_FILE_NAME)) {
+        Ok(lock_file) => lock_file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::Io(err)),
+    };
+
+    match FileExt::try_lock_shared(&lock_file) {
+        Ok(()) => Ok(Some(lock_file)),
+        Err(ref err) if err.kind() == fs2::lock_contended_error().kind() => {
+            info!("Cask is locked by a writer, opening without a lock");
+            Ok(None)
+        }
+        Err(err) => Err(Error::Io(err)),
+    }
+}
+
+fn corrupt(file_id: u32, entry_pos: u64, err: Error) -> Error {
+    match err {
+        Error::CorruptEntry { .. } => err,
+        err => {
+            if err.is_corruption() {
+                Error::CorruptEntry {
+                    file_id: file_id,
+                    entry_pos: entry_pos,
+                    cause: Box::new(err),
+                }
+            } else {
+                err
+            }
+        }
+    }
+}
+
 fn get_data_file_path(path: &Path, file_id: u32) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id).with_extension(DATA_FILE_EXTENSION)
@@ -509,6 +1362,20 @@ fn find_data_files(path: &Path) -> Result<Vec<u32>> {
     Ok(data_files)
 }
 
+fn is_zeroed<R: Read>(reader: &mut R) -> Result<bool> {
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let read = reader.read(&mut buf)?;
+
+        if read == 0 {
+            return Ok(true);
+        } else if buf[..read].iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+    }
+}
+
 fn is_valid_hint_file(path: &Path) -> Result<bool> {
     Ok(
         path.is_file() &&
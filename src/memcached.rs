@@ -0,0 +1,562 @@
+//! A server speaking the memcached text protocol, so that a `Cask` can replace memcached without
+//! changing its clients.
+//!
+//! The supported commands are `get`, `gets`, `set`, `add`, `replace`, `cas`, `delete`, `incr`,
+//! `decr`, `stats`, `version` and `quit`, along with the `noreply` option of the commands which
+//! take it.
+//!
+//! Items are stored as the keys of the `Cask` with their data as is, so they can be read and
+//! written through the other interfaces as well. The expiration time of an item is stored as the
+//! expiry of its key, so expired items are reclaimed by compaction. Non-zero flags are stored in
+//! the `memcached-flags` bucket, see `Cask::bucket`, and items last written through another
+//! interface have no flags. The CAS unique of an item is the sequence number of the write which
+//! stored it.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use std::net::TcpListener;
+//!
+//! use cask::CaskOptions;
+//! use cask::memcached;
+//!
+//! let cask = CaskOptions::default().open("cask.db").unwrap();
+//! let listener = TcpListener::bind("127.0.0.1:11211").unwrap();
+//!
+//! memcached::serve(&cask, listener).unwrap();
+//! ```
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::str;
+use std::thread;
+use std::time::Instant;
+use std::vec::Vec;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use cask::{Bucket, Cask};
+use data::SequenceNumber;
+use errors::{Error, Result};
+use util::now_millis;
+
+/// Maximum length of a key, as in memcached.
+const MAX_KEY_SIZE: usize = 250;
+
+/// Maximum size of a value, as memcached's default item size limit.
+const MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+/// Expiration times longer than 30 days are unix timestamps instead of a number of seconds, as in
+/// memcached.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// Name of the bucket holding the flags of the items.
+const FLAGS_BUCKET: &'static str = "memcached-flags";
+
+const FLAGS_SIZE: usize = 4;
+
+/// Maximum size of a command line, as in memcached.
+const MAX_LINE_SIZE: usize = 2048;
+
+/// Serves `cask` to the memcached clients connecting to `listener`. Each client is served by its
+/// own thread.
+pub fn serve(cask: &Cask, listener: TcpListener) -> Result<()> {
+    let started = Instant::now();
+    let flags = cask.bucket(FLAGS_BUCKET)?;
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let cask = cask.clone();
+        let flags = flags.clone();
+
+        thread::spawn(move || {
+            if let Err(err) = handle_client(&cask, &flags, stream, started) {
+                warn!("Closed connection to client: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_client(cask: &Cask, flags: &Bucket, stream: TcpStream, started: Instant) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let line = match read_line(&mut reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // the rest of a line too long can't be told apart from the next command, so the
+                // connection can't be used anymore
+                if err.kind() == io::ErrorKind::InvalidData {
+                    Response::ClientError("line is too long").write(&mut writer)?;
+                    writer.flush()?;
+                }
+                return Err(Error::Io(err));
+            }
+        };
+
+        let mut args: Vec<&[u8]> = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .collect();
+
+        let name = match args.first() {
+            Some(&name) => name,
+            None => {
+                Response::Error.write(&mut writer)?;
+                writer.flush()?;
+                continue;
+            }
+        };
+
+        let noreply = match name {
+            b"set" | b"add" | b"replace" | b"cas" | b"delete" | b"incr" | b"decr" => {
+                args.last() == Some(&&b"noreply"[..])
+            }
+            _ => false,
+        };
+        if noreply {
+            args.pop();
+        }
+
+        let response = match execute(cask, flags, &mut reader, name, &args[1..], started) {
+            Ok(response) => response,
+            Err(err) => Response::ServerError(err.to_string()),
+        };
+
+        if !noreply {
+            response.write(&mut writer)?;
+            writer.flush()?;
+        }
+
+        if name == b"quit" {
+            return Ok(());
+        }
+    }
+}
+
+fn execute<R: BufRead>(
+    cask: &Cask,
+    flags: &Bucket,
+    reader: &mut R,
+    name: &[u8],
+    args: &[&[u8]],
+    started: Instant,
+) -> Result<Response> {
+    match name {
+        b"get" | b"gets" if !args.is_empty() => get(cask, flags, args, name == b"gets"),
+        b"set" | b"add" | b"replace" if args.len() == 4 => {
+            store(cask, flags, reader, name, args)
+        }
+        b"cas" if args.len() == 5 => store(cask, flags, reader, name, args),
+        b"delete" if args.len() == 1 => delete(cask, flags, args[0]),
+        b"incr" | b"decr" if args.len() == 2 => incr_decr(cask, flags, args, name == b"incr"),
+        b"stats" if args.is_empty() => stats(cask, started),
+        b"version" if args.is_empty() => Ok(Response::Line(format!(
+            "VERSION {}",
+            env!("CARGO_PKG_VERSION")
+        ))),
+        b"quit" => Ok(Response::None),
+        b"get" | b"gets" | b"set" | b"add" | b"replace" | b"cas" | b"delete" | b"incr"
+        | b"decr" | b"stats" | b"version" => Ok(Response::ClientError("bad command line format")),
+        _ => Ok(Response::Error),
+    }
+}
+
+/// `get|gets <key>*`
+fn get(cask: &Cask, flags: &Bucket, keys: &[&[u8]], with_cas: bool) -> Result<Response> {
+    let mut items = Vec::with_capacity(keys.len());
+
+    for &key in keys {
+        if let Some(version) = cask.get_with_meta(key, flags)? {
+            items.push(Item {
+                key: key.to_vec(),
+                flags: decode_flags(version.meta),
+                data: version.value,
+                cas: if with_cas { Some(version.sequence) } else { None },
+            });
+        }
+    }
+
+    Ok(Response::Items(items))
+}
+
+/// `set|add|replace <key> <flags> <exptime> <bytes>` and
+/// `cas <key> <flags> <exptime> <bytes> <cas unique>`, followed by the data block.
+fn store<R: BufRead>(
+    cask: &Cask,
+    flags_bucket: &Bucket,
+    reader: &mut R,
+    name: &[u8],
+    args: &[&[u8]],
+) -> Result<Response> {
+    let key = args[0];
+    let flags = parse::<u32>(args[1]);
+    let exptime = parse::<i64>(args[2]);
+    let size = parse::<usize>(args[3]);
+    let cas = args.get(4).map(|cas| parse::<SequenceNumber>(cas));
+
+    let size = match size {
+        Some(size) => size,
+        // the data block can't be told apart from the next command
+        None => return Ok(Response::ClientError("bad command line format")),
+    };
+
+    if size > MAX_VALUE_SIZE {
+        io::copy(&mut reader.take(size as u64 + 2), &mut io::sink())?;
+        return Ok(Response::ServerError("object too large for cache".into()));
+    }
+
+    let mut data = Vec::with_capacity(size + 2);
+    reader.take(size as u64 + 2).read_to_end(&mut data)?;
+    if data.len() != size + 2 || !data.ends_with(b"\r\n") {
+        return Ok(Response::ClientError("bad data chunk"));
+    }
+    data.truncate(size);
+
+    let (flags, exptime, cas) = match (flags, exptime, cas) {
+        (Some(flags), Some(exptime), None) => (flags, exptime, None),
+        (Some(flags), Some(exptime), Some(Some(cas))) => (flags, exptime, Some(cas)),
+        _ => return Ok(Response::ClientError("bad command line format")),
+    };
+
+    if !is_valid_key(key) {
+        return Ok(Response::ClientError("bad command line format"));
+    }
+
+    let expiry = to_expiry(exptime, now_millis());
+
+    let mut response = Response::Stored;
+    cask.update_with_meta(key, flags_bucket, |current| {
+        response = match (name, current) {
+            (b"add", Some(_)) | (b"replace", None) => Response::NotStored,
+            (b"cas", None) => Response::NotFound,
+            (b"cas", Some(ref current)) if Some(current.sequence) != cas => Response::Exists,
+            _ => return Some((data, expiry, encode_flags(flags))),
+        };
+        None
+    })?;
+
+    Ok(response)
+}
+
+/// `delete <key>`
+fn delete(cask: &Cask, flags: &Bucket, key: &[u8]) -> Result<Response> {
+    // an expired key is still in the index, but isn't an item anymore
+    if cask.get_with_version(key)?.is_none() {
+        return Ok(Response::NotFound);
+    }
+
+    let deleted = cask.delete(key)?;
+    // the flags are stale once the item is gone, they're only deleted to reclaim their space
+    flags.delete(key)?;
+
+    Ok(match deleted {
+        Some(_) => Response::Line("DELETED".into()),
+        None => Response::NotFound,
+    })
+}
+
+/// `incr|decr <key> <value>`
+///
+/// Like in memcached, incrementing wraps around at 2^64 and decrementing stops at 0. The flags and
+/// expiration time of the item are kept.
+fn incr_decr(cask: &Cask, flags: &Bucket, args: &[&[u8]], incr: bool) -> Result<Response> {
+    let delta = match parse::<u64>(args[1]) {
+        Some(delta) => delta,
+        None => return Ok(Response::ClientError("invalid numeric delta argument")),
+    };
+
+    let mut response = Response::NotFound;
+    cask.update_with_meta(args[0], flags, |current| {
+        let current = current?;
+
+        let n = match parse::<u64>(&current.value) {
+            Some(n) if incr => n.wrapping_add(delta),
+            Some(n) => n.saturating_sub(delta),
+            None => {
+                response = Response::ClientError("cannot increment or decrement non-numeric value");
+                return None;
+            }
+        };
+
+        response = Response::Line(n.to_string());
+        Some((n.to_string().into_bytes(), current.expiry, current.meta))
+    })?;
+
+    Ok(response)
+}
+
+fn stats(cask: &Cask, started: Instant) -> Result<Response> {
+    let stats = cask.stats()?;
+
+    let stats = vec![
+        ("pid", process::id().to_string()),
+        ("uptime", started.elapsed().as_secs().to_string()),
+        ("time", (now_millis() / 1000).to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("curr_items", stats.keys.to_string()),
+        ("bytes", stats.live_bytes.to_string()),
+        ("dead_bytes", stats.dead_bytes.to_string()),
+        ("disk_size", stats.disk_size.to_string()),
+        ("sequence", stats.sequence.to_string()),
+        ("data_files", stats.files.len().to_string()),
+    ];
+
+    Ok(Response::Stats(stats))
+}
+
+/// Converts a memcached expiration time into an expiry timestamp, in milliseconds since the epoch,
+/// which saturates so that expiration times too far away to be represented never expire.
+fn to_expiry(exptime: i64, now: u64) -> Option<u64> {
+    if exptime == 0 {
+        None
+    } else if exptime < 0 {
+        // the item is expired right away
+        Some(1)
+    } else if exptime <= MAX_RELATIVE_EXPTIME {
+        Some(now.saturating_add((exptime as u64).saturating_mul(1000)))
+    } else {
+        Some((exptime as u64).saturating_mul(1000))
+    }
+}
+
+/// Returns the flags stored in the flags bucket for an item, if any. Items without stored flags
+/// have no flags.
+fn decode_flags(meta: Option<Vec<u8>>) -> u32 {
+    match meta {
+        Some(ref meta) if meta.len() == FLAGS_SIZE => BigEndian::read_u32(meta),
+        _ => 0,
+    }
+}
+
+/// Returns the flags to store in the flags bucket for an item, none are stored if they're zero.
+fn encode_flags(flags: u32) -> Option<Vec<u8>> {
+    if flags == 0 {
+        return None;
+    }
+
+    let mut meta = vec![0u8; FLAGS_SIZE];
+    BigEndian::write_u32(&mut meta, flags);
+    Some(meta)
+}
+
+fn is_valid_key(key: &[u8]) -> bool {
+    key.len() <= MAX_KEY_SIZE && !key.iter().any(|b| b.is_ascii_control())
+}
+
+fn parse<T: str::FromStr>(bytes: &[u8]) -> Option<T> {
+    str::from_utf8(bytes).ok().and_then(|s| s.parse().ok())
+}
+
+struct Item {
+    key: Vec<u8>,
+    flags: u32,
+    data: Vec<u8>,
+    cas: Option<SequenceNumber>,
+}
+
+enum Response {
+    None,
+    Line(String),
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+    /// The items found by `get` or `gets`, followed by `END`.
+    Items(Vec<Item>),
+    Stats(Vec<(&'static str, String)>),
+    Error,
+    ClientError(&'static str),
+    ServerError(String),
+}
+
+impl Response {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match *self {
+            Response::None => {}
+            Response::Line(ref line) => write!(writer, "{}\r\n", line)?,
+            Response::Stored => writer.write_all(b"STORED\r\n")?,
+            Response::NotStored => writer.write_all(b"NOT_STORED\r\n")?,
+            Response::Exists => writer.write_all(b"EXISTS\r\n")?,
+            Response::NotFound => writer.write_all(b"NOT_FOUND\r\n")?,
+            Response::Items(ref items) => {
+                for item in items {
+                    writer.write_all(b"VALUE ")?;
+                    writer.write_all(&item.key)?;
+                    write!(writer, " {} {}", item.flags, item.data.len())?;
+                    if let Some(cas) = item.cas {
+                        write!(writer, " {}", cas)?;
+                    }
+                    writer.write_all(b"\r\n")?;
+                    writer.write_all(&item.data)?;
+                    writer.write_all(b"\r\n")?;
+                }
+                writer.write_all(b"END\r\n")?;
+            }
+            Response::Stats(ref stats) => {
+                for &(name, ref value) in stats {
+                    write!(writer, "STAT {} {}\r\n", name, value)?;
+                }
+                writer.write_all(b"END\r\n")?;
+            }
+            Response::Error => writer.write_all(b"ERROR\r\n")?,
+            Response::ClientError(err) => write!(writer, "CLIENT_ERROR {}\r\n", err)?,
+            Response::ServerError(ref err) => {
+                // the message must fit on a single line
+                let err = err.replace(&['\r', '\n'][..], " ");
+                write!(writer, "SERVER_ERROR {}\r\n", err)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a command line, returning `None` once the client has gone away. Lines longer than
+/// `MAX_LINE_SIZE` fail with an `io::ErrorKind::InvalidData` error.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+
+    if reader
+        .take(MAX_LINE_SIZE as u64)
+        .read_until(b'\n', &mut line)? == 0
+    {
+        return Ok(None);
+    }
+
+    if line.len() == MAX_LINE_SIZE && line.last() != Some(&b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use cask::{CaskOptions, SyncStrategy};
+    use memcached::{serve, to_expiry, MAX_LINE_SIZE};
+
+    fn request(stream: &mut TcpStream, request: &str, expected: &str) {
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = vec![0u8; expected.len()];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_to_expiry() {
+        assert_eq!(to_expiry(0, 1000), None);
+        assert_eq!(to_expiry(-1, 1000), Some(1));
+        assert_eq!(to_expiry(10, 1000), Some(11_000));
+        assert_eq!(to_expiry(1_600_000_000, 1000), Some(1_600_000_000_000));
+        assert_eq!(to_expiry(10, u64::MAX - 1), Some(u64::MAX));
+        assert_eq!(to_expiry(i64::MAX, 1000), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_memcached() {
+        let path = "test-memcached.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        {
+            let cask = cask.clone();
+            thread::spawn(move || serve(&cask, listener));
+        }
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        request(&mut stream, "set a 42 0 5\r\nhello\r\n", "STORED\r\n");
+        request(
+            &mut stream,
+            "get a b\r\n",
+            "VALUE a 42 5\r\nhello\r\nEND\r\n",
+        );
+        request(&mut stream, "add a 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+        request(&mut stream, "replace b 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+        request(&mut stream, "add b 0 0 1\r\n1\r\n", "STORED\r\n");
+
+        // the CAS unique is the sequence number of the last write, storing the flags of `a` took
+        // one as well
+        request(&mut stream, "gets b\r\n", "VALUE b 0 1 3\r\n1\r\nEND\r\n");
+        request(&mut stream, "cas b 0 0 1 1\r\n2\r\n", "EXISTS\r\n");
+        request(&mut stream, "cas c 0 0 1 1\r\n2\r\n", "NOT_FOUND\r\n");
+        request(&mut stream, "cas b 7 0 1 3\r\n9\r\n", "STORED\r\n");
+
+        request(&mut stream, "incr b 3\r\n", "12\r\n");
+        request(&mut stream, "decr b 20\r\n", "0\r\n");
+        request(&mut stream, "get b\r\n", "VALUE b 7 1\r\n0\r\nEND\r\n");
+        request(
+            &mut stream,
+            "incr a 1\r\n",
+            "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+        );
+        request(&mut stream, "incr c 1\r\n", "NOT_FOUND\r\n");
+
+        // the data is stored as is, the flags are dropped by writes through other interfaces
+        assert_eq!(cask.get("a").unwrap(), Some(b"hello".to_vec()));
+        cask.put("d", "hi").unwrap();
+        request(&mut stream, "get d\r\n", "VALUE d 0 2\r\nhi\r\nEND\r\n");
+        request(&mut stream, "set d 1 0 1\r\nx\r\n", "STORED\r\n");
+        cask.put("d", "y").unwrap();
+        request(&mut stream, "get d\r\n", "VALUE d 0 1\r\ny\r\nEND\r\n");
+        request(&mut stream, "delete d\r\n", "DELETED\r\n");
+
+        // noreply commands don't respond, and expired items are gone
+        stream.write_all(b"set c 0 -1 1 noreply\r\nx\r\n").unwrap();
+        stream.write_all(b"delete a noreply\r\n").unwrap();
+        request(&mut stream, "get a c\r\n", "END\r\n");
+        request(&mut stream, "delete c\r\n", "NOT_FOUND\r\n");
+        request(&mut stream, "delete b\r\n", "DELETED\r\n");
+
+        request(&mut stream, "foo\r\n", "ERROR\r\n");
+        request(
+            &mut stream,
+            "get\r\n",
+            "CLIENT_ERROR bad command line format\r\n",
+        );
+        // the rest of the data block is taken for a command
+        request(
+            &mut stream,
+            "set a 0 0 1\r\nxyz\r\n",
+            "CLIENT_ERROR bad data chunk\r\nERROR\r\n",
+        );
+
+        let mut stats = String::new();
+        stream.write_all(b"stats\r\nquit\r\n").unwrap();
+        stream.read_to_string(&mut stats).unwrap();
+        assert!(stats.starts_with("STAT pid "));
+        assert!(stats.ends_with("END\r\n"));
+
+        // lines are bounded, the connection is closed once a line gets too long
+        let mut stream = TcpStream::connect(addr).unwrap();
+        request(
+            &mut stream,
+            &"a".repeat(MAX_LINE_SIZE),
+            "CLIENT_ERROR line is too long\r\n",
+        );
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
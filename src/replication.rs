@@ -0,0 +1,316 @@
+//! Log-shipping replication from a leader `Cask` to read-only followers.
+//!
+//! A leader exposes its append stream through `Cask::replicate`, which yields `LogRecord`s holding
+//! the raw bytes of every entry appended to its log. A follower, opened with
+//! `CaskOptions::follower`, appends the records to its own log with `Cask::apply`. Since the
+//! entries keep the sequence numbers assigned by the leader, the sequence number of the last
+//! write of the follower (`Cask::sequence`) is the position it resumes from after a restart.
+//!
+//! `serve` and `follow` ship the records over a Unix socket.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use std::thread;
+//!
+//! use cask::CaskOptions;
+//!
+//! let leader = CaskOptions::default().open("leader.db").unwrap();
+//! let follower = CaskOptions::default().follower(true).open("follower.db").unwrap();
+//!
+//! thread::spawn(move || {
+//!     for record in leader.replicate(follower.sequence()).unwrap() {
+//!         follower.apply(&record.unwrap()).unwrap();
+//!     }
+//! });
+//! ```
+
+use std::io::{self, Cursor, Read, Write};
+#[cfg(unix)]
+use std::io::{BufReader, BufWriter};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+#[cfg(unix)]
+use std::thread;
+use std::vec::Vec;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+#[cfg(unix)]
+use cask::Cask;
+use changes::Changes;
+use data::{Entry, SequenceNumber};
+use errors::{Error, Result};
+
+/// A record of the append stream of a `Cask`: the raw bytes of an entry, or of a batch marker
+/// followed by the entries of the batch, as written at `entry_pos` of the data file `file_id` of
+/// the leader. `sequence` is the sequence number of the last entry of the record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    pub file_id: u32,
+    pub entry_pos: u64,
+    pub sequence: SequenceNumber,
+    pub data: Vec<u8>,
+}
+
+impl LogRecord {
+    fn entry(file_id: u32, entry_pos: u64, entry: &Entry) -> LogRecord {
+        LogRecord {
+            file_id: file_id,
+            entry_pos: entry_pos,
+            sequence: entry.sequence,
+            data: entry.to_bytes().unwrap(),
+        }
+    }
+
+    fn batch(file_id: u32, entries: &[Entry], entry_positions: &[u64]) -> LogRecord {
+        let marker = Entry::batch(entries[0].sequence, entries.len() as u32);
+
+        let mut data = marker.to_bytes().unwrap();
+        for entry in entries {
+            entry.write_bytes(&mut data).unwrap();
+        }
+
+        LogRecord {
+            file_id: file_id,
+            entry_pos: entry_positions[0] - marker.size(),
+            sequence: entries[entries.len() - 1].sequence,
+            data: data,
+        }
+    }
+
+    /// Decodes the entries of the record, returning whether they form a batch.
+    pub(crate) fn entries<'a>(&self) -> Result<(bool, Vec<Entry<'a>>)> {
+        let mut cursor = Cursor::new(&self.data[..]);
+        let mut entries = Vec::new();
+
+        while cursor.position() < self.data.len() as u64 {
+            entries.push(Entry::from_read(&mut cursor)?);
+        }
+
+        let batch = match entries.first() {
+            Some(entry) => entry.batch,
+            None => false,
+        };
+
+        if batch {
+            let marker = entries.remove(0);
+            if marker.batch_size() != Some(entries.len() as u32) {
+                return Err(invalid_record("batch size doesn't match its entries"));
+            }
+        } else if entries.len() != 1 {
+            return Err(invalid_record("expected a single entry"));
+        }
+
+        if entries.iter().any(|entry| entry.batch) {
+            return Err(invalid_record("unexpected batch marker"));
+        }
+
+        Ok((batch, entries))
+    }
+}
+
+pub(crate) struct LogSubscribers {
+    senders: Vec<Sender<LogRecord>>,
+}
+
+impl LogSubscribers {
+    pub fn new() -> LogSubscribers {
+        LogSubscribers {
+            senders: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<LogRecord> {
+        let (sender, receiver) = channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    pub fn notify_entry(&mut self, file_id: u32, entry_pos: u64, entry: &Entry) {
+        if !self.senders.is_empty() {
+            let record = LogRecord::entry(file_id, entry_pos, entry);
+            self.notify(record);
+        }
+    }
+
+    pub fn notify_batch(&mut self, file_id: u32, entries: &[Entry], entry_positions: &[u64]) {
+        if !self.senders.is_empty() {
+            let record = LogRecord::batch(file_id, entries, entry_positions);
+            self.notify(record);
+        }
+    }
+
+    fn notify(&mut self, record: LogRecord) {
+        self.senders
+            .retain(|sender| sender.send(record.clone()).is_ok());
+    }
+}
+
+/// The append stream of a `Cask`, see `Cask::replicate`.
+///
+/// The stream first yields the entries still present in the data files, one record per entry or
+/// per batch whose entries are still in the data file they were written to, and then the records
+/// of the writes as they are committed. Iterating it blocks until the next record is available
+/// and ends once the `Cask` is closed.
+pub struct ReplicationStream {
+    catch_up: Changes,
+    live: Receiver<LogRecord>,
+    sequence: SequenceNumber,
+}
+
+impl ReplicationStream {
+    pub(crate) fn new(
+        catch_up: Changes,
+        live: Receiver<LogRecord>,
+        sequence: SequenceNumber,
+    ) -> ReplicationStream {
+        ReplicationStream {
+            catch_up: catch_up,
+            live: live,
+            sequence: sequence,
+        }
+    }
+
+    /// Returns the next record if one is available, without blocking.
+    pub fn try_next(&mut self) -> Option<Result<LogRecord>> {
+        self.next_record(|live| live.try_recv().ok())
+    }
+
+    fn next_record<F>(&mut self, recv: F) -> Option<Result<LogRecord>>
+    where
+        F: Fn(&Receiver<LogRecord>) -> Option<LogRecord>,
+    {
+        if let Some(batch) = self.catch_up.next_batch() {
+            return Some(batch.map(|(file_id, batch, entry_positions, entries)| {
+                self.sequence = entries[entries.len() - 1].sequence;
+                if batch {
+                    LogRecord::batch(file_id, &entries, &entry_positions)
+                } else {
+                    LogRecord::entry(file_id, entry_positions[0], &entries[0])
+                }
+            }));
+        }
+
+        // writes committed while catching up are both on disk and in the live stream
+        while let Some(record) = recv(&self.live) {
+            if record.sequence > self.sequence {
+                self.sequence = record.sequence;
+                return Some(Ok(record));
+            }
+        }
+
+        None
+    }
+}
+
+impl Iterator for ReplicationStream {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Result<LogRecord>> {
+        self.next_record(|live| live.recv().ok())
+    }
+}
+
+/// Serves the append stream of the leader `cask` to the followers connecting to `listener`, see
+/// `follow`. Each follower is served by its own thread.
+#[cfg(unix)]
+pub fn serve(cask: &Cask, listener: UnixListener) -> Result<()> {
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+
+        let records = match stream
+            .read_u64::<LittleEndian>()
+            .map_err(Error::Io)
+            .and_then(|sequence| cask.replicate(sequence))
+        {
+            Ok(records) => records,
+            Err(err) => {
+                warn!("Failed to start replication to follower: {}", err);
+                continue;
+            }
+        };
+
+        thread::spawn(move || {
+            if let Err(err) = send_records(stream, records) {
+                warn!("Replication to follower stopped: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Connects the follower `cask` to the leader served at `path`, see `serve`, and applies its
+/// append stream until the leader goes away.
+#[cfg(unix)]
+pub fn follow<P: AsRef<Path>>(cask: &Cask, path: P) -> Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_u64::<LittleEndian>(cask.sequence())?;
+
+    let mut reader = BufReader::new(stream);
+
+    while let Some(record) = read_record(&mut reader)? {
+        cask.apply(&record)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn send_records(stream: UnixStream, records: ReplicationStream) -> Result<()> {
+    let mut writer = BufWriter::new(stream);
+
+    for record in records {
+        write_record(&mut writer, &record?)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &LogRecord) -> Result<()> {
+    writer.write_u32::<LittleEndian>(record.file_id)?;
+    writer.write_u64::<LittleEndian>(record.entry_pos)?;
+    writer.write_u64::<LittleEndian>(record.sequence)?;
+    writer.write_u32::<LittleEndian>(record.data.len() as u32)?;
+    writer.write_all(&record.data)?;
+    Ok(())
+}
+
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<LogRecord>> {
+    let file_id = match reader.read_u32::<LittleEndian>() {
+        Ok(file_id) => file_id,
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(Error::Io(err)),
+    };
+    let entry_pos = reader.read_u64::<LittleEndian>()?;
+    let sequence = reader.read_u64::<LittleEndian>()?;
+    let data_size = reader.read_u32::<LittleEndian>()?;
+
+    let mut data = Vec::new();
+    reader.take(u64::from(data_size)).read_to_end(&mut data)?;
+    if data.len() != data_size as usize {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated log record",
+        )));
+    }
+
+    Ok(Some(LogRecord {
+        file_id: file_id,
+        entry_pos: entry_pos,
+        sequence: sequence,
+        data: data,
+    }))
+}
+
+fn invalid_record(msg: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid log record: {}", msg),
+    ))
+}
@@ -0,0 +1,652 @@
+//! A server speaking a subset of the Redis protocol (RESP), so that a `Cask` can be used with
+//! standard Redis clients.
+//!
+//! The supported commands are `GET`, `SET` (with the `EX`, `PX` and `NX` options), `DEL`,
+//! `EXISTS`, `KEYS`, `SCAN` (with the `MATCH` and `COUNT` options), `MGET`, `MSET`, `DBSIZE`,
+//! `INFO`, `PING` and `QUIT`. There is a single keyspace, `SELECT` isn't supported.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use std::net::TcpListener;
+//!
+//! use cask::CaskOptions;
+//! use cask::server;
+//!
+//! let cask = CaskOptions::default().open("cask.db").unwrap();
+//! let listener = TcpListener::bind("127.0.0.1:6379").unwrap();
+//!
+//! server::serve(&cask, listener).unwrap();
+//! ```
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::thread;
+use std::time::Duration;
+use std::vec::Vec;
+
+use cask::{expiry, Cask};
+use batch::WriteBatch;
+use errors::{Error, Result};
+use util::{now_millis, xxhash32};
+
+/// Maximum size of a single argument of a command, as in Redis.
+const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
+
+/// Maximum size of a line, i.e. of an inline command or of the header of a multibulk command or
+/// bulk string, as in Redis.
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+
+/// Maximum size of a pattern given to `KEYS` or `SCAN`, which bounds the time taken to match each
+/// key against it.
+const MAX_PATTERN_SIZE: usize = 1024;
+
+/// Number of keys returned by `SCAN` if no `COUNT` is given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Serves `cask` to the Redis clients connecting to `listener`. Each client is served by its own
+/// thread.
+pub fn serve(cask: &Cask, listener: TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let cask = cask.clone();
+
+        thread::spawn(move || {
+            if let Err(err) = handle_client(&cask, stream) {
+                warn!("Closed connection to client: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_client(cask: &Cask, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // the position in the stream is lost, so the connection can't be used anymore
+                Reply::Error(format!("ERR Protocol error: {}", err)).write(&mut writer)?;
+                writer.flush()?;
+                return Err(err);
+            }
+        };
+
+        if args.is_empty() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+        let reply = match execute(cask, &name, &args[1..]) {
+            Ok(reply) => reply,
+            Err(err) => Reply::Error(format!("ERR {}", err)),
+        };
+
+        reply.write(&mut writer)?;
+        writer.flush()?;
+
+        if name == "quit" {
+            return Ok(());
+        }
+    }
+}
+
+fn execute(cask: &Cask, name: &str, args: &[Vec<u8>]) -> Result<Reply> {
+    let arity_ok = match name {
+        "ping" => args.len() <= 1,
+        "quit" | "dbsize" => args.is_empty(),
+        "info" => args.len() <= 1,
+        "get" | "keys" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "mget" | "scan" => !args.is_empty(),
+        "mset" => !args.is_empty() && args.chunks(2).all(|pair| pair.len() == 2),
+        _ => return Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+    };
+
+    if !arity_ok {
+        return Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )));
+    }
+
+    let reply = match name {
+        "ping" => match args.first() {
+            Some(message) => Reply::Bulk(Some(message.clone())),
+            None => Reply::Status("PONG"),
+        },
+        "quit" => Reply::Status("OK"),
+        "get" => Reply::Bulk(cask.get(&args[0])?),
+        "set" => set(cask, args)?,
+        "del" => {
+            let mut deleted = 0;
+            for key in args {
+                if cask.delete(key)?.is_some() {
+                    deleted += 1;
+                }
+            }
+            Reply::Integer(deleted)
+        }
+        "exists" => {
+            let mut exists = 0;
+            for key in args {
+                if cask.get(key)?.is_some() {
+                    exists += 1;
+                }
+            }
+            Reply::Integer(exists)
+        }
+        "keys" if args[0].len() > MAX_PATTERN_SIZE => Reply::Error("ERR pattern too long".into()),
+        "keys" => Reply::Array(
+            cask.keys()
+                .into_iter()
+                .filter(|key| glob_match(&args[0], key))
+                .map(|key| Reply::Bulk(Some(key)))
+                .collect(),
+        ),
+        "scan" => scan(cask, args)?,
+        "mget" => {
+            let mut values = Vec::with_capacity(args.len());
+            for key in args {
+                values.push(Reply::Bulk(cask.get(key)?));
+            }
+            Reply::Array(values)
+        }
+        "mset" => {
+            let mut batch = WriteBatch::new();
+            for pair in args.chunks(2) {
+                batch.put(pair[0].clone(), &pair[1]);
+            }
+            cask.write_batch(batch)?;
+            Reply::Status("OK")
+        }
+        "dbsize" => Reply::Integer(cask.keys().len() as i64),
+        "info" => info(cask)?,
+        _ => unreachable!(),
+    };
+
+    Ok(reply)
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX]`
+fn set(cask: &Cask, args: &[Vec<u8>]) -> Result<Reply> {
+    let mut ttl = None;
+    let mut nx = false;
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match &option.to_ascii_lowercase()[..] {
+            b"ex" | b"px" if ttl.is_none() => {
+                let n = match options.next().and_then(|n| parse_integer(n)) {
+                    Some(n) if n > 0 => n as u64,
+                    _ => {
+                        return Ok(Reply::Error(
+                            "ERR invalid expire time in 'set' command".into(),
+                        ))
+                    }
+                };
+                ttl = Some(if option.eq_ignore_ascii_case(b"ex") {
+                    Duration::from_secs(n)
+                } else {
+                    Duration::from_millis(n)
+                });
+            }
+            b"nx" => nx = true,
+            _ => return Ok(Reply::Error("ERR syntax error".into())),
+        }
+    }
+
+    let key = &args[0];
+    let value = &args[1];
+
+    match (nx, ttl) {
+        (true, Some(ttl)) => {
+            let now = now_millis();
+            let written = cask.update(key, |current| match current {
+                Some(_) => None,
+                None => Some((value.clone(), Some(expiry(now, ttl)))),
+            })?;
+            Ok(if written.is_some() {
+                Reply::Status("OK")
+            } else {
+                Reply::Bulk(None)
+            })
+        }
+        (true, None) => Ok(if cask.put_if_absent(key, value)? {
+            Reply::Status("OK")
+        } else {
+            Reply::Bulk(None)
+        }),
+        (false, Some(ttl)) => {
+            cask.put_with_ttl(key.clone(), value, ttl)?;
+            Ok(Reply::Status("OK"))
+        }
+        (false, None) => {
+            cask.put(key.clone(), value)?;
+            Ok(Reply::Status("OK"))
+        }
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// Keys are scanned in the order of their hashes, with the cursor being the hash of the next key
+/// to return (offset by one so that 0 can start and end a scan). This order doesn't depend on the
+/// other keys, so like in Redis, a key which is present during the whole scan is returned.
+fn scan(cask: &Cask, args: &[Vec<u8>]) -> Result<Reply> {
+    let cursor = match parse_integer(&args[0]) {
+        Some(cursor) if cursor >= 0 => cursor as u64,
+        _ => return Ok(Reply::Error("ERR invalid cursor".into())),
+    };
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match (&option.to_ascii_lowercase()[..], options.next()) {
+            (b"match", Some(p)) if p.len() > MAX_PATTERN_SIZE => {
+                return Ok(Reply::Error("ERR pattern too long".into()))
+            }
+            (b"match", Some(p)) => pattern = Some(p),
+            (b"count", Some(n)) => match parse_integer(n) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(Reply::Error("ERR syntax error".into())),
+            },
+            _ => return Ok(Reply::Error("ERR syntax error".into())),
+        }
+    }
+
+    let mut keys: Vec<_> = cask
+        .keys()
+        .into_iter()
+        .map(|key| (scan_hash(&key), key))
+        .filter(|&(hash, _)| hash >= cursor)
+        .collect();
+    keys.sort();
+
+    // keys with the same hash can't be told apart by the cursor so they're returned together
+    let mut end = keys.len().min(count);
+    while end < keys.len() && keys[end].0 == keys[end - 1].0 {
+        end += 1;
+    }
+
+    let next_cursor = match keys.get(end) {
+        Some(&(hash, _)) => hash,
+        None => 0,
+    };
+
+    let keys = keys
+        .into_iter()
+        .take(end)
+        .filter(|(_, key)| match pattern {
+            Some(pattern) => glob_match(pattern, key),
+            None => true,
+        })
+        .map(|(_, key)| Reply::Bulk(Some(key)))
+        .collect();
+
+    Ok(Reply::Array(vec![
+        Reply::Bulk(Some(next_cursor.to_string().into_bytes())),
+        Reply::Array(keys),
+    ]))
+}
+
+fn scan_hash(key: &[u8]) -> u64 {
+    u64::from(xxhash32(key)) + 1
+}
+
+fn info(cask: &Cask) -> Result<Reply> {
+    let stats = cask.stats()?;
+
+    let info = format!(
+        "# Server\r\n\
+         cask_version:{}\r\n\
+         \r\n\
+         # Cask\r\n\
+         live_bytes:{}\r\n\
+         dead_bytes:{}\r\n\
+         disk_size:{}\r\n\
+         sequence:{}\r\n\
+         data_files:{}\r\n\
+         \r\n\
+         # Keyspace\r\n\
+         db0:keys={}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        stats.live_bytes,
+        stats.dead_bytes,
+        stats.disk_size,
+        stats.sequence,
+        stats.files.len(),
+        stats.keys
+    );
+
+    Ok(Reply::Bulk(Some(info.into_bytes())))
+}
+
+/// Matches `s` against a Redis glob-style pattern, supporting `*`, `?`, `[...]` (with `^` and
+/// ranges) and `\` escapes.
+///
+/// The pattern is matched from left to right, going back to the last `*` whenever the rest of the
+/// pattern doesn't match, which takes at most `O(pattern.len() * s.len())` steps.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the position in the pattern after the last `*`, and the position in `s` it was matched from
+    let mut star = None;
+
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            // a run of `*` matches like a single one
+            while pattern.get(p) == Some(&b'*') {
+                p += 1;
+            }
+            if p == pattern.len() {
+                return true;
+            }
+
+            star = Some((p, i));
+            continue;
+        }
+
+        match match_byte(pattern, p, s[i]) {
+            Some(next) => {
+                p = next;
+                i += 1;
+            }
+            None => match star {
+                // the last `*` matches one more byte
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    star = Some((star_p, i));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    while pattern.get(p) == Some(&b'*') {
+        p += 1;
+    }
+
+    p == pattern.len()
+}
+
+/// Matches the byte `c` against the element of `pattern` at `p`, other than `*`, returning the
+/// position of the next element if it matches.
+fn match_byte(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (start, end) = (pattern[i], pattern[i + 2]);
+                    matched |= start.min(end) <= c && c <= start.max(end);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+
+            // an unterminated class runs to the end of the pattern
+            if matched != negate {
+                Some((i + 1).min(pattern.len()))
+            } else {
+                None
+            }
+        }
+        b'\\' if p + 1 < pattern.len() => {
+            if pattern[p + 1] == c {
+                Some(p + 2)
+            } else {
+                None
+            }
+        }
+        b if b == c => Some(p + 1),
+        _ => None,
+    }
+}
+
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    str::from_utf8(bytes).ok().and_then(|s| s.parse().ok())
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match *self {
+            Reply::Status(status) => write!(writer, "+{}\r\n", status)?,
+            Reply::Error(ref err) => {
+                // the message must fit on a single line
+                let err = err.replace(&['\r', '\n'][..], " ");
+                write!(writer, "-{}\r\n", err)?
+            }
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(Some(ref bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Array(ref replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write(writer)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a command, either as an array of bulk strings or inline, returning `None` once the
+/// client has gone away.
+fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        ));
+    }
+
+    let len = match parse_integer(&line[1..]) {
+        Some(len) if len >= 0 => len as usize,
+        _ => return Err(protocol_error("invalid multibulk length")),
+    };
+
+    let mut args = Vec::with_capacity(len.min(1024));
+
+    for _ in 0..len {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+
+        let size = match line.first() {
+            Some(&b'$') => match parse_integer(&line[1..]) {
+                Some(size) if size >= 0 && size as usize <= MAX_BULK_SIZE => size as usize,
+                _ => return Err(protocol_error("invalid bulk length")),
+            },
+            _ => return Err(protocol_error("expected '$'")),
+        };
+
+        let mut arg = Vec::new();
+        reader.take(size as u64 + 2).read_to_end(&mut arg)?;
+
+        if arg.len() != size + 2 || !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("invalid bulk string"));
+        }
+
+        arg.truncate(size);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+
+    if reader
+        .take(MAX_INLINE_SIZE as u64)
+        .read_until(b'\n', &mut line)? == 0
+    {
+        return Ok(None);
+    }
+
+    if line.len() == MAX_INLINE_SIZE && line.last() != Some(&b'\n') {
+        return Err(protocol_error("too big inline request"));
+    }
+
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use cask::{CaskOptions, SyncStrategy};
+    use server::{glob_match, serve, MAX_INLINE_SIZE, MAX_PATTERN_SIZE};
+
+    fn request(stream: &mut TcpStream, request: &str, reply_len: usize) -> String {
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut reply = vec![0u8; reply_len];
+        stream.read_exact(&mut reply).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(!glob_match(b"hello", b"hell"));
+        assert!(glob_match(b"*a*b", b"xaxxb"));
+        assert!(!glob_match(b"*a*b", b"xaxxbx"));
+        assert!(glob_match(b"a**", b"a"));
+        assert!(!glob_match(b"[a", b""));
+
+        // backtracking is bounded, however many stars the pattern has
+        let pattern = [&[&b"a*"[..]; 100].concat()[..], b"b"].concat();
+        assert!(!glob_match(&pattern, &[b'a'; 1000]));
+    }
+
+    #[test]
+    fn test_server() {
+        let path = "test-server.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(&cask, listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        assert_eq!(request(&mut stream, "PING\r\n", 7), "+PONG\r\n");
+        assert_eq!(
+            request(&mut stream, "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n", 5),
+            "+OK\r\n"
+        );
+        assert_eq!(request(&mut stream, "mset b 2 c 3\r\n", 5), "+OK\r\n");
+        assert_eq!(request(&mut stream, "set a 2 nx\r\n", 5), "$-1\r\n");
+        assert_eq!(request(&mut stream, "set l 1 nx ex 10\r\n", 5), "+OK\r\n");
+        assert_eq!(request(&mut stream, "set l 2 px 10 nx\r\n", 5), "$-1\r\n");
+        assert_eq!(request(&mut stream, "del l\r\n", 4), ":1\r\n");
+        assert_eq!(
+            request(&mut stream, "*2\r\n$3\r\nGET\r\n$1\r\na\r\n", 7),
+            "$1\r\n1\r\n"
+        );
+        assert_eq!(
+            request(&mut stream, "mget a d\r\n", 16),
+            "*2\r\n$1\r\n1\r\n$-1\r\n"
+        );
+        assert_eq!(request(&mut stream, "exists a b d\r\n", 4), ":2\r\n");
+        assert_eq!(request(&mut stream, "del a d\r\n", 4), ":1\r\n");
+        assert_eq!(request(&mut stream, "dbsize\r\n", 4), ":2\r\n");
+        assert_eq!(request(&mut stream, "keys [b]\r\n", 11), "*1\r\n$1\r\nb\r\n");
+        assert_eq!(
+            request(&mut stream, "scan 0 match b\r\n", 22),
+            "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            request(&mut stream, "get\r\n", 50),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            request(&mut stream, "\r\nfoo\r\n", 28),
+            "-ERR unknown command 'foo'\r\n"
+        );
+        assert_eq!(
+            request(
+                &mut stream,
+                &format!("keys {}\r\n", "*".repeat(MAX_PATTERN_SIZE + 1)),
+                23
+            ),
+            "-ERR pattern too long\r\n"
+        );
+        assert_eq!(request(&mut stream, "quit\r\n", 5), "+OK\r\n");
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+
+        // lines are bounded, the connection is closed once a line gets too long
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&vec![b'a'; MAX_INLINE_SIZE]).unwrap();
+
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("-ERR Protocol error:"));
+        assert!(reply.contains("too big inline request"));
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
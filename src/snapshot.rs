@@ -0,0 +1,122 @@
+use std::collections::{BTreeMap, HashMap};
+use std::vec::Vec;
+
+use cask::IndexEntry;
+use data::SequenceNumber;
+
+/// An index entry which has been superseded by a newer write (or a delete) but which is still
+/// visible to a live snapshot. It is visible to snapshots in `[entry.sequence, superseded_at)`.
+struct Version {
+    entry: IndexEntry,
+    superseded_at: SequenceNumber,
+}
+
+/// Keeps track of the live snapshots of a `Cask` and of the superseded index entries that they can
+/// still see.
+pub struct Snapshots {
+    live: BTreeMap<SequenceNumber, usize>,
+    versions: HashMap<Vec<u8>, Vec<Version>>,
+}
+
+impl Snapshots {
+    pub fn new() -> Snapshots {
+        Snapshots {
+            live: BTreeMap::new(),
+            versions: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    pub fn register(&mut self, sequence: SequenceNumber) {
+        *self.live.entry(sequence).or_insert(0) += 1;
+    }
+
+    pub fn release(&mut self, sequence: SequenceNumber) {
+        if let Some(count) = self.live.get_mut(&sequence) {
+            *count -= 1;
+        }
+
+        if self.live.get(&sequence) == Some(&0) {
+            self.live.remove(&sequence);
+            self.prune();
+        }
+    }
+
+    /// Records that `entry` was superseded at `sequence`, retaining it if a live snapshot can
+    /// still see it.
+    pub fn supersede(&mut self, key: Vec<u8>, entry: IndexEntry, sequence: SequenceNumber) {
+        if self.live.range(entry.sequence..sequence).next().is_none() {
+            return;
+        }
+
+        self.versions.entry(key).or_default().push(Version {
+            entry: entry,
+            superseded_at: sequence,
+        });
+    }
+
+    /// Returns the entry for `key` visible at `sequence`, given the `current` entry in the index.
+    pub fn get<'a>(
+        &'a self,
+        key: &[u8],
+        current: Option<&'a IndexEntry>,
+        sequence: SequenceNumber,
+    ) -> Option<&'a IndexEntry> {
+        match current {
+            Some(entry) if entry.sequence <= sequence => Some(entry),
+            _ => self.versions.get(key).and_then(|versions| {
+                versions
+                    .iter()
+                    .find(|v| v.entry.sequence <= sequence && sequence < v.superseded_at)
+                    .map(|v| &v.entry)
+            }),
+        }
+    }
+
+    /// Returns the keys which have superseded entries.
+    pub fn keys<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
+        Box::new(self.versions.keys())
+    }
+
+    /// Returns the sequence number at which the entry for `key` with `sequence` was superseded, if
+    /// it must be retained by compaction.
+    pub fn retained(&self, key: &[u8], sequence: SequenceNumber) -> Option<SequenceNumber> {
+        self.versions.get(key).and_then(|versions| {
+            versions
+                .iter()
+                .find(|v| v.entry.sequence == sequence)
+                .map(|v| v.superseded_at)
+        })
+    }
+
+    /// Updates the location of a retained entry which was moved by compaction.
+    pub fn relocate(&mut self, key: &[u8], sequence: SequenceNumber, file_id: u32, entry_pos: u64) {
+        if let Some(versions) = self.versions.get_mut(key) {
+            for version in versions.iter_mut() {
+                if version.entry.sequence == sequence {
+                    version.entry.file_id = file_id;
+                    version.entry.entry_pos = entry_pos;
+                }
+            }
+        }
+    }
+
+    /// Drops the superseded entries which aren't visible to any live snapshot anymore.
+    fn prune(&mut self) {
+        if self.live.is_empty() {
+            self.versions.clear();
+            return;
+        }
+
+        let live = &self.live;
+
+        for versions in self.versions.values_mut() {
+            versions.retain(|v| live.range(v.entry.sequence..v.superseded_at).next().is_some());
+        }
+
+        self.versions.retain(|_, versions| !versions.is_empty());
+    }
+}
@@ -1,13 +1,68 @@
 use std::collections::HashMap;
 use std::collections::hash_map::Entry as HashMapEntry;
+use std::time::SystemTime;
 
 use cask::IndexEntry;
+use data::SequenceNumber;
+
+/// Statistics about a `Cask`, see `Cask::stats`.
+#[derive(Clone, Debug)]
+pub struct CaskStats {
+    /// Number of live keys, across all buckets.
+    pub keys: u64,
+    /// Amount of data occupied by live entries.
+    pub live_bytes: u64,
+    /// Amount of data occupied by dead (updated/deleted) entries that can be reclaimed by
+    /// compaction.
+    pub dead_bytes: u64,
+    /// Total size of all data and hint files.
+    pub disk_size: u64,
+    /// Sequence number of the last write.
+    pub sequence: SequenceNumber,
+    /// Id of the data file currently being written to, if any.
+    pub active_file_id: Option<u32>,
+    /// Time at which data files were last compacted by this `Cask` instance, if ever.
+    pub last_compaction: Option<SystemTime>,
+    /// Per data file statistics, ordered by file id.
+    pub files: Vec<FileStats>,
+}
+
+/// Statistics about a single data file of a `Cask`.
+#[derive(Clone, Debug)]
+pub struct FileStats {
+    pub file_id: u32,
+    /// Size of the data file.
+    pub size: u64,
+    /// Number of entries written to the file, excluding tombstones.
+    pub entries: u64,
+    /// Number of entries that have since been updated or deleted.
+    pub dead_entries: u64,
+    /// Amount of data occupied by live entries.
+    pub live_bytes: u64,
+    /// Amount of data occupied by dead entries.
+    pub dead_bytes: u64,
+    /// Ratio of dead entries to total entries, which is compared against the compaction triggers
+    /// and thresholds.
+    pub fragmentation: f64,
+}
+
+/// Statistics about a bucket of a `Cask`, see `Bucket::stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BucketStats {
+    /// Number of live keys.
+    pub keys: u64,
+    /// Amount of data occupied by live entries.
+    pub live_bytes: u64,
+    /// Amount of data occupied by dead entries that can be reclaimed by compaction.
+    pub dead_bytes: u64,
+}
 
 #[derive(Debug)]
-struct StatsEntry {
-    entries: u64,
-    dead_entries: u64,
-    dead_bytes: u64,
+pub struct StatsEntry {
+    pub entries: u64,
+    pub dead_entries: u64,
+    pub bytes: u64,
+    pub dead_bytes: u64,
 }
 
 #[derive(Debug)]
@@ -24,11 +79,13 @@ impl Stats {
         match self.map.entry(entry.file_id) {
             HashMapEntry::Occupied(mut o) => {
                 o.get_mut().entries += 1;
+                o.get_mut().bytes += entry.entry_size;
             }
             HashMapEntry::Vacant(e) => {
                 e.insert(StatsEntry {
                     entries: 1,
                     dead_entries: 0,
+                    bytes: entry.entry_size,
                     dead_bytes: 0,
                 });
             }
@@ -47,12 +104,34 @@ impl Stats {
         }
     }
 
+    /// Accounts for the entries which are live according to `stats` as dead, e.g. those of a
+    /// dropped bucket.
+    pub fn remove_all(&mut self, stats: &Stats) {
+        for (file_id, removed) in &stats.map {
+            if let Some(entry) = self.map.get_mut(file_id) {
+                entry.dead_entries += removed.entries - removed.dead_entries;
+                entry.dead_bytes += removed.bytes - removed.dead_bytes;
+            }
+        }
+    }
+
+    /// Returns the total amount of data occupied by live and dead entries.
+    pub fn bytes(&self) -> (u64, u64) {
+        self.map.values().fold((0, 0), |(live, dead), entry| {
+            (live + entry.bytes - entry.dead_bytes, dead + entry.dead_bytes)
+        })
+    }
+
     pub fn remove_files(&mut self, files: &[u32]) {
         for file_id in files {
             self.map.remove(file_id);
         }
     }
 
+    pub fn get(&self, file_id: u32) -> Option<&StatsEntry> {
+        self.map.get(&file_id)
+    }
+
     pub fn file_stats(&self) -> Vec<(u32, f64, u64)> {
         self.map
             .iter()
@@ -0,0 +1,317 @@
+//! Typed access to a `Cask`, with keys and values encoded through a `Codec`.
+//!
+//! Three codecs are provided: `Bincode` and `Json`, for any type implementing serde's `Serialize`
+//! and `Deserialize`, and `Raw`, which stores byte vectors and strings as they are.
+//!
+//! Keys are ordered by their encoded bytes, so iterating over a range of keys (see
+//! `TypedCask::range`) only follows the order of the keys themselves if their codec preserves it.
+//! This is the case of `Raw`, and of `Bincode` for unsigned integers along with arrays, tuples and
+//! structs of them, since it encodes integers as fixed-size big endian numbers. It isn't the case
+//! of `Json`.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! extern crate cask;
+//! #[macro_use]
+//! extern crate serde_derive;
+//!
+//! use cask::{CaskOptions, TypedCask};
+//! use cask::errors::Result;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! fn example() -> Result<()> {
+//!     let cask = CaskOptions::default().ordered_index(true).open("cask.db")?;
+//!     let users: TypedCask<u64, User> = TypedCask::new(cask);
+//!
+//!     users.put(&1, &User { name: "alice".into() })?;
+//!     users.get(&1)?;
+//!
+//!     for result in users.range(1..10)? {
+//!         let (id, user) = result?;
+//!         println!("{}: {}", id, user.name);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! #
+//! # fn main() {}
+//! ```
+
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::vec::Vec;
+
+use bincode::{self, Options};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use cask::Cask;
+use data::SequenceNumber;
+use errors::{Error, Result};
+
+type Marker<K, V, KC, VC> = PhantomData<fn() -> (K, V, KC, VC)>;
+
+type EntryIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// Encodes values of type `T` to bytes and decodes them back.
+pub trait Codec<T> {
+    fn encode(value: &T) -> Result<Vec<u8>>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// Encodes values with bincode, as fixed-size big endian integers.
+pub struct Bincode;
+
+/// Encodes values as JSON.
+pub struct Json;
+
+/// Stores byte vectors and strings as they are.
+pub struct Raw;
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Bincode {
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        bincode_options()
+            .serialize(value)
+            .map_err(|err| Error::Encode(err))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        bincode_options()
+            .deserialize(bytes)
+            .map_err(|err| Error::Decode(err))
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|err| Error::Encode(Box::new(err)))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|err| Error::Decode(Box::new(err)))
+    }
+}
+
+impl Codec<Vec<u8>> for Raw {
+    fn encode(value: &Vec<u8>) -> Result<Vec<u8>> {
+        Ok(value.clone())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Codec<String> for Raw {
+    fn encode(value: &String) -> Result<Vec<u8>> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<String> {
+        String::from_utf8(bytes.to_vec()).map_err(|err| Error::Decode(Box::new(err)))
+    }
+}
+
+/// A handle to a `Cask` storing keys of type `K` and values of type `V`, encoded with the codecs
+/// `KC` and `VC` respectively.
+///
+/// The keys of the `Cask` are expected to all be encoded the same way, otherwise reading them
+/// fails with `Error::Decode`.
+pub struct TypedCask<K, V, KC = Bincode, VC = Bincode> {
+    cask: Cask,
+    marker: Marker<K, V, KC, VC>,
+}
+
+impl<K, V, KC, VC> TypedCask<K, V, KC, VC>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    pub fn new(cask: Cask) -> TypedCask<K, V, KC, VC> {
+        TypedCask {
+            cask: cask,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the underlying `Cask`.
+    pub fn cask(&self) -> &Cask {
+        &self.cask
+    }
+
+    /// Returns the value corresponding to the key, if any.
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.cask.get(KC::encode(key)?)? {
+            Some(value) => VC::decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Inserts a key-value pair into the map, returning the sequence number assigned to the write.
+    pub fn put(&self, key: &K, value: &V) -> Result<SequenceNumber> {
+        self.cask.put(KC::encode(key)?, VC::encode(value)?)
+    }
+
+    /// Removes a key from the map, returning the sequence number assigned to the write if the key
+    /// was present.
+    pub fn delete(&self, key: &K) -> Result<Option<SequenceNumber>> {
+        self.cask.delete(KC::encode(key)?)
+    }
+
+    /// Returns the keys of the map, in no particular order.
+    pub fn keys(&self) -> Result<Vec<K>> {
+        self.cask.keys().iter().map(|key| KC::decode(key)).collect()
+    }
+
+    /// Returns a lazy iterator over the key-value pairs of the map, see `Cask::iter`.
+    pub fn iter(&self) -> TypedIter<K, V, KC, VC> {
+        TypedIter::new(Box::new(self.cask.iter()))
+    }
+
+    /// Returns a lazy iterator over the key-value pairs whose encoded keys are within the encoded
+    /// `range`, in the order of the encoded keys, see `Cask::range`.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<TypedIter<K, V, KC, VC>> {
+        let start = encode_bound::<K, KC>(range.start_bound())?;
+        let end = encode_bound::<K, KC>(range.end_bound())?;
+
+        Ok(TypedIter::new(Box::new(self.cask.range((start, end)))))
+    }
+}
+
+impl<K, V, KC, VC> Clone for TypedCask<K, V, KC, VC> {
+    fn clone(&self) -> TypedCask<K, V, KC, VC> {
+        TypedCask {
+            cask: self.cask.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+fn encode_bound<K, KC: Codec<K>>(bound: Bound<&K>) -> Result<Bound<Vec<u8>>> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(KC::encode(key)?),
+        Bound::Excluded(key) => Bound::Excluded(KC::encode(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// An iterator over the decoded key-value pairs of a `TypedCask`.
+pub struct TypedIter<K, V, KC, VC> {
+    iter: EntryIter,
+    marker: Marker<K, V, KC, VC>,
+}
+
+impl<K, V, KC, VC> TypedIter<K, V, KC, VC> {
+    fn new(iter: EntryIter) -> TypedIter<K, V, KC, VC> {
+        TypedIter {
+            iter: iter,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, KC, VC> Iterator for TypedIter<K, V, KC, VC>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Result<(K, V)>> {
+        self.iter.next().map(|result| {
+            let (key, value) = result?;
+            Ok((KC::decode(&key)?, VC::decode(&value)?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use cask::{CaskOptions, SyncStrategy};
+    use errors::Error;
+    use typed::{Bincode, Json, Raw, TypedCask};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    #[test]
+    fn test_typed_cask() {
+        let path = "test-typed-cask.db";
+        let cask = CaskOptions::default()
+            .compaction(false)
+            .ordered_index(true)
+            .sync(SyncStrategy::Never)
+            .open(path)
+            .unwrap();
+
+        let users: TypedCask<u64, User> = TypedCask::new(cask.clone());
+        for &(id, name) in &[(300, "carol"), (2, "bob"), (1, "alice")] {
+            let user = User {
+                name: name.into(),
+                age: 42,
+            };
+            users.put(&id, &user).unwrap();
+        }
+
+        assert_eq!(users.get(&2).unwrap().unwrap().name, "bob");
+        assert_eq!(users.get(&3).unwrap(), None);
+
+        // big endian integers sort in numeric order
+        let ids: Vec<u64> = users
+            .range(..)
+            .unwrap()
+            .map(|result| result.unwrap().0)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 300]);
+        assert_eq!(users.iter().count(), 3);
+        let names: Vec<String> = users
+            .range(2..)
+            .unwrap()
+            .map(|result| result.unwrap().1.name)
+            .collect();
+        assert_eq!(names, vec!["bob", "carol"]);
+
+        assert!(users.delete(&1).unwrap().is_some());
+        let mut ids = users.keys().unwrap();
+        ids.sort();
+        assert_eq!(ids, vec![2, 300]);
+
+        let json: TypedCask<String, User, Raw, Json> = TypedCask::new(cask.clone());
+        let user = User {
+            name: "dave".into(),
+            age: 7,
+        };
+        json.put(&"dave".to_string(), &user).unwrap();
+        assert_eq!(
+            cask.get("dave").unwrap().unwrap(),
+            br#"{"name":"dave","age":7}"#.to_vec()
+        );
+        assert_eq!(json.get(&"dave".to_string()).unwrap(), Some(user));
+
+        // the values written with bincode aren't valid JSON
+        let strings: TypedCask<u64, String, Bincode, Json> = TypedCask::new(cask);
+        match strings.get(&2) {
+            Err(Error::Decode(..)) => {}
+            _ => panic!("expected a decode error"),
+        }
+
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
@@ -4,6 +4,7 @@ use std::io::{Result, Write};
 use std::path::Path;
 use std::result::Result::Ok;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use twox_hash::XxHash32 as TwoXhash32;
 
@@ -52,6 +53,14 @@ pub fn get_file_handle(path: &Path, write: bool) -> Result<File> {
     }
 }
 
+/// Returns the number of milliseconds elapsed since the UNIX epoch.
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the UNIX epoch");
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
 pub struct Sequence(AtomicUsize);
 
 impl Sequence {
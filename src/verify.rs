@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::vec::Vec;
+
+use data::SequenceNumber;
+use errors::{Error, Result};
+use log::{Entries, Hints};
+
+/// The result of verifying the integrity of a `Cask`, see `Cask::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of data files that were verified.
+    pub data_files: usize,
+    /// Number of entries that were verified.
+    pub entries: u64,
+    /// Entries whose checksum doesn't match or which can't be read.
+    pub corrupt_entries: Vec<CorruptEntry>,
+    /// Hint files that are invalid or that don't match their data file.
+    pub hint_file_problems: Vec<HintFileProblem>,
+    /// Data files without a hint file, e.g. because a read-only `Cask` doesn't write them. This
+    /// isn't a problem, the data file is read instead when the `Cask` is opened.
+    pub missing_hint_files: Vec<u32>,
+    /// Keys whose index entry doesn't point to a matching entry in a data file.
+    pub index_problems: Vec<IndexProblem>,
+    /// Files in the `Cask` directory which don't belong to the log.
+    pub orphan_files: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problems were found. Missing hint files aren't problems.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_entries.is_empty() && self.hint_file_problems.is_empty() &&
+            self.index_problems.is_empty() && self.orphan_files.is_empty()
+    }
+}
+
+/// A corrupt entry found in a data file.
+#[derive(Debug)]
+pub struct CorruptEntry {
+    pub file_id: u32,
+    pub entry_pos: u64,
+    pub error: Error,
+}
+
+/// A problem found with a hint file.
+#[derive(Debug, PartialEq)]
+pub enum HintFileProblem {
+    /// The hint file can't be read or its checksum doesn't match.
+    Invalid { file_id: u32 },
+    /// The hint for the entry at `entry_pos` doesn't match the entry in the data file.
+    Mismatch { file_id: u32, entry_pos: u64 },
+    /// The entry at `entry_pos` in the data file has no corresponding hint.
+    MissingHint { file_id: u32, entry_pos: u64 },
+}
+
+/// An index entry which doesn't point to a matching live entry in a data file.
+#[derive(Debug, PartialEq)]
+pub struct IndexProblem {
+    pub key: Vec<u8>,
+    pub file_id: u32,
+    pub entry_pos: u64,
+}
+
+struct EntrySummary {
+    key: Vec<u8>,
+    sequence: SequenceNumber,
+    value_size: u32,
+    deleted: bool,
+}
+
+/// The hint file of a data file being verified. `hints` is `None` if the hint file is either
+/// missing or invalid, `exists` tells both cases apart.
+pub struct HintFile<'a> {
+    pub hints: Option<Hints<'a>>,
+    pub exists: bool,
+}
+
+/// Verifies the entries of the data file `file_id` along with its hint file (if given) and the
+/// index entries that point into it.
+pub fn verify_file(
+    file_id: u32,
+    mut entries: Entries,
+    hint_file: Option<HintFile>,
+    index_entries: &[(Vec<u8>, u64, SequenceNumber)],
+    report: &mut VerifyReport,
+) -> Result<()> {
+    report.data_files += 1;
+
+    let mut summaries = HashMap::new();
+
+    while let Some((entry_pos, entry)) = entries.next() {
+        match entry {
+            Ok(entry) => {
+                report.entries += 1;
+
+                if !entry.batch {
+                    summaries.insert(
+                        entry_pos,
+                        EntrySummary {
+                            key: entry.key.into_owned(),
+                            sequence: entry.sequence,
+                            value_size: entry.value.len() as u32,
+                            deleted: entry.deleted,
+                        },
+                    );
+                }
+            }
+            Err(err) => {
+                report.corrupt_entries.push(CorruptEntry {
+                    file_id: file_id,
+                    entry_pos: entry_pos,
+                    error: err,
+                });
+                entries.resync()?;
+            }
+        }
+    }
+
+    if let Some(hint_file) = hint_file {
+        verify_hints(file_id, hint_file, &summaries, report);
+    }
+
+    for &(ref key, entry_pos, sequence) in index_entries {
+        let valid = match summaries.get(&entry_pos) {
+            Some(summary) => {
+                !summary.deleted && summary.sequence == sequence && summary.key == *key
+            }
+            None => false,
+        };
+
+        if !valid {
+            report.index_problems.push(IndexProblem {
+                key: key.clone(),
+                file_id: file_id,
+                entry_pos: entry_pos,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn verify_hints(
+    file_id: u32,
+    hint_file: HintFile,
+    summaries: &HashMap<u64, EntrySummary>,
+    report: &mut VerifyReport,
+) {
+    let hints = match hint_file {
+        HintFile {
+            hints: Some(hints), ..
+        } => hints,
+        HintFile { exists: true, .. } => {
+            report
+                .hint_file_problems
+                .push(HintFileProblem::Invalid { file_id: file_id });
+            return;
+        }
+        HintFile { exists: false, .. } => {
+            report.missing_hint_files.push(file_id);
+            return;
+        }
+    };
+
+    let mut hinted = Vec::new();
+
+    for hint in hints {
+        let hint = match hint {
+            Ok(hint) => hint,
+            Err(..) => {
+                report
+                    .hint_file_problems
+                    .push(HintFileProblem::Invalid { file_id: file_id });
+                return;
+            }
+        };
+
+        let matches = match summaries.get(&hint.entry_pos) {
+            Some(summary) => {
+                summary.key[..] == *hint.key && summary.sequence == hint.sequence &&
+                    summary.deleted == hint.deleted &&
+                    (hint.deleted || summary.value_size == hint.value_size)
+            }
+            None => false,
+        };
+
+        if !matches {
+            report.hint_file_problems.push(HintFileProblem::Mismatch {
+                file_id: file_id,
+                entry_pos: hint.entry_pos,
+            });
+        }
+
+        hinted.push(hint.entry_pos);
+    }
+
+    hinted.sort();
+
+    let mut unhinted: Vec<_> = summaries
+        .keys()
+        .filter(|entry_pos| hinted.binary_search(entry_pos).is_err())
+        .cloned()
+        .collect();
+    unhinted.sort();
+
+    for entry_pos in unhinted {
+        report.hint_file_problems.push(HintFileProblem::MissingHint {
+            file_id: file_id,
+            entry_pos: entry_pos,
+        });
+    }
+}